@@ -0,0 +1,209 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Resolver and domains pushed by the server; empty until a session config arrives
+#[derive(Debug, Default, Clone)]
+pub struct PushedDns {
+    pub servers: Vec<IpAddr>,
+    pub domains: Vec<String>,
+}
+
+pub type SharedPushedDns = Arc<RwLock<PushedDns>>;
+
+// Restores the original resolv.conf when dropped
+pub struct ResolvConfGuard {
+    original: String,
+}
+
+impl Drop for ResolvConfGuard {
+    fn drop(&mut self) {
+        match std::fs::write(RESOLV_CONF, &self.original) {
+            Ok(()) => info!("Restored {RESOLV_CONF}"),
+            Err(e) => warn!("Failed to restore {RESOLV_CONF}: {e}"),
+        }
+    }
+}
+
+// Points the system resolver at the DNS servers pushed by the server while a session is up,
+// for --manage-dns. Writes resolv.conf, or with systemd-resolved owning it (resolv.conf is
+// its symlink) sets the servers on the TUN link instead. Restored after every session, so the
+// tunnel's resolver isn't left in place while it is unreachable, and when dropped.
+pub struct ManagedDns {
+    tun_if_name: String,
+    applied: Mutex<Option<Applied>>,
+}
+
+enum Applied {
+    ResolvConf(ResolvConfGuard),
+    Resolved,
+}
+
+impl ManagedDns {
+    pub fn new(tun_if_name: &str) -> Self {
+        ManagedDns { tun_if_name: tun_if_name.to_string(), applied: Mutex::new(None) }
+    }
+
+    pub fn apply(&self, pushed: &PushedDns) -> Result<(), String> {
+        if pushed.servers.is_empty() {
+            self.restore();
+            return Ok(());
+        }
+        let mut applied = self.applied.lock().unwrap();
+        if uses_resolved() {
+            // marked first, so a half-applied change is reverted too
+            *applied = Some(Applied::Resolved);
+            let mut args = vec!["dns".to_string(), self.tun_if_name.clone()];
+            args.extend(pushed.servers.iter().map(IpAddr::to_string));
+            resolvectl(&args)?;
+            // `~.` sends every query to this link, not only those for the pushed domains
+            let mut args = vec!["domain".to_string(), self.tun_if_name.clone(), "~.".to_string()];
+            args.extend(pushed.domains.iter().cloned());
+            resolvectl(&args)?;
+        } else {
+            // keep the resolv.conf from before the first session, not one we wrote
+            let guard = match applied.take() {
+                Some(Applied::ResolvConf(guard)) => guard,
+                _ => ResolvConfGuard {
+                    original: std::fs::read_to_string(RESOLV_CONF).map_err(|e| format!("Failed to read {RESOLV_CONF}: {e}"))?,
+                },
+            };
+            let mut content = String::from("# managed by httpstun_client\n");
+            for server in &pushed.servers {
+                content.push_str(&format!("nameserver {server}\n"));
+            }
+            if !pushed.domains.is_empty() {
+                content.push_str(&format!("search {}\n", pushed.domains.join(" ")));
+            }
+            let written = std::fs::write(RESOLV_CONF, content).map_err(|e| format!("Failed to write {RESOLV_CONF}: {e}"));
+            *applied = Some(Applied::ResolvConf(guard));
+            written?;
+        }
+        info!("Using DNS servers {:?} pushed by the server", pushed.servers);
+        Ok(())
+    }
+
+    pub fn restore(&self) {
+        match self.applied.lock().unwrap().take() {
+            // restores it when dropped
+            Some(Applied::ResolvConf(guard)) => drop(guard),
+            Some(Applied::Resolved) => match resolvectl(&["revert".to_string(), self.tun_if_name.clone()]) {
+                Ok(()) => info!("Reverted DNS settings of {}", self.tun_if_name),
+                Err(e) => warn!("Failed to revert DNS settings of {}: {e}", self.tun_if_name),
+            },
+            None => {}
+        }
+    }
+}
+
+impl Drop for ManagedDns {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+fn uses_resolved() -> bool {
+    std::fs::read_link(RESOLV_CONF).is_ok_and(|target| target.to_string_lossy().contains("systemd/resolve"))
+}
+
+fn resolvectl(args: &[String]) -> Result<(), String> {
+    let output = std::process::Command::new("resolvectl")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute resolvectl: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(())
+}
+
+// Start the stub on `listen` port 53 and point the system resolver at it. Queries for pushed
+// domains go to the pushed resolver, everything else to the resolvers resolv.conf had before.
+pub async fn start_stub(listen: IpAddr, pushed: SharedPushedDns) -> Result<ResolvConfGuard, String> {
+    let original = std::fs::read_to_string(RESOLV_CONF).map_err(|e| format!("Failed to read {RESOLV_CONF}: {e}"))?;
+    let system: Vec<IpAddr> = nameservers(&original).into_iter().filter(|ns| *ns != listen).collect();
+    if system.is_empty() {
+        warn!("No system nameservers found in {RESOLV_CONF}; only pushed domains will resolve");
+    }
+    let socket = UdpSocket::bind(SocketAddr::new(listen, 53)).await.map_err(|e| format!("Failed to bind DNS stub on {listen}:53: {e}"))?;
+    std::fs::write(RESOLV_CONF, format!("# managed by httpstun_client\nnameserver {listen}\n"))
+        .map_err(|e| format!("Failed to write {RESOLV_CONF}: {e}"))?;
+    let guard = ResolvConfGuard { original };
+    info!("DNS stub listening on {listen}:53, system resolvers: {system:?}");
+    tokio::spawn(run_stub(Arc::new(socket), system, pushed));
+    Ok(guard)
+}
+
+async fn run_stub(socket: Arc<UdpSocket>, system: Vec<IpAddr>, pushed: SharedPushedDns) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => { warn!("DNS stub receive error: {e}"); continue; }
+        };
+        let query = buf[..len].to_vec();
+        let upstream = match query_name(&query) {
+            Some(name) => select_upstream(&name, &system, &pushed.read().unwrap()),
+            None => { debug!("Dropping malformed DNS query from {peer}"); continue; }
+        };
+        let Some(upstream) = upstream else {
+            debug!("No resolver available for query from {peer}");
+            continue;
+        };
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            match forward(&query, upstream).await {
+                Ok(response) => { let _ = socket.send_to(&response, peer).await; }
+                Err(e) => debug!("DNS forward to {upstream} failed: {e}"),
+            }
+        });
+    }
+}
+
+fn select_upstream(name: &str, system: &[IpAddr], pushed: &PushedDns) -> Option<IpAddr> {
+    let tunneled = pushed.domains.iter().any(|d| {
+        let d = d.trim_end_matches('.').to_ascii_lowercase();
+        name == d || name.ends_with(&format!(".{d}"))
+    });
+    if tunneled && let Some(server) = pushed.servers.first() {
+        return Some(*server);
+    }
+    system.first().or(pushed.servers.first()).copied()
+}
+
+async fn forward(query: &[u8], upstream: IpAddr) -> std::io::Result<Vec<u8>> {
+    let bind: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.send_to(query, SocketAddr::new(upstream, 53)).await?;
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "upstream timed out"))??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn nameservers(resolv_conf: &str) -> Vec<IpAddr> {
+    resolv_conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|rest| rest.trim().parse().ok())
+        .collect()
+}
+
+// Lowercased name of the first question in a DNS query
+fn query_name(packet: &[u8]) -> Option<String> {
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 { break; }
+        // compression pointers aren't valid in a question name
+        if len & 0xC0 != 0 { return None; }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+    Some(labels.join("."))
+}
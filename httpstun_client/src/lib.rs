@@ -0,0 +1,746 @@
+use clap::Parser;
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use log::{debug, info, warn, error};
+use futures_util::{StreamExt, SinkExt};
+use tappers::{Interface, DeviceState};
+use reqwest_websocket::{CloseCode, Message, RequestBuilderExt};
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub mod dns;
+pub mod routes;
+pub mod tun;
+
+use bytes::Bytes;
+use httpstun_core::compression::{Codec, COMPRESSION_HEADER};
+use httpstun_core::control::{
+    ServerMessage, TunAddress, CLOSE_CLIENT_REMOVED, CLOSE_UNAUTHORIZED, CONTROL_CHANNEL, MAX_MTU, MIN_MTU, SESSION_ID_HEADER,
+    SESSION_TOKEN_HEADER, SUPPORTED_FEATURES, WS_SUBPROTOCOL,
+};
+use httpstun_core::pool::PacketPool;
+use httpstun_core::psk;
+use httpstun_core::reconnect::ReconnectBuffer;
+use tun::{AsyncTun, TunDevice};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Args {
+    #[clap(long, value_delimiter = ',', default_value = "ws://127.0.0.1:8080/")]
+    #[serde(deserialize_with = "one_or_more")]
+    /// Server base URLs, tried in order when connecting fails (each with scheme and trailing slash; comma-separated or repeated)
+    server_url: Vec<String>,
+    #[clap(long, default_value = "client1")]
+    /// Client name for auth header
+    client_name: String,
+    #[clap(long, default_value = "changeme123")]
+    /// Client password (will be sent to server for Argon2 verification)
+    client_password: String,
+    #[clap(long)]
+    /// Send credentials as URL query parameters instead of headers (wss:// only)
+    query_auth: bool,
+    #[clap(long, default_value = "tun0")]
+    /// Local TUN interface name
+    tun_interface_name: String,
+    #[clap(long)]
+    /// Address for the TUN device in CIDR notation (e.g. 10.10.10.2/24); overrides the one pushed by the server
+    tun_address: Option<ipnet::IpNet>,
+    #[clap(long)]
+    /// Server's tunnel address, set as the peer of --tun-address
+    tun_gateway: Option<IpAddr>,
+    #[clap(long, default_value = "./httpstun_client.toml")]
+    /// Path to client config file
+    config_file: String,
+    #[clap(long, default_value = "info")]
+    /// Log level
+    log_level: String,
+    #[clap(long, default_value = "64")]
+    /// Outbound packets held while reconnecting and sent once the tunnel is back (0 disables)
+    reconnect_buffer: usize,
+    #[clap(long, default_value = "10")]
+    /// Seconds a buffered outbound packet stays worth sending after a reconnect
+    reconnect_buffer_max_age: u64,
+    #[clap(long)]
+    /// Ask the server to send control messages over a second WebSocket instead of the data connection
+    control_channel: bool,
+    #[clap(long)]
+    /// Only follow server redirects to URLs starting with one of these prefixes (repeatable; any ws(s) URL if unset)
+    allowed_redirect: Vec<String>,
+    #[clap(long)]
+    /// MTU of the TUN device; overrides the one pushed by the server
+    mtu: Option<u16>,
+    #[clap(long, value_enum)]
+    /// Compress tunneled packets with this codec, if the server supports it
+    compression: Option<Codec>,
+    #[clap(long)]
+    /// Don't add the routes pushed by the server
+    ignore_pushed_routes: bool,
+    #[clap(long)]
+    /// Send all traffic through the tunnel, except the connection to the server itself
+    full_tunnel: bool,
+    #[clap(long)]
+    /// Run a local DNS stub that sends server-pushed domains through the tunnel
+    dns_stub: bool,
+    #[clap(long, default_value = "127.0.53.53")]
+    /// Address the DNS stub listens on (port 53)
+    dns_stub_address: IpAddr,
+    #[clap(long)]
+    /// Use the DNS servers pushed by the server as the system resolver while connected
+    manage_dns: bool,
+    #[clap(long)]
+    /// Key encrypting every tunneled packet, 32 bytes in base64; must match the server's --psk
+    psk: Option<String>,
+}
+
+// A single URL as well as a list, as config files written before failover have one
+fn one_or_more<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(url) => url.split(',').map(|url| url.trim().to_string()).collect(),
+        OneOrMore::More(urls) => urls,
+    })
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args::parse_from([env!("CARGO_PKG_NAME")])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub client_args: Args,
+}
+
+// How a session with the server ended without an error
+enum SessionEnd {
+    Closed,
+    // the server asked us to continue with another server at this URL
+    Redirected(String),
+    // the server no longer accepts our credentials, so reconnecting can't succeed
+    Revoked(String),
+}
+
+fn parse_config(path: &str) -> Option<Config> {
+    if !Path::new(path).exists() { return None; }
+    let content = std::fs::read_to_string(path).ok()?;
+    toml::from_str(&content).ok()
+}
+
+fn override_config(mut config: Config, args: &Args) -> Config { config.client_args = args.clone(); config }
+
+#[tokio::main]
+pub async fn run() {
+    let args = Args::parse();
+    let config = match parse_config(&args.config_file) { Some(c)=> override_config(c,&args), None => Config{ client_args: args.clone() } };
+    let mut env_log_builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.client_args.log_level));
+    env_log_builder.init();
+    // the server refuses query credentials over plain HTTP, and they'd be exposed in transit anyway
+    if config.client_args.server_url.iter().all(|url| url.trim().is_empty()) {
+        error!("--server-url must name a server");
+        return;
+    }
+    if config.client_args.query_auth && !config.client_args.server_url.iter().all(|url| url.starts_with("wss://")) {
+        error!("--query-auth requires wss:// server URLs");
+        return;
+    }
+    // both take over resolv.conf
+    if config.client_args.manage_dns && config.client_args.dns_stub {
+        error!("--manage-dns and --dns-stub can't be combined");
+        return;
+    }
+    if let Err(e) = tun::validate_interface_name(&config.client_args.tun_interface_name) {
+        error!("Invalid --tun-interface-name: {e}");
+        return;
+    }
+    if let Err(e) = psk::Psk::load(config.client_args.psk.as_deref()) {
+        error!("{e}");
+        return;
+    }
+    println!("httpstun_client starting. Will connect to {} as {}", config.client_args.server_url.join(", "), config.client_args.client_name);
+    // Create / open TUN interface
+    // no fallback name: whatever kept this one from being created would stop any other too
+    let tap = Interface::new(&config.client_args.tun_interface_name).and_then(AsyncTun::new_named);
+    let mut tap = match tap {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}", tun::open_failure(&config.client_args.tun_interface_name, &e));
+            std::process::exit(1);
+        }
+    };
+    if let Some(address) = config.client_args.tun_address {
+        let applied = tun::static_address(address, config.client_args.tun_gateway)
+            .and_then(|req| tap.add_addr(req).map_err(|e| format!("Failed to add address {address}: {e}")));
+        match applied {
+            Ok(()) => info!("Configured TUN address {address}"),
+            Err(e) => { error!("Invalid --tun-address: {e}"); return; }
+        }
+    } else if config.client_args.tun_gateway.is_some() {
+        error!("--tun-gateway requires --tun-address");
+        return;
+    }
+    if let Some(mtu) = config.client_args.mtu {
+        if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            error!("--mtu {mtu} is outside of {MIN_MTU}..={MAX_MTU}");
+            return;
+        }
+        match set_mtu(&config.client_args.tun_interface_name, mtu) {
+            Ok(()) => info!("Configured MTU {mtu}"),
+            Err(e) => { error!("Failed to set MTU {mtu}: {e}"); return; }
+        }
+    }
+    if let Err(e) = tap.set_state(DeviceState::Up) { error!("Failed to set device up: {e:?}"); }
+
+    let pushed_dns = dns::SharedPushedDns::default();
+    // restores resolv.conf when main returns
+    let _dns_guard = if config.client_args.dns_stub {
+        match dns::start_stub(config.client_args.dns_stub_address, pushed_dns.clone()).await {
+            Ok(guard) => Some(guard),
+            Err(e) => { error!("{e}"); return; }
+        }
+    } else { None };
+    // restores the resolver when main returns
+    let managed_dns = config.client_args.manage_dns.then(|| dns::ManagedDns::new(&config.client_args.tun_interface_name));
+    // restores the routing table when main returns
+    let full_tunnel = if config.client_args.full_tunnel {
+        let full_tunnel = routes::FullTunnel::new(&config.client_args.tun_interface_name);
+        // every server we may fail over to
+        for url in &config.client_args.server_url {
+            if let Err(e) = full_tunnel.pin_server(url).await { error!("--full-tunnel: {e}"); return; }
+        }
+        Some(full_tunnel)
+    } else { None };
+
+    tokio::select! {
+        _ = run_forever(&config, &mut tap, &pushed_dns, managed_dns.as_ref(), full_tunnel.as_ref()) => {}
+        _ = shutdown_signal() => info!("Received termination signal, shutting down"),
+    }
+}
+
+async fn shutdown_signal() {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Failed to set up signal handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
+
+pub async fn run_forever<D: TunDevice>(config: &Config, tap: &mut D, pushed_dns: &dns::SharedPushedDns, managed_dns: Option<&dns::ManagedDns>, full_tunnel: Option<&routes::FullTunnel>) {
+    let mut buffer = ReconnectBuffer::new(config.client_args.reconnect_buffer, Duration::from_secs(config.client_args.reconnect_buffer_max_age));
+    let servers = &config.client_args.server_url;
+    // the server in use: the last one that accepted us, for as long as it keeps doing so
+    let mut current = 0;
+    // replaced by server redirects until connecting to the target fails
+    let mut url = servers[current].clone();
+    let mut link = Link::default();
+    // Reconnect loop
+    loop {
+        link.established = false;
+        let ended = connect_and_run(config, &url, tap, pushed_dns, managed_dns, &mut buffer, &mut link).await;
+        // the tunnel's resolver is unreachable until the next session pushes it again
+        if let Some(managed_dns) = managed_dns {
+            managed_dns.restore();
+        }
+        match ended {
+            Ok(SessionEnd::Redirected(target)) => {
+                // the new server must stay reachable outside the tunnel too
+                if let Some(full_tunnel) = full_tunnel
+                    && let Err(e) = full_tunnel.pin_server(&target).await {
+                    warn!("Not following redirect to {target}: {e}, retrying in 5s");
+                } else {
+                    info!("Server redirected us to {target}, reconnecting");
+                    url = target;
+                    // only valid on the server that issued it
+                    link.session_token = None;
+                    continue;
+                }
+            }
+            Ok(SessionEnd::Closed) => {
+                info!("Connection closed gracefully, retrying in 5s");
+            }
+            Ok(SessionEnd::Revoked(reason)) => {
+                error!("Server revoked our access ({reason}), giving up");
+                return;
+            }
+            // a server that went away mid-session is tried again first; one that can't be
+            // reached at all makes way for the next
+            Err(e) if !link.established && servers.len() > 1 => {
+                current = (current + 1) % servers.len();
+                url = servers[current].clone();
+                link.session_token = None;
+                warn!("Connection error: {e:?}, trying {url} in 5s");
+            }
+            Err(e) => {
+                warn!("Connection error: {e:?}, retrying in 5s");
+            }
+        }
+        buffer_until(tap, &mut buffer, read_len(&config.client_args), tokio::time::sleep(Duration::from_secs(5))).await;
+    }
+}
+
+// What the reconnect loop learns about the server it is connecting to
+#[derive(Default)]
+struct Link {
+    // issued by the server after a password login, presented on reconnects to it
+    session_token: Option<String>,
+    // set once the server accepted the WebSocket
+    established: bool,
+}
+
+// Flush the outbound packets buffered while reconnecting, logging any it had to drop
+fn drain_buffer(buffer: &mut ReconnectBuffer) -> Vec<Bytes> {
+    let fresh = buffer.drain_fresh();
+    let (full, stale) = buffer.take_evicted();
+    if full > 0 || stale > 0 {
+        info!(
+            "Reconnect buffer dropped {full} packet(s) over its capacity of {} and {stale} older than {}s",
+            buffer.capacity(), buffer.max_age().as_secs()
+        );
+    }
+    fresh
+}
+
+// Size of TUN reads: the local MTU, or the largest a server may push in its place
+fn read_len(args: &Args) -> usize {
+    usize::from(args.mtu.unwrap_or(MAX_MTU))
+}
+
+// Keep reading the TUN into `buffer` until `wait` completes
+async fn buffer_until<D: TunDevice>(tap: &mut D, buffer: &mut ReconnectBuffer, read_len: usize, wait: impl std::future::Future<Output = ()>) {
+    let mut tap_buf = vec![0u8; read_len];
+    tokio::pin!(wait);
+    loop {
+        tokio::select! {
+            _ = &mut wait => return,
+            tap_read = tap.recv(&mut tap_buf), if buffer.capacity() > 0 => {
+                match tap_read {
+                    Ok(sz) => buffer.push(Bytes::copy_from_slice(&tap_buf[..sz])),
+                    Err(e) => { warn!("Tap read error while reconnecting: {e:?}"); (&mut wait).await; return; }
+                }
+            }
+        }
+    }
+}
+
+async fn connect_and_run<D: TunDevice>(config: &Config, url: &str, tap: &mut D, pushed_dns: &dns::SharedPushedDns, managed_dns: Option<&dns::ManagedDns>, buffer: &mut ReconnectBuffer, link: &mut Link) -> Result<SessionEnd, Box<dyn std::error::Error + Send + Sync>> {
+    info!("Connecting to server {url}");
+    let session_token = &mut link.session_token;
+    let client = reqwest::Client::new();
+    let args = &config.client_args;
+    let offered: Vec<&str> = SUPPORTED_FEATURES.iter().copied()
+        .filter(|f| *f != CONTROL_CHANNEL || args.control_channel)
+        .collect();
+    let mut request = authed_request(&client, url, args, session_token.as_deref()).header("X-Httpstun-Features", offered.join(","));
+    if let Some(codec) = args.compression {
+        request = request.header(COMPRESSION_HEADER, codec.name());
+    }
+    let psk = psk::Psk::load(args.psk.as_deref())?;
+    let client_salt = match psk {
+        Some(_) => Some(psk::Psk::salt()?),
+        None => None,
+    };
+    if let Some(salt) = &client_salt {
+        request = request.header(psk::SALT_HEADER, salt);
+    }
+    let response = request
+        .upgrade()
+        .protocols([WS_SUBPROTOCOL])
+        .send()
+        .await?;
+    refused_token(&response, session_token);
+    if let Some(token) = response.headers().get(SESSION_TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+        *session_token = Some(token.to_string());
+    }
+    let session_id = response.headers().get(SESSION_ID_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+    // an older server ignores the request and sends plain packets
+    let codec = response.headers().get(COMPRESSION_HEADER).and_then(|v| v.to_str().ok()).and_then(Codec::from_name);
+    match (args.compression, codec) {
+        (Some(_), Some(codec)) => info!("Compressing packets with {}", codec.name()),
+        (Some(_), None) => warn!("Server doesn't support compression, sending packets uncompressed"),
+        _ => {}
+    }
+    // this connection's keys, from both sides' salts
+    let (mut sealer, mut opener) = match (&psk, &client_salt) {
+        (Some(psk), Some(client_salt)) => {
+            let Some(server_salt) = response.headers().get(psk::SALT_HEADER).and_then(|v| v.to_str().ok()) else {
+                return Err(format!("Server sent no {}; does it have --psk set?", psk::SALT_HEADER).into());
+            };
+            let (sealer, opener) = psk.session(psk::Side::Client, client_salt, server_salt)?;
+            (Some(sealer), Some(opener))
+        }
+        _ => (None, None),
+    };
+    let mut ws = response.into_websocket().await?;
+    link.established = true;
+    info!("WebSocket established");
+    // with a control connection the data connection carries packets only; session config,
+    // stats and redirects arrive on the control one
+    let mut control = match session_id {
+        Some(id) => {
+            // below the tunnel's path, wherever the server serves it (--ws-path)
+            let mut control_url = reqwest::Url::parse(url)?;
+            let control_path = format!("{}/control", control_url.path().trim_end_matches('/'));
+            control_url.set_path(&control_path);
+            control_url.set_query(None);
+            let control_response = authed_request(&client, control_url.as_str(), args, session_token.as_deref())
+                .header(SESSION_ID_HEADER, id)
+                .upgrade()
+                .protocols([WS_SUBPROTOCOL])
+                .send()
+                .await?;
+            refused_token(&control_response, session_token);
+            let control_ws = control_response.into_websocket().await?;
+            info!("Control connection established");
+            Some(control_ws)
+        }
+        None if args.control_channel => {
+            warn!("Server doesn't support a separate control connection, using the data connection");
+            None
+        }
+        None => None,
+    };
+    let buffered = drain_buffer(buffer);
+    if !buffered.is_empty() {
+        info!("Sending {} packet(s) buffered while reconnecting", buffered.len());
+        for packet in buffered {
+            ws.send(Message::Binary(encode(codec, sealer.as_mut(), packet)?)).await?;
+        }
+    }
+    let mut pool = PacketPool::new(read_len(&config.client_args));
+    // decompressed packets; `pool` is lent to the pending TUN read
+    let mut inflated = PacketPool::new(read_len(&config.client_args));
+    let mut scratch = Vec::new();
+    let mut undecryptable = 0u64;
+    loop {
+        tokio::select! {
+            ws_msg = ws.next() => {
+                match ws_msg {
+                    Some(Ok(Message::Binary(bin))) => {
+                        let bin = match &mut opener {
+                            Some(opener) => match opener.open(&bin) {
+                                Some(frame) => frame,
+                                None => {
+                                    undecryptable += 1;
+                                    // a server with another key, or none, fails on every frame
+                                    if undecryptable == 1 {
+                                        warn!("Dropping frame that failed decryption; does the server have the same --psk?");
+                                    } else {
+                                        debug!("Dropping frame that failed decryption ({undecryptable} so far)");
+                                    }
+                                    continue;
+                                }
+                            },
+                            None => bin,
+                        };
+                        let packet = match codec {
+                            Some(codec) => match codec.decode(&bin, &mut scratch, &mut inflated) {
+                                Ok(packet) => packet,
+                                Err(e) => { warn!("Dropping undecodable frame: {e}"); continue; }
+                            },
+                            None => bin,
+                        };
+                        if let Err(e) = tap.send(&packet).await { warn!("Failed sending to tap: {e:?}"); }
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some(end) = on_server_text(config, url, &text, pushed_dns, managed_dns, &mut ws).await { return end; }
+                    }
+                    Some(Ok(Message::Ping(p))) => { ws.send(Message::Pong(p)).await?; }
+                    Some(Ok(Message::Close { code, reason })) => {
+                        info!("Server closed connection ({code}): {reason}");
+                        return Ok(match u16::from(code) {
+                            CLOSE_UNAUTHORIZED | CLOSE_CLIENT_REMOVED => SessionEnd::Revoked(reason),
+                            _ => SessionEnd::Closed,
+                        });
+                    }
+                    Some(Ok(_)) => { /* ignore other frames */ }
+                    Some(Err(e)) => { return Err(Box::new(e)); }
+                    None => return Ok(SessionEnd::Closed),
+                }
+            }
+            control_msg = next_message(&mut control) => {
+                match control_msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some(end) = on_server_text(config, url, &text, pushed_dns, managed_dns, &mut ws).await { return end; }
+                    }
+                    Some(Ok(Message::Ping(p))) => {
+                        if let Some(control) = control.as_mut() { control.send(Message::Pong(p)).await?; }
+                    }
+                    Some(Ok(_)) => {}
+                    // the tunnel itself is unaffected; control messages fall back to the data connection
+                    Some(Err(_)) | None => {
+                        warn!("Control connection closed, continuing without it");
+                        control = None;
+                    }
+                }
+            }
+            tap_read = tap.recv(pool.slot()) => {
+                match tap_read {
+                    Ok(sz) => {
+                        let packet = encode(codec, sealer.as_mut(), pool.take(sz))?;
+                        if let Err(e) = ws.send(Message::Binary(packet)).await { return Err(Box::new(e)); }
+                    }
+                    Err(e) => { warn!("Tap read error: {e:?}"); return Err(Box::new(e)); }
+                }
+            }
+        }
+    }
+}
+
+
+// A packet as a binary frame: with the codec's prefix byte when compression was agreed, and
+// sealed when a --psk is set
+fn encode(codec: Option<Codec>, sealer: Option<&mut psk::Sealer>, packet: Bytes) -> Result<Bytes, String> {
+    let frame = match codec {
+        Some(codec) => Bytes::from(codec.encode(&packet)),
+        None => packet,
+    };
+    match sealer {
+        Some(sealer) => sealer.seal(&frame),
+        None => Ok(frame),
+    }
+}
+
+// Act on a control message from either connection. Some means the session is over.
+async fn on_server_text(config: &Config, url: &str, text: &str, pushed_dns: &dns::SharedPushedDns, managed_dns: Option<&dns::ManagedDns>, ws: &mut reqwest_websocket::WebSocket) -> Option<Result<SessionEnd, Box<dyn std::error::Error + Send + Sync>>> {
+    match handle_server_message(config, url, text, pushed_dns, managed_dns) {
+        Ok(Some(target)) => {
+            let _ = ws.send(Message::Close { code: CloseCode::Normal, reason: "redirected".to_string() }).await;
+            Some(Ok(SessionEnd::Redirected(target)))
+        }
+        Ok(None) => None,
+        Err(reason) => {
+            let _ = ws.send(Message::Close { code: CloseCode::Policy, reason: reason.clone() }).await;
+            Some(Err(reason.into()))
+        }
+    }
+}
+
+fn authed_request(client: &reqwest::Client, url: &str, args: &Args, session_token: Option<&str>) -> reqwest::RequestBuilder {
+    if let Some(token) = session_token {
+        client.get(url).header(SESSION_TOKEN_HEADER, token)
+    } else if args.query_auth {
+        client.get(url).query(&[("name", &args.client_name), ("password", &args.client_password)])
+    } else {
+        client.get(url)
+            .header("X-Httpstun-Client-Name", &args.client_name)
+            .header("X-Httpstun-Client-Password", &args.client_password)
+    }
+}
+
+// Forget a session token the server didn't accept, e.g. expired or from before a restart, so
+// the next attempt logs in with the password
+fn refused_token(response: &reqwest_websocket::UpgradeResponse, session_token: &mut Option<String>) {
+    if response.status() != reqwest::StatusCode::SWITCHING_PROTOCOLS && session_token.take().is_some() {
+        info!("Server refused the session token, logging in with the password next time");
+    }
+}
+
+// Next message from an optional WebSocket; never completes when there is none
+async fn next_message(ws: &mut Option<reqwest_websocket::WebSocket>) -> Option<Result<Message, reqwest_websocket::Error>> {
+    match ws {
+        Some(ws) => ws.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Apply a control message from the server. Returns the URL to move to for an accepted
+// redirect; an error means the session can't continue.
+fn handle_server_message(config: &Config, url: &str, text: &str, pushed_dns: &dns::SharedPushedDns, managed_dns: Option<&dns::ManagedDns>) -> Result<Option<String>, String> {
+    match serde_json::from_str::<ServerMessage>(text) {
+        Ok(ServerMessage::SessionConfig(session)) => {
+            let unsupported: Vec<&String> = session.features.iter().filter(|f| !SUPPORTED_FEATURES.contains(&f.as_str())).collect();
+            if !unsupported.is_empty() {
+                return Err(format!("server selected unsupported features: {unsupported:?}"));
+            }
+            if let Some(address) = &session.address {
+                if let Some(local) = config.client_args.tun_address {
+                    info!("Keeping --tun-address {local} instead of {}/{} pushed by server", address.ip, address.prefix_len);
+                } else {
+                    match set_address(&config.client_args.tun_interface_name, address) {
+                        Ok(()) => info!("Applied address {}/{} pushed by server", address.ip, address.prefix_len),
+                        Err(e) => warn!("Failed to apply address {}/{}: {e}", address.ip, address.prefix_len),
+                    }
+                    // one pushed by a server we failed over from
+                    if let Err(e) = remove_stale_addresses(&config.client_args.tun_interface_name, address.ip) {
+                        warn!("Failed to remove earlier addresses: {e}");
+                    }
+                }
+            }
+            // after the address: the kernel refuses routes over a device without one
+            let mut wanted = Vec::new();
+            if config.client_args.ignore_pushed_routes {
+                if !session.routes.is_empty() {
+                    info!("Ignoring {} route(s) pushed by server", session.routes.len());
+                }
+            } else {
+                for route in &session.routes {
+                    match add_route(&config.client_args.tun_interface_name, route) {
+                        Ok(()) => info!("Routing {route} through the tunnel"),
+                        Err(e) => warn!("Failed to add route {route}: {e}"),
+                    }
+                }
+                wanted.extend(session.routes.iter().copied());
+            }
+            if config.client_args.full_tunnel {
+                let address = config.client_args.tun_address.map(|net| net.addr()).or(session.address.as_ref().map(|a| a.ip));
+                match address {
+                    Some(address) => {
+                        for route in routes::split_routes(address.is_ipv6()) {
+                            if let Err(e) = add_route(&config.client_args.tun_interface_name, &route) {
+                                warn!("Failed to add full-tunnel route {route}: {e}");
+                            }
+                            wanted.push(route);
+                        }
+                        info!("Routing all {} traffic through the tunnel", if address.is_ipv6() { "IPv6" } else { "IPv4" });
+                    }
+                    None => warn!("--full-tunnel needs a TUN address, but none was set or pushed by the server"),
+                }
+            }
+            // ones pushed by a server we failed over from
+            if let Err(e) = remove_stale_routes(&config.client_args.tun_interface_name, &wanted) {
+                warn!("Failed to remove earlier routes: {e}");
+            }
+            if let Some(mtu) = session.mtu {
+                if let Some(local) = config.client_args.mtu {
+                    info!("Keeping --mtu {local} instead of {mtu} pushed by server");
+                } else {
+                    match set_mtu(&config.client_args.tun_interface_name, mtu) {
+                        Ok(()) => info!("Applied MTU {mtu} pushed by server"),
+                        Err(e) => warn!("Failed to apply MTU {mtu}: {e}"),
+                    }
+                }
+            }
+            let pushed = dns::PushedDns { servers: session.dns_servers, domains: session.dns_domains };
+            if let Some(managed_dns) = managed_dns
+                && let Err(e) = managed_dns.apply(&pushed) {
+                warn!("Failed to apply DNS servers {:?} pushed by server: {e}", pushed.servers);
+            }
+            *pushed_dns.write().unwrap() = pushed;
+        }
+        // only ever read from the authenticated session, so it comes from the server we logged in to
+        Ok(ServerMessage::SessionStats(stats)) => info!(
+            "Session stats: connected {}s, sent {} packets/{} bytes, received {} packets/{} bytes",
+            stats.connected_secs, stats.packets_from_client, stats.bytes_from_client, stats.packets_to_client, stats.bytes_to_client
+        ),
+        // answers pings this client doesn't send
+        Ok(ServerMessage::Pong(_)) => {}
+        Ok(ServerMessage::Redirect(redirect)) => match check_redirect(url, &redirect.url, &config.client_args.allowed_redirect) {
+            Ok(()) => return Ok(Some(redirect.url)),
+            Err(e) => warn!("Ignoring redirect to {}: {e}", redirect.url),
+        },
+        Err(e) => warn!("Ignoring unrecognized control message: {e}"),
+    }
+    Ok(None)
+}
+
+// A redirect must stay a WebSocket (or HTTP) URL, must not drop TLS, and must match --allowed-redirect
+// when that is set
+fn check_redirect(current: &str, target: &str, allowed: &[String]) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(target).map_err(|e| format!("invalid URL: {e}"))?;
+    let current_tls = current.starts_with("wss://") || current.starts_with("https://");
+    match parsed.scheme() {
+        "wss" | "https" => {}
+        "ws" | "http" if !current_tls => {}
+        "ws" | "http" => return Err("refusing to drop TLS".to_string()),
+        scheme => return Err(format!("unsupported scheme {scheme}")),
+    }
+    if !allowed.is_empty() && !allowed.iter().any(|prefix| target.starts_with(prefix.as_str())) {
+        return Err("not in --allowed-redirect".to_string());
+    }
+    Ok(())
+}
+
+fn set_mtu(if_name: &str, mtu: u16) -> Result<(), String> {
+    let output = std::process::Command::new("ip")
+        .args(["link", "set", "dev", if_name, "mtu", &mtu.to_string()])
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(())
+}
+
+// Routing protocol number the routes we add are tagged with, telling them apart from routes
+// others added over the device
+const ROUTE_PROTO: &str = "72";
+
+// `replace` like set_address. The routes go away with the device when the client exits.
+fn add_route(if_name: &str, route: &ipnet::IpNet) -> Result<(), String> {
+    let output = std::process::Command::new("ip")
+        .args(["route", "replace", &route.to_string(), "dev", if_name, "proto", ROUTE_PROTO])
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(())
+}
+
+// Our routes over the device that aren't in `wanted`
+fn remove_stale_routes(if_name: &str, wanted: &[ipnet::IpNet]) -> Result<(), String> {
+    for family in ["-4", "-6"] {
+        let output = std::process::Command::new("ip")
+            .args([family, "route", "show", "dev", if_name, "proto", ROUTE_PROTO])
+            .output()
+            .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+        if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            // host routes are listed without their prefix length
+            let Some(route) = line.split_whitespace().next()
+                .and_then(|dest| dest.parse::<ipnet::IpNet>().ok().or_else(|| dest.parse::<IpAddr>().ok().map(ipnet::IpNet::from))) else { continue };
+            if wanted.iter().any(|net| net.trunc() == route) {
+                continue;
+            }
+            let output = std::process::Command::new("ip")
+                .args(["route", "del", &route.to_string(), "dev", if_name, "proto", ROUTE_PROTO])
+                .output()
+                .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+            if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+            info!("Removed route {route} no longer pushed by the server");
+        }
+    }
+    Ok(())
+}
+
+// Global addresses on the device other than `keep`
+fn remove_stale_addresses(if_name: &str, keep: IpAddr) -> Result<(), String> {
+    let output = std::process::Command::new("ip")
+        .args(["-o", "addr", "show", "dev", if_name, "scope", "global"])
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // `N: dev inet 10.0.0.2/24 ...`, or `inet 10.0.0.2 peer 10.0.0.1/32 ...` with a peer
+        let mut fields = line.split_whitespace().skip_while(|field| *field != "inet" && *field != "inet6").skip(1);
+        let Some(local) = fields.next() else { continue };
+        let Ok(ip) = local.split('/').next().unwrap_or(local).parse::<IpAddr>() else { continue };
+        if ip == keep {
+            continue;
+        }
+        let output = std::process::Command::new("ip")
+            .args(["addr", "del", local, "dev", if_name])
+            .output()
+            .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+        if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+        info!("Removed address {local} no longer pushed by the server");
+    }
+    Ok(())
+}
+
+// `replace` rather than `add` so reconnects re-applying the same address don't fail
+fn set_address(if_name: &str, address: &TunAddress) -> Result<(), String> {
+    let local = format!("{}/{}", address.ip, address.prefix_len);
+    let mut args = vec!["addr".to_string(), "replace".to_string(), local];
+    if let Some(peer) = address.peer {
+        args.extend(["peer".to_string(), peer.to_string()]);
+    }
+    args.extend(["dev".to_string(), if_name.to_string()]);
+    let output = std::process::Command::new("ip")
+        .args(&args)
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(())
+}
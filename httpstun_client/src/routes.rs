@@ -0,0 +1,107 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use ipnet::IpNet;
+use log::{info, warn};
+
+// Halves of the address space routed through the tunnel with --full-tunnel. Being more
+// specific than the default route they win over it, which stays in place for the server.
+const SPLIT_V4: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+const SPLIT_V6: [&str; 2] = ["::/1", "8000::/1"];
+
+pub fn split_routes(v6: bool) -> Vec<IpNet> {
+    let halves = if v6 { SPLIT_V6 } else { SPLIT_V4 };
+    halves.iter().map(|net| net.parse().unwrap()).collect()
+}
+
+// Where the kernel sent a destination before the tunnel took over
+#[derive(Debug, Clone)]
+struct Gateway {
+    via: Option<IpAddr>,
+    dev: String,
+}
+
+// Host routes that keep the WebSocket to the server off the tunnel while --full-tunnel sends
+// everything else into it. Dropping it removes them and the split routes, restoring the
+// routing table the client started with.
+pub struct FullTunnel {
+    tun_if_name: String,
+    // by address family (false: IPv4), so servers a redirect names later go the same way
+    gateways: Mutex<[Option<Gateway>; 2]>,
+    pinned: Mutex<Vec<IpAddr>>,
+}
+
+impl FullTunnel {
+    pub fn new(tun_if_name: &str) -> Self {
+        FullTunnel { tun_if_name: tun_if_name.to_string(), gateways: Mutex::new([None, None]), pinned: Mutex::new(Vec::new()) }
+    }
+
+    // Route the addresses of the server at `url` through the gateway they use now. Must run
+    // before connecting, so the WebSocket never goes into the tunnel it carries.
+    pub async fn pin_server(&self, url: &str) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid server URL {url}: {e}"))?;
+        let host = parsed.host_str().ok_or_else(|| format!("Server URL {url} has no host"))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addrs = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port)).await
+            .map_err(|e| format!("Failed to resolve {host}: {e}"))?;
+        for ip in addrs.map(|a| a.ip()) {
+            if ip.is_loopback() || self.pinned.lock().unwrap().contains(&ip) {
+                continue;
+            }
+            let gateway = self.gateway_for(ip)?;
+            let mut args = vec!["route".to_string(), "replace".to_string(), ip.to_string()];
+            if let Some(via) = gateway.via {
+                args.extend(["via".to_string(), via.to_string()]);
+            }
+            args.extend(["dev".to_string(), gateway.dev.clone()]);
+            ip_command(&args)?;
+            info!("Routing server address {ip} through {} outside the tunnel", gateway.dev);
+            self.pinned.lock().unwrap().push(ip);
+        }
+        Ok(())
+    }
+
+    // The route the kernel has for `ip`, or the one found for an earlier server once the
+    // tunnel's own routes already cover it
+    fn gateway_for(&self, ip: IpAddr) -> Result<Gateway, String> {
+        let family = usize::from(ip.is_ipv6());
+        let current = route_get(ip)?;
+        let mut gateways = self.gateways.lock().unwrap();
+        if current.dev != self.tun_if_name {
+            gateways[family] = Some(current.clone());
+            return Ok(current);
+        }
+        gateways[family].clone().ok_or_else(|| format!("{ip} is already routed into {}, and no other route to the server is known", self.tun_if_name))
+    }
+}
+
+impl Drop for FullTunnel {
+    fn drop(&mut self) {
+        for ip in self.pinned.lock().unwrap().drain(..) {
+            if let Err(e) = ip_command(&["route".to_string(), "del".to_string(), ip.to_string()]) {
+                warn!("Failed to remove route to server address {ip}: {e}");
+            }
+        }
+        // gone with the device if it was already closed
+        for net in split_routes(false).into_iter().chain(split_routes(true)) {
+            let _ = ip_command(&["route".to_string(), "del".to_string(), net.to_string(), "dev".to_string(), self.tun_if_name.clone()]);
+        }
+        info!("Restored the routing table");
+    }
+}
+
+fn route_get(ip: IpAddr) -> Result<Gateway, String> {
+    let output = ip_command(&["route".to_string(), "get".to_string(), ip.to_string()])?;
+    let tokens: Vec<&str> = output.split_whitespace().collect();
+    let after = |key: &str| tokens.iter().position(|t| *t == key).and_then(|i| tokens.get(i + 1)).copied();
+    let dev = after("dev").ok_or_else(|| format!("No route to {ip}: {}", output.trim()))?;
+    Ok(Gateway { via: after("via").and_then(|via| via.parse().ok()), dev: dev.to_string() })
+}
+
+fn ip_command(args: &[String]) -> Result<String, String> {
+    let output = std::process::Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {e}"))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use ipnet::IpNet;
+use tappers::{AddAddress, AddAddressV4, AddAddressV6};
+
+pub use httpstun_core::device::AsyncTun;
+
+// Linux interface names must fit IFNAMSIZ (16 bytes including the NUL)
+const MAX_INTERFACE_NAME: usize = 15;
+// Opened to create every TUN device
+const TUN_CLONE_DEVICE: &str = "/dev/net/tun";
+
+// Reject names the kernel would refuse, mirroring dev_valid_name(), before trying to create
+// the device, whose error for them is unhelpful
+pub fn validate_interface_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Interface name is empty".to_string());
+    }
+    if name.len() > MAX_INTERFACE_NAME {
+        return Err(format!("Interface name {name} is {} bytes long, Linux allows at most {MAX_INTERFACE_NAME}", name.len()));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("Interface name {name} is reserved"));
+    }
+    if let Some(c) = name.chars().find(|c| *c == '/' || *c == ':' || c.is_whitespace()) {
+        return Err(format!("Interface name {name:?} contains invalid character {c:?}"));
+    }
+    Ok(())
+}
+
+// Why the TUN device couldn't be created and what to do about it, since the OS error alone
+// ("Operation not permitted", "No such file or directory") rarely says
+pub fn open_failure(name: &str, e: &io::Error) -> String {
+    let mut message = format!("Failed to create TUN interface {name}: {e}");
+    if !Path::new(TUN_CLONE_DEVICE).exists() {
+        message.push_str(&format!("\n  {TUN_CLONE_DEVICE} does not exist: load the tun module (modprobe tun), or pass the device into the container"));
+    }
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => message.push_str(&format!(
+            "\n  creating TUN devices needs CAP_NET_ADMIN and access to {TUN_CLONE_DEVICE}: run as root, or grant the capability with setcap cap_net_admin+ep on the binary"
+        )),
+        io::ErrorKind::ResourceBusy => message.push_str(&format!(
+            "\n  {name} is already in use, probably by another tunnel: pick another --tun-interface-name"
+        )),
+        _ => {}
+    }
+    message
+}
+
+// Build the request for a locally configured address (--tun-address), with the server's end
+// of the tunnel (--tun-gateway) as its peer
+pub fn static_address(address: IpNet, gateway: Option<IpAddr>) -> Result<AddAddress, String> {
+    if let IpNet::V4(net) = address
+        && net.prefix_len() <= 30
+        && (net.addr() == net.network() || net.addr() == net.broadcast()) {
+        return Err(format!("{} is the network or broadcast address of {}", net.addr(), net.trunc()));
+    }
+    match (address, gateway) {
+        (_, Some(gw)) if gw == address.addr() => Err(format!("Gateway {gw} is the TUN address itself")),
+        (IpNet::V4(net), gw) => {
+            let mut req = AddAddressV4::new(net.addr());
+            req.set_netmask(net.prefix_len());
+            match gw {
+                Some(IpAddr::V4(gw)) => req.set_destination(gw),
+                Some(gw) => return Err(format!("Gateway {gw} is IPv6 but the TUN address {address} is IPv4")),
+                None => {}
+            }
+            Ok(req.into())
+        }
+        (IpNet::V6(net), gw) => {
+            let mut req = AddAddressV6::new(net.addr());
+            req.set_netmask(net.prefix_len());
+            match gw {
+                Some(IpAddr::V6(gw)) => req.set_destination(gw),
+                Some(gw) => return Err(format!("Gateway {gw} is IPv4 but the TUN address {address} is IPv6")),
+                None => {}
+            }
+            Ok(req.into())
+        }
+    }
+}
+
+// Packet I/O of the TUN device. The tunnel loop is written against this, so it can also run
+// over other packet sources, like the in-memory devices of the integration tests.
+pub trait TunDevice {
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl TunDevice for AsyncTun {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        AsyncTun::recv(self, buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        AsyncTun::send(self, buf).await
+    }
+}
@@ -0,0 +1,55 @@
+// Packets read from the TUN device and sent go through a PacketPool so the client's hot path
+// doesn't allocate per packet. Counts the allocations of many such round trips.
+//
+//     cargo test -p httpstun_client --test pool
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use httpstun_core::pool::PacketPool;
+
+const MTU: usize = 1500;
+const PACKETS: usize = 10_000;
+
+struct Counting;
+
+thread_local! {
+    // per thread, so the test harness allocating meanwhile doesn't count
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+// SAFETY: defers to the system allocator, only counting calls
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[test]
+fn sending_packets_reuses_the_pool() {
+    let mut pool = PacketPool::new(MTU);
+    let before = ALLOCATIONS.with(Cell::get);
+    for i in 0..PACKETS {
+        // a read of a packet of varying size
+        let len = 40 + i % (MTU - 40);
+        pool.slot()[..len].fill(0x45);
+        let packet = pool.take(len);
+        assert_eq!(packet.len(), len);
+        // sent over the WebSocket, which drops it
+        drop(packet);
+    }
+    let allocations = ALLOCATIONS.with(Cell::get) - before;
+    assert!(allocations <= 2, "{} allocations for {} packets", allocations, PACKETS);
+}
@@ -0,0 +1,87 @@
+// Allocations made per packet on the route-to-client path: a fresh Vec for every packet read
+// from the device, as the data plane used to queue, against packets cut from a PacketPool.
+// A counting global allocator tallies them. Packets go through a queue as deep as a client's
+// default one to another thread that drops them, as a client's session does in the server.
+//
+//     cargo bench -p httpstun_core --bench packet_pool
+#[allow(dead_code)]
+#[path = "../src/pool.rs"]
+mod pool;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Instant;
+
+use pool::PacketPool;
+
+const PACKETS: usize = 1_000_000;
+const PACKET_SIZE: usize = 1400;
+const MTU: usize = 1500;
+const CLIENT_QUEUE: usize = 256;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+// SAFETY: forwards every call to the system allocator unchanged
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// `read` stands in for a read from the device that yields one queued packet
+fn run<T: Send + 'static>(name: &str, mut read: impl FnMut(&[u8]) -> T) {
+    let packet = [0x45u8; PACKET_SIZE];
+    let (queue, session) = mpsc::sync_channel::<T>(CLIENT_QUEUE);
+    let sender = std::thread::spawn(move || {
+        for packet in session {
+            std::hint::black_box(packet);
+        }
+    });
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        queue.send(read(&packet)).unwrap();
+    }
+    drop(queue);
+    sender.join().unwrap();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{:<6} {:>8.3} allocations/packet  {:>10.0} pps",
+        name,
+        allocations as f64 / PACKETS as f64,
+        PACKETS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!("{} packets of {} bytes per run", PACKETS, PACKET_SIZE);
+
+    let mut buf = [0u8; MTU];
+    run("vec", |packet| {
+        buf[..packet.len()].copy_from_slice(packet);
+        buf[..packet.len()].to_vec()
+    });
+
+    let mut pool = PacketPool::new(MTU);
+    run("pooled", |packet| {
+        pool.slot()[..packet.len()].copy_from_slice(packet);
+        pool.take(packet.len())
+    });
+}
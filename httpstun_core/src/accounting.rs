@@ -0,0 +1,108 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::{ClientRegistry, ClientSession};
+use crate::stats::load;
+
+// One line of the accounting log, in the spirit of RADIUS Accounting-Request start, interim
+// update and stop records
+#[derive(Serialize)]
+struct Record<'a> {
+    event: &'a str,
+    time: u64,
+    client: &'a str,
+    ip: IpAddr,
+    session_start: u64,
+    duration_secs: u64,
+    bytes_from_client: u64,
+    bytes_to_client: u64,
+    packets_from_client: u64,
+    packets_to_client: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+// Session accounting written as JSON lines. When no log is configured every call is a no-op.
+pub struct Accounting {
+    file: Option<Mutex<File>>,
+}
+
+impl Accounting {
+    pub fn open(path: Option<&str>) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+            None => None,
+        };
+        Ok(Accounting { file })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn start(&self, session: &ClientSession) {
+        self.write("start", session, None);
+    }
+
+    pub fn interim(&self, session: &ClientSession) {
+        self.write("interim", session, None);
+    }
+
+    // Written at most once per session, by whichever of the session task and the sweep ends
+    // it first, so abnormal disconnects are accounted too
+    pub fn stop(&self, session: &ClientSession, reason: &str) {
+        if !session.accounted.swap(true, Ordering::AcqRel) {
+            self.write("stop", session, Some(reason));
+        }
+    }
+
+    fn write(&self, event: &str, session: &ClientSession, reason: Option<&str>) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let now = SystemTime::now();
+        let duration = session.connected_at.elapsed();
+        let record = Record {
+            event,
+            time: unix_secs(now),
+            client: &session.name,
+            ip: session.ip,
+            session_start: unix_secs(now - duration),
+            duration_secs: duration.as_secs(),
+            bytes_from_client: load(&session.traffic.bytes_from_client),
+            bytes_to_client: load(&session.traffic.bytes_to_client),
+            packets_from_client: load(&session.traffic.packets_from_client),
+            packets_to_client: load(&session.traffic.packets_to_client),
+            reason,
+        };
+        let line = serde_json::to_string(&record).expect("accounting records always serialize");
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            warn!("Failed to write accounting record: {}", e);
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Write an interim record for every connected session each `interval`
+pub async fn run_interim(registry: ClientRegistry, accounting: Arc<Accounting>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // the first tick completes immediately; sessions just got their start record
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let sessions: Vec<_> = registry.read().await.values().cloned().collect();
+        for session in sessions {
+            accounting.interim(&session);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::{LazyLock, OnceLock};
+
+use argon2::{
+    password_hash::{
+        rand_core::OsRng,
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString
+    },
+    Algorithm, Argon2, Params, Version
+};
+use log::info;
+
+use crate::{lock_config, parse_config, write_config, Args, Client, Config};
+
+// Server-wide secret mixed into every hash as Argon2's secret input, so hashes from a leaked
+// config file can't be cracked without it. Loaded once at startup, never written to the config.
+static PEPPER: OnceLock<Vec<u8>> = OnceLock::new();
+
+pub fn load_pepper(args: &Args) -> Result<(), String> {
+    let pepper = match &args.pepper_file {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("Failed to read pepper file {}: {}", path, e))?,
+        None => match std::env::var("HTTPSTUN_PEPPER") {
+            Ok(pepper) => pepper,
+            Err(_) => return Ok(()),
+        },
+    };
+    let pepper = pepper.trim_end_matches(['\r', '\n']);
+    if pepper.is_empty() {
+        return Err("Pepper is empty".to_string());
+    }
+    PEPPER.set(pepper.as_bytes().to_vec()).map_err(|_| "Pepper already loaded".to_string())
+}
+
+fn argon2(peppered: bool) -> Argon2<'static> {
+    match PEPPER.get() {
+        Some(pepper) if peppered => Argon2::new_with_secret(pepper, Algorithm::default(), Version::default(), Params::default())
+            .expect("pepper length is within Argon2 limits"),
+        _ => Argon2::default(),
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2(true).hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+// Hash of a random password, verified against when the client name is unknown so that
+// the response time doesn't reveal whether a name exists
+static DECOY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let decoy_password = SaltString::generate(&mut OsRng);
+    hash_password(decoy_password.as_str())
+});
+
+// Compute the decoy hash up front so the first unknown-name request isn't slower
+pub fn compute_decoy_hash() {
+    LazyLock::force(&DECOY_HASH);
+}
+
+#[cfg(test)]
+thread_local! {
+    // Argon2 verifications run on this thread, so tests can see the decoy is verified against
+    static VERIFICATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn verify_password(password: &str, hash: &str, peppered: bool) -> Result<bool, String> {
+    #[cfg(test)]
+    VERIFICATIONS.with(|n| n.set(n.get() + 1));
+    let parsed_hash = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+    Ok(argon2(peppered).verify_password(password.as_bytes(), &parsed_hash).is_ok())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    // unknown name or wrong password, deliberately not told apart
+    InvalidCredentials,
+    // the client's stored hash doesn't parse; only a hand-edited config gets here
+    CorruptHash(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "invalid client name or password"),
+            AuthError::CorruptHash(e) => write!(f, "stored password hash is invalid: {}", e),
+        }
+    }
+}
+
+// Check a client's credentials, returning its config entry. Every path runs the same number
+// of Argon2 verifications, against a decoy hash where there is no usable one, so the response
+// time reveals neither whether the name exists nor whether its hash is broken.
+pub fn validate_client<'a>(name: &str, password: &str, config: &'a Config) -> Result<&'a Client, AuthError> {
+    // hashes made before the pepper was set only verify without it
+    let migrating = config.server_args.pepper_migrate && PEPPER.get().is_some();
+    let decoy = |rounds: usize| {
+        for _ in 0..rounds {
+            let _ = verify_password(password, &DECOY_HASH, true);
+        }
+    };
+    let rounds = if migrating { 2 } else { 1 };
+    let Some(client) = config.clients().iter().find(|c| c.name == name) else {
+        decoy(rounds);
+        return Err(AuthError::InvalidCredentials);
+    };
+    match verify_password(password, &client.token, true) {
+        Ok(true) => Ok(client),
+        Ok(false) if migrating && verify_password(password, &client.token, false) == Ok(true) => {
+            rehash_client(name, password, &client.token, &config.server_args.config_file);
+            Ok(client)
+        }
+        Ok(false) => Err(AuthError::InvalidCredentials),
+        Err(e) => {
+            decoy(rounds);
+            Err(AuthError::CorruptHash(e))
+        }
+    }
+}
+
+// Replace a client's unpeppered hash in the config file with a peppered one. The running
+// config keeps the old hash, which the migration fallback still accepts until restart.
+fn rehash_client(name: &str, password: &str, old_hash: &str, config_file_path: &str) {
+    let _lock = match lock_config(config_file_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            log::warn!("Client {} logged in with an unpeppered hash but the config could not be locked to upgrade it: {}", name, e);
+            return;
+        }
+    };
+    let mut config = match parse_config(config_file_path) {
+        Ok(config) => config,
+        Err(e) => {
+            log::warn!("Client {} logged in with an unpeppered hash but the config could not be read to upgrade it: {}", name, e);
+            return;
+        }
+    };
+    // already upgraded by an earlier login, or changed since startup
+    let Some(client) = config.clients_mut().iter_mut().find(|c| c.name == name && c.token == old_hash) else {
+        return;
+    };
+    client.token = hash_password(password);
+    match write_config(config_file_path, &config) {
+        Ok(()) => info!("Re-hashed password of client {} with the pepper", name),
+        Err(e) => log::warn!("Failed to write re-hashed password of client {}: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Argon2 verifications `f` runs
+    fn verifications<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = VERIFICATIONS.with(|n| n.get());
+        let result = f();
+        (result, VERIFICATIONS.with(|n| n.get()) - before)
+    }
+
+    #[test]
+    fn unknown_name_is_verified_against_the_decoy_like_a_wrong_password() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_args": {},
+            "clients": [{ "name": "client1", "token": hash_password("hunter22"), "ip": "10.0.0.2" }],
+        })).unwrap();
+        let (unknown, unknown_rounds) = verifications(|| validate_client("nobody", "hunter22", &config).map(|c| c.name.clone()));
+        let (wrong, wrong_rounds) = verifications(|| validate_client("client1", "wrong", &config).map(|c| c.name.clone()));
+        assert_eq!(unknown, Err(AuthError::InvalidCredentials));
+        assert_eq!(wrong, Err(AuthError::InvalidCredentials));
+        assert_eq!((unknown_rounds, wrong_rounds), (1, 1));
+        assert_eq!(validate_client("client1", "hunter22", &config).map(|c| c.name.as_str()), Ok("client1"));
+    }
+}
@@ -0,0 +1,77 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::pool::PacketPool;
+
+// Per-packet compression of tunneled packets, negotiated at connect: the client lists the
+// codecs it speaks in COMPRESSION_HEADER and the server answers with the one it picked. With a
+// codec agreed, every binary frame in both directions starts with a byte telling how the rest
+// is encoded, so packets that wouldn't shrink can still go out as they are.
+pub const COMPRESSION_HEADER: &str = "X-Httpstun-Compression";
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+// Largest packet a frame may decompress to: the largest IP packet
+const MAX_PACKET: usize = 65535;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    /// LZ4, fast enough not to slow the tunnel down
+    Lz4,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    // The codec the server answered with in COMPRESSION_HEADER
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name.trim() {
+            "lz4" => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    // The first codec of a COMPRESSION_HEADER value that the server speaks
+    pub fn negotiate(offered: &str) -> Option<Codec> {
+        offered.split(',').find_map(Codec::from_name)
+    }
+
+    pub fn encode(self, packet: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(packet.len() + 1);
+        match self {
+            Codec::Lz4 => {
+                let compressed = lz4_flex::block::compress(packet);
+                if compressed.len() < packet.len() {
+                    frame.push(LZ4);
+                    frame.extend_from_slice(&compressed);
+                    return frame;
+                }
+            }
+        }
+        frame.push(RAW);
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    // The packet carried by a frame. A raw one shares the frame's memory; a compressed one is
+    // decompressed into `scratch` and copied from there into `pool`.
+    pub fn decode(self, frame: &Bytes, scratch: &mut Vec<u8>, pool: &mut PacketPool) -> Result<Bytes, String> {
+        match frame.split_first() {
+            Some((&RAW, _)) => Ok(frame.slice(1..)),
+            Some((&LZ4, block)) => {
+                scratch.resize(MAX_PACKET, 0);
+                let len = lz4_flex::block::decompress_into(block, scratch)
+                    .map_err(|e| format!("invalid LZ4 block: {}", e))?;
+                Ok(pool.copy(&scratch[..len]))
+            }
+            Some((codec, _)) => Err(format!("unknown codec {}", codec)),
+            None => Err("empty frame".to_string()),
+        }
+    }
+}
@@ -0,0 +1,813 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+use argon2::password_hash::PasswordHash;
+use clap::Parser;
+use ipnet::{IpAddrRange, IpNet, Ipv4AddrRange, Ipv6AddrRange};
+use serde::{Deserialize, Serialize};
+
+use crate::config_format::ConfigFormat;
+use crate::control::{MAX_MTU, MIN_MTU};
+use crate::netmask::Netmask;
+use crate::{control, decoy, flow, fw, logging, psk, routing, syslog, tls, unauthenticated, ws};
+
+// What TUN devices get from the kernel when no MTU is set
+const DEFAULT_MTU: u16 = 1500;
+
+#[derive(Parser, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Args{
+    #[clap(short, long, default_value = "8080")]
+    pub port: u16,
+    /// Addresses to listen on (comma separated): IPs or host names, which take --port, or
+    /// socket addresses with a port of their own; [::] serves IPv4 and IPv6 alike
+    #[clap(long, value_delimiter = ',', default_value = "127.0.0.1")]
+    #[serde(deserialize_with = "one_or_many")]
+    pub host: Vec<String>,
+    /// PEM certificate chain to serve TLS (wss://) with; requires --tls-key
+    #[clap(long)]
+    pub tls_cert: Option<String>,
+    /// PEM private key for --tls-cert
+    #[clap(long)]
+    pub tls_key: Option<String>,
+    /// Maximum number of pending connections waiting to be accepted
+    #[clap(long, default_value = "1024")]
+    pub backlog: u32,
+    /// Seconds a client has to send its request headers (0 disables)
+    #[clap(long, default_value = "5")]
+    pub client_request_timeout: u64,
+    /// Seconds an idle keep-alive connection is held open between requests (0 disables keep-alive)
+    #[clap(long, default_value = "5")]
+    pub keep_alive: u64,
+    /// Path the tunnel WebSocket is served at; the control connection is at <path>/control
+    #[clap(long, default_value = "/")]
+    pub ws_path: String,
+    #[clap(short, long, default_value = "info")]
+    pub log_level: String,
+    /// Format of log lines on stderr
+    #[clap(long, value_enum, default_value_t = logging::LogFormat::Text)]
+    pub log_format: logging::LogFormat,
+    #[clap(short, long, default_value = "tun0")]
+    pub tun_interface_name: String,
+    #[clap(short, long, default_value = "eth0")]
+    pub external_interface_name: String,
+    #[clap(short, long, default_value = "./httpstun_server.toml", global = true)]
+    pub config_file: String,
+    #[clap(short, long, default_value = "true")]
+    pub interactive: bool,
+    #[clap(short, long, default_value = "10.10.10.1")]
+    pub server_ip: IpAddr,
+    /// Netmask of the server subnet, dotted (255.255.255.0) or as a prefix length (24)
+    #[clap(short, long, default_value = "255.255.255.0")]
+    pub netmask: Netmask,
+    /// First address given to clients added without one (default: the subnet's first host)
+    #[clap(long)]
+    pub ip_pool_start: Option<IpAddr>,
+    /// Last address given to clients added without one (default: the subnet's last host)
+    #[clap(long)]
+    pub ip_pool_end: Option<IpAddr>,
+    /// Seconds between sweeps of the client registry
+    #[clap(long, default_value = "15")]
+    pub sweep_interval: u64,
+    /// Simultaneous sessions allowed per client unless its config entry sets max_sessions; only 1 is supported for now
+    #[clap(long, default_value = "1")]
+    pub max_sessions_per_client: u32,
+    /// Most clients connected at once; 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub max_clients: usize,
+    /// What to do when a client connects with an IP another connected client holds
+    #[clap(long, value_enum, default_value_t = IpConflictPolicy::Reject)]
+    pub ip_conflict_policy: IpConflictPolicy,
+    /// Seconds between session stats pushed over control connections
+    #[clap(long, default_value = "10")]
+    pub control_stats_interval: u64,
+    /// Close sessions with no traffic for this many seconds (0 disables)
+    #[clap(long, default_value = "0")]
+    pub client_idle_timeout: u64,
+    /// Seconds between pings the server sends on each data connection (0 disables)
+    #[clap(long, default_value = "30")]
+    pub ping_interval: u64,
+    /// Close sessions that send no frame, pongs included, for this many seconds (0 disables)
+    #[clap(long, default_value = "90")]
+    pub client_timeout: u64,
+    /// Close sessions older than this many seconds (0 disables)
+    #[clap(long, default_value = "0")]
+    pub client_max_lifetime: u64,
+    /// Close sessions that send no tunneled packet within this many seconds of connecting (0 disables)
+    #[clap(long, default_value = "0")]
+    pub first_packet_timeout: u64,
+    /// Source port handling of the NAT rule
+    #[clap(long, value_enum, default_value_t = fw::NatPortMode::Preserve)]
+    pub nat_port_mode: fw::NatPortMode,
+    /// Tool that installs the NAT rule (default: iptables if installed, else nftables)
+    #[clap(long, value_enum)]
+    pub firewall_backend: Option<fw::FirewallBackend>,
+    /// SNAT to this address with --persistent instead of masquerading
+    #[clap(long)]
+    pub snat_address: Option<IpAddr>,
+    /// MTU of the TUN device, also pushed to clients without their own (default: the device's)
+    #[clap(long)]
+    pub mtu: Option<u16>,
+    /// Networks clients should route through the tunnel, in CIDR notation (comma separated)
+    #[clap(long, value_delimiter = ',')]
+    pub push_route: Vec<IpNet>,
+    /// DNS servers pushed to clients (comma separated)
+    #[clap(long, value_delimiter = ',')]
+    pub dns_server: Vec<IpAddr>,
+    /// Domains clients should resolve through the pushed DNS servers (comma separated)
+    #[clap(long, value_delimiter = ',')]
+    pub dns_domain: Vec<String>,
+    /// How clients address their TUN device: a shared subnet or a point-to-point link to the server
+    #[clap(long, value_enum, default_value_t = control::AddressingMode::Subnet)]
+    pub addressing_mode: control::AddressingMode,
+    /// Account tunneled traffic per L4 protocol (TCP/UDP/ICMP/other)
+    #[clap(long)]
+    pub protocol_stats: bool,
+    /// Reject clients that don't offer the httpstun.v1 WebSocket subprotocol
+    #[clap(long)]
+    pub require_subprotocol: bool,
+    /// Cap on total tunneled throughput across all clients and both directions, in kbit/s (0 disables)
+    #[clap(long, default_value = "0")]
+    pub max_throughput_kbps: u64,
+    /// Cap on each session's throughput in bit/s per direction, unless its client's config entry sets rate_limit_bps (0 disables)
+    #[clap(long, default_value = "0")]
+    pub client_rate_limit_bps: u64,
+    /// Largest WebSocket message accepted from a client in bytes, whole or pieced together from continuation frames
+    #[clap(long, default_value = "9001")]
+    pub max_frame_size: usize,
+    /// Key encrypting every tunneled packet, 32 bytes in base64; clients must be given the same --psk
+    #[clap(long)]
+    pub psk: Option<String>,
+    /// Packets queued toward each client
+    #[clap(long, alias = "max-queue-depth", default_value = "256")]
+    pub client_queue: usize,
+    /// What to do with a packet for a client whose queue is full
+    #[clap(long, value_enum, default_value_t = QueueOverflowPolicy::DropNewest)]
+    pub client_queue_overflow: QueueOverflowPolicy,
+    /// Packets held for each client whose session dropped and delivered if it reconnects in time (0 disables)
+    #[clap(long, default_value = "0")]
+    pub reconnect_buffer: usize,
+    /// Seconds a packet held for a disconnected client stays worth delivering
+    #[clap(long, default_value = "10")]
+    pub reconnect_buffer_max_age: u64,
+    /// Packets from clients queued for the TUN device; clients are read from no faster than it drains
+    #[clap(long, default_value = "1024")]
+    pub tun_queue: usize,
+    /// Seconds a shutdown waits for clients to close their sessions before dropping them
+    #[clap(long, default_value = "5")]
+    pub shutdown_grace: u64,
+    /// Deliver packets between clients directly instead of dropping them
+    #[clap(long)]
+    pub allow_client_to_client: bool,
+    /// Answer packets for clients that aren't connected with ICMP host unreachable
+    #[clap(long)]
+    pub icmp_unreachable: bool,
+    /// Most ICMP unreachable errors, and separately most ICMP too-big errors, sent per second
+    #[clap(long, default_value = "10")]
+    pub icmp_unreachable_rate: u32,
+    /// HTTP status for requests that fail authentication (default 404, or 302 with --unauthenticated-redirect)
+    #[clap(long)]
+    pub unauthenticated_status: Option<u16>,
+    /// Redirect requests that fail authentication to this URL
+    #[clap(long)]
+    pub unauthenticated_redirect: Option<String>,
+    /// File served as the body of responses to requests that fail authentication
+    #[clap(long)]
+    pub unauthenticated_body: Option<String>,
+    /// File served with a 200 to requests for the tunnel path that aren't WebSocket upgrades
+    #[clap(long, conflicts_with = "decoy_dir")]
+    pub decoy_html: Option<String>,
+    /// Directory of a static site served like --decoy-html (its index.html) and at other paths
+    #[clap(long)]
+    pub decoy_dir: Option<String>,
+    /// Failed logins from one address after which it is refused for --auth-ban seconds (0 disables)
+    #[clap(long, default_value = "10")]
+    pub auth_max_failures: u32,
+    /// Seconds over which failed logins are counted
+    #[clap(long, default_value = "60")]
+    pub auth_failure_window: u64,
+    /// Seconds an address is refused after too many failed logins
+    #[clap(long, default_value = "300")]
+    pub auth_ban: u64,
+    /// Seconds a session token issued at a password login stays valid for reconnects (0 disables)
+    #[clap(long, default_value = "3600")]
+    pub session_token_lifetime: u64,
+    /// Optional protocol features clients must support (comma separated)
+    #[clap(long, value_delimiter = ',')]
+    pub require_feature: Vec<String>,
+    /// Use io_uring for TUN reads and writes
+    #[cfg(feature = "io-uring")]
+    #[clap(long)]
+    pub io_uring: bool,
+    /// Accept credentials as `name`/`password` query parameters on TLS connections
+    #[clap(long)]
+    pub allow_query_auth: bool,
+    /// File holding a secret pepper mixed into password hashes (else $HTTPSTUN_PEPPER, if set)
+    #[clap(long)]
+    pub pepper_file: Option<String>,
+    /// Accept hashes made before the pepper was configured, re-hashing them on login
+    #[clap(long)]
+    pub pepper_migrate: bool,
+    /// Check adding, authenticating and removing a client on a scratch config file, then exit
+    #[clap(long)]
+    #[serde(skip)]
+    pub self_test: bool,
+    /// Check the config, the server address, the NAT interfaces and the firewall tools, print a summary and exit
+    #[clap(long)]
+    #[serde(skip)]
+    pub check: bool,
+    /// Append session accounting records (JSON lines) to this file
+    #[clap(long)]
+    pub accounting_log: Option<String>,
+    /// Seconds between interim accounting records for connected sessions (0 disables)
+    #[clap(long, default_value = "0")]
+    pub accounting_interval: u64,
+    /// Send connection events (connect, disconnect, auth failure, rejection, kick) to the system logger
+    #[clap(long, value_enum)]
+    pub connection_log: Option<syslog::Target>,
+    /// Syslog facility of connection events
+    #[clap(long, value_enum, default_value_t = syslog::Facility::Auth)]
+    pub syslog_facility: syslog::Facility,
+    /// Export flow records of tunneled traffic over UDP to this collector
+    #[clap(long)]
+    pub flow_collector: Option<SocketAddr>,
+    /// Encoding of exported flow records
+    #[clap(long, value_enum, default_value_t = flow::FlowFormat::Ipfix)]
+    pub flow_format: flow::FlowFormat,
+    /// Seconds without packets after which a flow ends
+    #[clap(long, default_value = "15")]
+    pub flow_idle_timeout: u64,
+    /// Seconds between records of long-running flows
+    #[clap(long, default_value = "60")]
+    pub flow_active_timeout: u64,
+    /// Most flows tracked at once; when full, the least recently active one is exported early
+    #[clap(long, default_value = "65536")]
+    pub flow_max_flows: usize,
+    /// Remove leftover httpstun firewall rules and exit
+    #[clap(long)]
+    #[serde(skip)]
+    pub cleanup: bool,
+    /// With --cleanup, also delete TUN interfaces matching this name (trailing * allowed)
+    #[clap(long)]
+    #[serde(skip)]
+    pub cleanup_interfaces: Option<String>,
+    /// Serve Prometheus metrics at /metrics, without authentication
+    #[clap(long)]
+    pub metrics: bool,
+    /// Serve a health check for load balancers at /healthz, without authentication
+    #[clap(long)]
+    pub health_check: bool,
+    /// Serve the client management API on this port, apart from the tunnel; requires --admin-token
+    #[clap(long)]
+    pub admin_port: Option<u16>,
+    /// Address the client management API listens on
+    #[clap(long, default_value = "127.0.0.1")]
+    pub admin_host: String,
+    /// Bearer token the client management API requires of every request
+    #[clap(long)]
+    pub admin_token: Option<String>,
+    /// Accept admin commands on this UNIX socket, one per line, answered in JSON
+    #[clap(long)]
+    pub control_socket: Option<String>,
+    /// Tell systemd when the server is ready and ping its watchdog (for Type=notify units)
+    #[clap(long)]
+    pub systemd: bool,
+    /// What to do with SIGHUPs that arrive while a restart is in progress
+    #[clap(long, value_enum, default_value_t = SighupPolicy::Coalesce)]
+    pub sighup_policy: SighupPolicy,
+    /// Switch to this user once the TUN device, NAT rule and listeners are set up
+    #[clap(long)]
+    pub run_as_user: Option<String>,
+    /// Group to switch to with --run-as-user (default: the user's primary group)
+    #[clap(long, requires = "run_as_user")]
+    pub run_as_group: Option<String>,
+    // started by --run-as-user to remove the firewall rules once the server exits
+    #[clap(long, hide = true)]
+    #[serde(skip)]
+    pub cleanup_helper: bool,
+}
+
+// Handling of SIGHUPs received while a restart is already underway. Signals arriving before
+// the exec are always dropped, since the new process reads the config afresh; this decides
+// the fate of those arriving while the new process starts up.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SighupPolicy {
+    /// Fold them into a single reload once the new process is up
+    #[default]
+    Coalesce,
+    /// Drop them
+    Ignore,
+}
+
+// Handling of a client whose assigned IP is held by a different, still connected client,
+// e.g. when two config entries share an address
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpConflictPolicy {
+    /// Turn the newcomer away
+    #[default]
+    Reject,
+    /// Disconnect the current holder and take over the address
+    Evict,
+}
+
+// Handling of a packet for a client that doesn't drain its queue fast enough
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflowPolicy {
+    /// Drop the new packet
+    #[default]
+    DropNewest,
+    /// Drop the packet that has waited longest to make room for the new one
+    DropOldest,
+    /// Drop the new packet and close the session
+    Disconnect,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args::parse_from([env!("CARGO_PKG_NAME")])
+    }
+}
+
+impl Args {
+    // The server's address within its subnet, as given by --server-ip and --netmask
+    pub fn subnet(&self) -> Result<IpNet, String> {
+        let prefix_len = self.netmask.prefix_len(self.server_ip)?;
+        IpNet::new(self.server_ip, prefix_len).map_err(|e| format!("Invalid server subnet: {}", e))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Client {
+    pub name: String,
+    pub token : String,
+    pub ip : IpAddr,
+    // Networks behind the client, e.g. a site's LAN, routed to it and accepted as its sources
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_ips: Vec<IpNet>,
+    // Destinations this client may send to; empty means allow all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_destinations: Vec<IpNet>,
+    // MTU pushed to this client, overriding the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+    // Simultaneous sessions allowed for this client, overriding --max-sessions-per-client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<u32>,
+    // Throughput cap on each of this client's sessions in bit/s, overriding --client-rate-limit-bps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_bps: Option<u64>,
+    // Bootstrap protocols allowed to use a source other than the client's IP
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub special_sources: Vec<SpecialSource>,
+}
+
+// Well-known source addresses protocols use before a host has its address, exempted from
+// the anti-spoofing check for clients that opt in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpecialSource {
+    // DHCP discovery and requests from 0.0.0.0 (RFC 2131 4.1)
+    Dhcp,
+    // IPv6 link-local sources, and :: for duplicate address detection (RFC 4862 5.4)
+    LinkLocal,
+}
+
+impl SpecialSource {
+    fn permits(self, src: IpAddr, pkt: &etherparse::SlicedPacket) -> bool {
+        use etherparse::TransportSlice;
+        match (self, src) {
+            (SpecialSource::Dhcp, IpAddr::V4(v4)) => v4.is_unspecified()
+                && matches!(&pkt.transport, Some(TransportSlice::Udp(udp)) if udp.source_port() == 68 && udp.destination_port() == 67),
+            (SpecialSource::LinkLocal, IpAddr::V6(v6)) => v6.is_unicast_link_local()
+                || (v6.is_unspecified() && matches!(&pkt.transport, Some(TransportSlice::Icmpv6(_)))),
+            _ => false,
+        }
+    }
+}
+
+impl Client {
+    // The exemption letting a packet from `src` past the anti-spoofing check, if any
+    pub fn special_source(&self, src: IpAddr, pkt: &etherparse::SlicedPacket) -> Option<SpecialSource> {
+        self.special_sources.iter().copied().find(|exemption| exemption.permits(src, pkt))
+    }
+
+    pub fn session_limit(&self, args: &Args) -> u32 {
+        self.max_sessions.unwrap_or(args.max_sessions_per_client)
+    }
+
+    // 0 means unlimited
+    pub fn rate_limit(&self, args: &Args) -> u64 {
+        self.rate_limit_bps.unwrap_or(args.client_rate_limit_bps)
+    }
+
+    pub fn may_reach(&self, dst: &IpAddr) -> bool {
+        self.allowed_destinations.is_empty() || self.allowed_destinations.iter().any(|net| net.contains(dst))
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub server_args: Args,
+    clients: Vec<Client>,
+    // Uplinks to spread client egress over; when empty, everything leaves through
+    // --external-interface-name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub egress: Vec<fw::Egress>,
+    // built from `clients` on the first lookup, and dropped whenever they change
+    #[serde(skip)]
+    routes: OnceLock<routing::ClientRoutes>,
+}
+
+impl Config {
+    // A config with no clients, as a server started without a config file has
+    pub fn new(server_args: Args) -> Self {
+        Config { server_args, clients: vec![], egress: vec![], routes: OnceLock::new() }
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    // The client packets for `ip` go to: the one with that address, or else the one whose
+    // allowed_ips hold it with the longest prefix
+    pub fn client_for(&self, ip: &IpAddr) -> Option<&Client> {
+        let routes = self.routes.get_or_init(|| routing::ClientRoutes::new(&self.clients));
+        routes.lookup(ip).map(|i| &self.clients[i])
+    }
+
+    pub fn clients_mut(&mut self) -> &mut Vec<Client> {
+        self.routes = OnceLock::new();
+        &mut self.clients
+    }
+
+    // Every client's allowed_ips, which the TUN device must be routed
+    pub fn client_networks(&self) -> Vec<IpNet> {
+        self.clients.iter().flat_map(|c| c.allowed_ips.iter().map(IpNet::trunc)).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(port) = self.server_args.admin_port {
+            if self.server_args.admin_token.as_deref().is_none_or(str::is_empty) {
+                return Err("--admin-port requires --admin-token".to_string());
+            }
+            if port == self.server_args.port {
+                return Err("--admin-port must differ from --port".to_string());
+            }
+        }
+        if self.server_args.host.iter().all(|host| host.trim().is_empty()) {
+            return Err("No address to listen on; --host must not be empty".to_string());
+        }
+        ws::validate_path(&self.server_args)?;
+        for feature in &self.server_args.require_feature {
+            if !control::SUPPORTED_FEATURES.contains(&feature.as_str()) {
+                return Err(format!("Required feature {} is not supported by this server", feature));
+            }
+        }
+        validate_server_ip(&self.server_args)?;
+        ip_pool(&self.server_args)?;
+        unauthenticated::UnauthenticatedResponse::load(&self.server_args)?;
+        decoy::Decoy::load(&self.server_args)?;
+        self.tls()?;
+        // only pongs keep a quiet but healthy client from timing out
+        if self.server_args.client_timeout != 0 && self.server_args.client_timeout <= self.server_args.ping_interval {
+            return Err("--client-timeout must be longer than --ping-interval".to_string());
+        }
+        if self.server_args.client_timeout != 0 && self.server_args.ping_interval == 0 {
+            return Err("--client-timeout needs --ping-interval".to_string());
+        }
+        let sealing = match psk::Psk::load(self.server_args.psk.as_deref())? {
+            Some(_) => psk::OVERHEAD,
+            None => 0,
+        };
+        // a packet of the largest MTU clients are told to use, behind the compression byte and sealed with --psk
+        let largest_mtu = self.clients.iter().filter_map(|c| c.mtu).chain(self.server_args.mtu).max().unwrap_or(DEFAULT_MTU);
+        if self.server_args.max_frame_size <= usize::from(largest_mtu) + sealing {
+            return Err(format!("--max-frame-size {} is too small for packets of the {} byte MTU clients use", self.server_args.max_frame_size, largest_mtu));
+        }
+        if self.server_args.client_queue == 0 || self.server_args.tun_queue == 0 {
+            return Err("--client-queue and --tun-queue must be positive".to_string());
+        }
+        if self.server_args.reconnect_buffer != 0 && self.server_args.reconnect_buffer_max_age == 0 {
+            return Err("--reconnect-buffer-max-age must be positive".to_string());
+        }
+        if self.server_args.flow_collector.is_some() {
+            let args = &self.server_args;
+            if args.flow_idle_timeout == 0 || args.flow_active_timeout == 0 || args.flow_max_flows == 0 {
+                return Err("--flow-idle-timeout, --flow-active-timeout and --flow-max-flows must be positive".to_string());
+            }
+        }
+        fw::validate_interface_name(&self.server_args.tun_interface_name)?;
+        fw::validate_interface_name(&self.server_args.external_interface_name)?;
+        for uplink in &self.egress {
+            fw::validate_interface_name(&uplink.interface)?;
+        }
+        if self.egress.len() > fw::MAX_EGRESS {
+            return Err(format!("At most {} egress interfaces are supported", fw::MAX_EGRESS));
+        }
+        if let Some(uplink) = self.egress.iter().find(|e| e.weight == 0) {
+            return Err(format!("Egress interface {} has weight 0", uplink.interface));
+        }
+        if !self.egress.is_empty() && self.server_args.snat_address.is_some() {
+            return Err("--snat-address can't be combined with multiple egress interfaces".to_string());
+        }
+        if let Some(addr) = self.server_args.snat_address
+            && !fw::nat_families(self).contains(&fw::Family::of(addr)) {
+            return Err(format!("--snat-address {} is {}, which no client sends", addr, fw::Family::of(addr).name()));
+        }
+        // the registry and the TUN routing are keyed on the client IP, so a shared one would
+        // silently send a client's traffic to whichever session registered last
+        let subnet = self.server_args.subnet()?.trunc();
+        let mut names = HashSet::new();
+        let mut ips = HashMap::new();
+        let mut networks = HashMap::new();
+        if let Some(mtu) = self.server_args.mtu
+            && !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            return Err(format!("MTU {} is outside of {}..={}", mtu, MIN_MTU, MAX_MTU));
+        }
+        for client in &self.clients {
+            if let Err(e) = PasswordHash::new(&client.token) {
+                return Err(format!("Client {} has an invalid password hash: {}", client.name, e));
+            }
+            if !names.insert(client.name.as_str()) {
+                return Err(format!("Client name {} is used more than once", client.name));
+            }
+            if let Some(other) = ips.insert(client.ip, client.name.as_str()) {
+                return Err(format!("Clients {} and {} have the same IP {}", other, client.name, client.ip));
+            }
+            if !subnet.contains(&client.ip) {
+                return Err(format!("Client {} has IP {} outside of the server subnet {}", client.name, client.ip, subnet));
+            }
+            if client.ip == self.server_args.server_ip {
+                return Err(format!("Client {} has the server's IP {}", client.name, client.ip));
+            }
+            // packets for it would go to the whole subnet, or nowhere, rather than the client
+            if let Some(kind) = reserved_address(subnet, client.ip) {
+                return Err(format!("Client {} has IP {}, the {} address of {}", client.name, client.ip, kind, subnet));
+            }
+            // routed into the TUN device, so they must leave the server's own subnet to it
+            for net in &client.allowed_ips {
+                if net.contains(&subnet.network()) || subnet.contains(&net.network()) {
+                    return Err(format!("Client {} has allowed IPs {} overlapping the server subnet {}", client.name, net, subnet));
+                }
+                if let Some(other) = networks.insert(net.trunc(), client.name.as_str()) {
+                    return Err(format!("Clients {} and {} both have allowed IPs {}", other, client.name, net.trunc()));
+                }
+            }
+            match client.session_limit(&self.server_args) {
+                0 => return Err(format!("Client {} allows no sessions; remove it instead", client.name)),
+                1 => {}
+                // return traffic is routed by the client's one IP, so only the newest would get any
+                limit => return Err(format!("Client {} allows {} sessions (--max-sessions-per-client or max_sessions); only 1 is supported, as its sessions would share one IP", client.name, limit)),
+            }
+            if let Some(mtu) = client.mtu
+                && !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+                return Err(format!("Client {} has MTU {} outside of {}..={}", client.name, mtu, MIN_MTU, MAX_MTU));
+            }
+        }
+        Ok(())
+    }
+
+    // The TLS config to serve with, if --tls-cert and --tls-key are set. One without the other
+    // is an error rather than a silent fallback to plain HTTP.
+    pub fn tls(&self) -> Result<Option<rustls::ServerConfig>, String> {
+        match (&self.server_args.tls_cert, &self.server_args.tls_key) {
+            (Some(cert), Some(key)) => tls::load(cert, key).map(Some),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("--tls-cert needs --tls-key".to_string()),
+            (None, Some(_)) => Err("--tls-key needs --tls-cert".to_string()),
+        }
+    }
+}
+
+// The TUN address must be usable as a host address in its own subnet; the kernel accepts the
+// others, but the interface then silently fails to talk to clients
+pub fn validate_server_ip(args: &Args) -> Result<(), String> {
+    let ip = args.server_ip;
+    if ip.is_unspecified() || ip.is_multicast() {
+        return Err(format!("Server IP {} is not a unicast host address", ip));
+    }
+    let net = args.subnet()?;
+    if let Some(kind) = reserved_address(net, ip) {
+        return Err(format!("Server IP {} is the {} address of {}", ip, kind, net.trunc()));
+    }
+    Ok(())
+}
+
+// Whether `ip` is the network or broadcast address of an IPv4 subnet, which no host can use.
+// /31 and /32 have neither (RFC 3021).
+fn reserved_address(net: IpNet, ip: IpAddr) -> Option<&'static str> {
+    match net {
+        IpNet::V4(net) if net.prefix_len() <= 30 && ip == IpAddr::V4(net.network()) => Some("network"),
+        IpNet::V4(net) if net.prefix_len() <= 30 && ip == IpAddr::V4(net.broadcast()) => Some("broadcast"),
+        _ => None,
+    }
+}
+
+// The range clients added without an address are given one from, within the server's subnet
+fn ip_pool(args: &Args) -> Result<IpAddrRange, String> {
+    let net = args.subnet()?.trunc();
+    let start = args.ip_pool_start.or_else(|| net.hosts().next());
+    let end = args.ip_pool_end.or_else(|| net.hosts().next_back());
+    let (Some(start), Some(end)) = (start, end) else {
+        return Err(format!("Subnet {} has no host addresses", net));
+    };
+    if let Some(bound) = [start, end].into_iter().find(|ip| !net.contains(ip)) {
+        return Err(format!("IP pool bound {} is outside of the server subnet {}", bound, net));
+    }
+    match (start, end) {
+        (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => Ok(Ipv4AddrRange::new(start, end).into()),
+        (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => Ok(Ipv6AddrRange::new(start, end).into()),
+        _ => Err(format!("IP pool start {} is after its end {}", start, end)),
+    }
+}
+
+// The lowest pool address that is neither the server's nor assigned to a client
+pub fn allocate_ip(args: &Args, clients: &[Client]) -> Result<IpAddr, String> {
+    let mut pool = ip_pool(args)?;
+    let net = args.subnet()?;
+    // the scan ends within clients.len() + 4 steps however large the pool
+    pool.find(|ip| *ip != args.server_ip && reserved_address(net, *ip).is_none() && !clients.iter().any(|c| c.ip == *ip))
+        .ok_or_else(|| "IP pool is exhausted; add the client with an explicit IP or widen the pool".to_string())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NotFound(String),
+    Io(String, std::io::Error),
+    Parse { path: String, line: usize, column: usize, message: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NotFound(path) => write!(f, "Config file {} not found", path),
+            ConfigError::Io(path, e) => write!(f, "Unable to read config file {}: {}", path, e),
+            ConfigError::Parse { path, line, column, message } => {
+                write!(f, "Invalid config file {} at line {}, column {}: {}", path, line, column, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn parse_config(file_path: &str) -> Result<Config, ConfigError> {
+    let config_content = std::fs::read_to_string(file_path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => ConfigError::NotFound(file_path.to_string()),
+        _ => ConfigError::Io(file_path.to_string(), e),
+    })?;
+    ConfigFormat::from_path(file_path).parse(&config_content).map_err(|e| ConfigError::Parse {
+        path: file_path.to_string(),
+        line: e.line,
+        column: e.column,
+        message: e.message,
+    })
+}
+
+// Write the config back in the format of its file. The new content goes to a temporary file
+// in the same directory that is renamed over the old one, so a crash mid-write leaves either
+// the old config or the new one, never a truncated file.
+pub fn write_config(file_path: &str, config: &Config) -> Result<(), String> {
+    let content = ConfigFormat::from_path(file_path).serialize(config).map_err(|e| format!("Failed to serialize config: {}", e))?;
+    let path = resolve_config_path(file_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
+    };
+    let name = path.file_name().ok_or_else(|| format!("Config file path {} names no file", file_path))?;
+    let temp = dir.join(format!(".{}.{}.tmp", name.to_string_lossy(), std::process::id()));
+    let written = replace_file(&path, &temp, content.as_bytes())
+        .and_then(|()| std::fs::File::open(&dir)?.sync_all());
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written.map_err(|e| format!("Unable to write config file: {}", e))
+}
+
+// Where a config file really is: a symlinked config is replaced and locked at its target,
+// not at the link
+fn resolve_config_path(file_path: &str) -> std::path::PathBuf {
+    std::fs::canonicalize(file_path).unwrap_or_else(|_| std::path::PathBuf::from(file_path))
+}
+
+fn replace_file(path: &std::path::Path, temp: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    // private until told otherwise, as the file holds password hashes
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(temp)?;
+    if let Ok(existing) = std::fs::metadata(path) {
+        file.set_permissions(existing.permissions())?;
+    }
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(temp, path)
+}
+
+// Held while a config file is read, changed and written back, so that concurrent edits
+// (add-client and remove-client runs, the prompt, password re-hashing) don't lose each
+// other's changes. The lock is on a file beside the config, which every write replaces.
+pub struct ConfigLock {
+    _file: std::fs::File,
+}
+
+pub fn lock_config(file_path: &str) -> Result<ConfigLock, String> {
+    let mut lock_path = resolve_config_path(file_path).into_os_string();
+    lock_path.push(".lock");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Unable to open config lock file {}: {}", lock_path.to_string_lossy(), e))?;
+    file.lock().map_err(|e| format!("Unable to lock config file {}: {}", file_path, e))?;
+    Ok(ConfigLock { _file: file })
+}
+
+// `host = "0.0.0.0"`, as config files from before several addresses could be given have it,
+// or a list of addresses
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hosts {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Hosts::deserialize(deserializer)? {
+        Hosts::One(hosts) => hosts.split(',').map(|h| h.trim().to_string()).collect(),
+        Hosts::Many(hosts) => hosts,
+    })
+}
+
+impl Args {
+    // The addresses to listen on, with --port added to those that don't name a port
+    pub fn bind_addresses(&self) -> Vec<String> {
+        self.host.iter().map(|host| {
+            if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                SocketAddr::new(ip, self.port).to_string()
+            } else if host.parse::<SocketAddr>().is_ok() || host.contains(':') {
+                host.clone()
+            } else {
+                format!("{}:{}", host, self.port)
+            }
+        }).collect()
+    }
+}
+
+pub fn override_config_with_args(mut config: Config, args: &Args) -> Config {
+    config.server_args = args.clone();
+    config
+}
+
+// Load the config file the way startup does, but report what is wrong with it instead of
+// falling back to command line arguments only
+pub fn check_config(args: &Args) -> Result<Config, String> {
+    let config = parse_config(&args.config_file).map_err(|e| e.to_string())?;
+    let config = override_config_with_args(config, args);
+    config.validate()?;
+    Ok(config)
+}
+
+pub fn is_valid_ip(ip: &IpAddr, config: &Config) -> bool {
+    config.client_for(ip).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_ip(ip: &str, netmask: &str) -> Result<(), String> {
+        validate_server_ip(&Args::parse_from(["httpstun_server", "--server-ip", ip, "--netmask", netmask]))
+    }
+
+    #[test]
+    fn server_ip_must_be_a_host_address_of_its_subnet() {
+        assert_eq!(server_ip("10.0.0.0", "24"), Err("Server IP 10.0.0.0 is the network address of 10.0.0.0/24".to_string()));
+        assert_eq!(server_ip("10.0.0.255", "255.255.255.0"), Err("Server IP 10.0.0.255 is the broadcast address of 10.0.0.0/24".to_string()));
+        assert!(server_ip("0.0.0.0", "24").unwrap_err().contains("not a unicast host address"));
+        assert!(server_ip("224.0.0.1", "24").unwrap_err().contains("not a unicast host address"));
+        assert!(server_ip("ff02::1", "64").unwrap_err().contains("not a unicast host address"));
+        assert_eq!(server_ip("10.0.0.1", "24"), Ok(()));
+        assert_eq!(server_ip("10.0.0.254", "24"), Ok(()));
+    }
+
+    #[test]
+    fn point_to_point_and_host_subnets_have_no_reserved_addresses() {
+        assert_eq!(server_ip("10.0.0.0", "31"), Ok(()));
+        assert_eq!(server_ip("10.0.0.1", "31"), Ok(()));
+        assert_eq!(server_ip("10.0.0.7", "32"), Ok(()));
+        assert!(server_ip("10.0.0.0", "30").is_err());
+    }
+
+    #[test]
+    fn ipv6_subnets_have_no_broadcast_address() {
+        assert_eq!(server_ip("fd00::", "64"), Ok(()));
+        assert_eq!(server_ip("fd00::ffff:ffff:ffff:ffff", "64"), Ok(()));
+        assert_eq!(server_ip("fd00::1", "64"), Ok(()));
+        assert!(server_ip("::", "64").is_err());
+    }
+}
+
+
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+// The format of a config file, told by its extension. Anything but .json, .yaml and .yml is
+// TOML, as every config file was before the others were supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+// Where in the file parsing failed, 1-based, and why
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &str) -> ConfigFormat {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T, ParseError> {
+        match self {
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e: toml::de::Error| {
+                // counted up to the start of the offending span
+                let offset = e.span().map_or(0, |span| span.start);
+                let before = &content[..offset.min(content.len())];
+                ParseError {
+                    line: before.matches('\n').count() + 1,
+                    column: before.chars().rev().take_while(|&c| c != '\n').count() + 1,
+                    message: e.message().to_string(),
+                }
+            }),
+            ConfigFormat::Json => serde_json::from_str(content).map_err(|e| {
+                let (line, column) = (e.line(), e.column());
+                ParseError { line, column, message: without_location(e.to_string(), line, column) }
+            }),
+            ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| {
+                let (line, column) = e.location().map_or((1, 1), |l| (l.line(), l.column()));
+                ParseError { line, column, message: without_location(e.to_string(), line, column) }
+            }),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String, String> {
+        match self {
+            ConfigFormat::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map(|json| json + "\n").map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// serde_json and serde_yaml end their messages with the location, which is reported apart
+fn without_location(message: String, line: usize, column: usize) -> String {
+    let suffix = format!(" at line {} column {}", line, column);
+    match message.strip_suffix(&suffix) {
+        Some(message) => message.to_string(),
+        None => message,
+    }
+}
@@ -0,0 +1,187 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+// Control messages sent from the server to a client as JSON text frames
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    SessionConfig(SessionConfig),
+    Redirect(Redirect),
+    SessionStats(SessionStats),
+    Pong(Pong),
+}
+
+// Control messages a client may send as JSON text frames, on either connection. Types this
+// server doesn't know are ignored, so newer clients can talk to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Describes the client software, for the logs
+    ClientInfo(ClientInfo),
+    // Asks for a session_stats message right away
+    Stats,
+    // Answered with a pong carrying the same nonce, to measure latency through the server
+    Ping(Ping),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClientInfo {
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub platform: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Ping {
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pong {
+    pub nonce: u64,
+}
+
+// How clients address their end of the tunnel
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressingMode {
+    /// Clients share the server's subnet
+    #[default]
+    Subnet,
+    /// Each client gets a host address with the server as its only on-link peer
+    PointToPoint,
+}
+
+// Address the client should give its TUN device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TunAddress {
+    pub ip: IpAddr,
+    pub prefix_len: u8,
+    // far end of a point-to-point link; absent in subnet mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<IpAddr>,
+}
+
+impl TunAddress {
+    // `server` is the server's address with the subnet's prefix length
+    pub fn for_client(mode: AddressingMode, client_ip: IpAddr, server: IpNet) -> Self {
+        match mode {
+            AddressingMode::Subnet => TunAddress { ip: client_ip, prefix_len: server.prefix_len(), peer: None },
+            AddressingMode::PointToPoint => {
+                let prefix_len = if client_ip.is_ipv4() { 32 } else { 128 };
+                TunAddress { ip: client_ip, prefix_len, peer: Some(server.addr()) }
+            }
+        }
+    }
+}
+
+// Settings the client should apply to its TUN device for this session
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<TunAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+    // networks the client should route through the tunnel
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<IpNet>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<IpAddr>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_domains: Vec<String>,
+    // Optional protocol features both ends agreed to use for this session
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+// Tells the client to close the session and reconnect to another server, e.g. before this
+// one is decommissioned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Redirect {
+    pub url: String,
+}
+
+// Live counters of the session, pushed periodically over a separate control connection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionStats {
+    pub connected_secs: u64,
+    pub bytes_from_client: u64,
+    pub bytes_to_client: u64,
+    pub packets_from_client: u64,
+    pub packets_to_client: u64,
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("control messages always serialize")
+    }
+}
+
+// Bounds for the MTU pushed to clients; the upper bound matches the TUN read buffer
+pub const MIN_MTU: u16 = 576;
+pub const MAX_MTU: u16 = 9000;
+
+// WebSocket subprotocol naming this wire protocol; bumped on incompatible changes
+pub const WS_SUBPROTOCOL: &str = "httpstun.v1";
+
+// Feature moving control messages onto a second WebSocket at /control, so the data
+// connection carries nothing but packets. The upgrade response of the data connection then
+// carries SESSION_ID_HEADER, which the client sends back when opening the control connection.
+pub const CONTROL_CHANNEL: &str = "control-channel";
+pub const SESSION_ID_HEADER: &str = "X-Httpstun-Session-Id";
+
+// Issued by the server after a password login and presented instead of the password on
+// reconnects, sparing it an Argon2 verification each time
+pub const SESSION_TOKEN_HEADER: &str = "X-Httpstun-Session-Token";
+
+// Close codes from the range RFC 6455 leaves to applications, for sessions closed because the
+// client can no longer log in, so it knows reconnecting won't help
+pub const CLOSE_UNAUTHORIZED: u16 = 4001;
+pub const CLOSE_CLIENT_REMOVED: u16 = 4002;
+
+// Optional protocol features this version implements, offered by clients in the
+// X-Httpstun-Features header
+pub const SUPPORTED_FEATURES: &[&str] = &[CONTROL_CHANNEL];
+
+// Pick the features to use from what the client offered. Fails with the required features
+// the client didn't offer, since tunneling without them would garble the stream.
+pub fn negotiate_features(offered: &[String], required: &[String]) -> Result<Vec<String>, Vec<String>> {
+    let missing: Vec<String> = required.iter().filter(|f| !offered.contains(f)).cloned().collect();
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+    Ok(SUPPORTED_FEATURES.iter()
+        .filter(|f| offered.iter().any(|o| o == *f))
+        .map(|f| f.to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(names: &[&str]) -> Vec<String> {
+        names.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn unoffered_required_features_are_reported() {
+        let offered = features(&["lz4-frames"]);
+        let required = features(&[CONTROL_CHANNEL, "lz4-frames", "other"]);
+        assert_eq!(negotiate_features(&offered, &required), Err(features(&[CONTROL_CHANNEL, "other"])));
+    }
+
+    #[test]
+    fn offered_features_are_narrowed_to_supported_ones() {
+        let offered = features(&["unknown", CONTROL_CHANNEL]);
+        assert_eq!(negotiate_features(&offered, &[]), Ok(features(&[CONTROL_CHANNEL])));
+        assert_eq!(negotiate_features(&features(&["unknown"]), &[]), Ok(vec![]));
+        assert_eq!(negotiate_features(&offered, &features(&[CONTROL_CHANNEL])), Ok(features(&[CONTROL_CHANNEL])));
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::{Component, Path, PathBuf};
+
+use actix_web::{http::{header, Method}, web, web::Bytes, HttpRequest, HttpResponse};
+
+use crate::unauthenticated::{content_type, UnauthenticatedResponse};
+use crate::Args;
+
+// What plain page loads of the tunnel path get, so probing the server without a WebSocket
+// upgrade finds an ordinary website rather than a 404
+#[derive(Debug, Clone)]
+pub enum Decoy {
+    // --decoy-html, served at the tunnel path only
+    Page { content_type: &'static str, body: Bytes },
+    // --decoy-dir: its index.html at the tunnel path, and its other files at their own paths
+    Dir(PathBuf),
+}
+
+impl Decoy {
+    pub fn load(args: &Args) -> Result<Option<Self>, String> {
+        match (&args.decoy_html, &args.decoy_dir) {
+            (Some(_), Some(_)) => Err("--decoy-html and --decoy-dir can't be combined".to_string()),
+            (Some(path), None) => {
+                let body = std::fs::read(path).map_err(|e| format!("Failed to read decoy page {}: {}", path, e))?;
+                Ok(Some(Decoy::Page { content_type: content_type(path), body: Bytes::from(body) }))
+            }
+            (None, Some(dir)) => {
+                if !Path::new(dir).is_dir() {
+                    return Err(format!("Decoy directory {} is not a directory", dir));
+                }
+                Ok(Some(Decoy::Dir(PathBuf::from(dir))))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    // The page for a request to the tunnel path that isn't a WebSocket upgrade
+    pub async fn index(&self) -> Option<HttpResponse> {
+        match self {
+            Decoy::Page { content_type, body } => Some(HttpResponse::Ok().content_type(*content_type).body(body.clone())),
+            Decoy::Dir(dir) => serve_file(&dir.join("index.html")).await,
+        }
+    }
+
+    // A file of the decoy site for any other path; read on every request, so the site can be
+    // edited without a restart
+    async fn file(&self, path: &str) -> Option<HttpResponse> {
+        let Decoy::Dir(dir) = self else {
+            return None;
+        };
+        let mut file = dir.clone();
+        for component in Path::new(path.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => file.push(part),
+                Component::CurDir => {}
+                // nothing outside the directory
+                _ => return None,
+            }
+        }
+        if file.is_dir() {
+            file.push("index.html");
+        }
+        serve_file(&file).await
+    }
+}
+
+// Whether a request asks for a WebSocket, as opposed to being a page load
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    req.headers().get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+async fn serve_file(path: &Path) -> Option<HttpResponse> {
+    let body = tokio::fs::read(path).await.ok()?;
+    Some(HttpResponse::Ok().content_type(content_type(&path.to_string_lossy())).body(body))
+}
+
+// Every path but the tunnel's: files of --decoy-dir, else the unauthenticated response
+pub async fn decoy_service(req: HttpRequest, decoy: web::Data<Decoy>, unauthenticated: web::Data<UnauthenticatedResponse>) -> HttpResponse {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return unauthenticated.respond();
+    }
+    match decoy.file(req.path()).await {
+        Some(response) => response,
+        None => unauthenticated.respond(),
+    }
+}
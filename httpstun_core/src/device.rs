@@ -0,0 +1,89 @@
+use std::future::Future;
+use std::io;
+
+use bytes::Bytes;
+use tappers::{AddAddress, DeviceState, Interface, Tun};
+use tokio::io::unix::AsyncFd;
+
+use crate::pool::PacketPool;
+
+// Most packets taken from the device per wake-up of the data plane
+pub const BATCH_SIZE: usize = 32;
+
+// Packet I/O of the server's TUN device. The data plane in `tun.rs` is written against this
+// so the tokio and io_uring backends share the routing and anti-spoofing logic.
+pub trait TunDevice {
+    fn send(&self, packet: Bytes) -> impl Future<Output = io::Result<usize>> + Send;
+    // Append the packets already waiting, up to BATCH_SIZE in all, waiting only for the first
+    fn recv_batch(&self, pool: &mut PacketPool, packets: &mut Vec<Bytes>) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+// The TUN device on the tokio reactor, for the server and the client alike. tappers' own
+// AsyncTun::new_named leaves the fd in blocking mode, so a read with no packet waiting would
+// stall the whole runtime thread.
+pub struct AsyncTun(AsyncFd<Tun>);
+
+impl AsyncTun {
+    pub fn new(mut tun: Tun) -> io::Result<Self> {
+        tun.set_nonblocking(true)?;
+        Ok(AsyncTun(AsyncFd::new(tun)?))
+    }
+
+    pub fn new_named(if_name: Interface) -> io::Result<Self> {
+        Self::new(Tun::new_named(if_name)?)
+    }
+
+    pub fn add_addr(&self, req: AddAddress) -> io::Result<()> {
+        self.0.get_ref().add_addr(req)
+    }
+
+    pub fn set_state(&mut self, state: DeviceState) -> io::Result<()> {
+        self.0.get_mut().set_state(state)
+    }
+
+    // One packet at a time, for callers without a PacketPool
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(result) = guard.try_io(|tun| tun.get_ref().recv(buf)) {
+                return result;
+            }
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.writable().await?;
+            if let Ok(result) = guard.try_io(|tun| tun.get_ref().send(buf)) {
+                return result;
+            }
+        }
+    }
+}
+
+impl TunDevice for AsyncTun {
+    async fn send(&self, packet: Bytes) -> io::Result<usize> {
+        AsyncTun::send(self, &packet).await
+    }
+
+    async fn recv_batch(&self, pool: &mut PacketPool, packets: &mut Vec<Bytes>) -> io::Result<()> {
+        let start = packets.len();
+        loop {
+            let mut guard = self.0.readable().await?;
+            // one readiness wake-up drains the device until it would block
+            while packets.len() < BATCH_SIZE {
+                match guard.get_inner().recv(pool.slot()) {
+                    Ok(size) => packets.push(pool.take(size)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready();
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if packets.len() > start || packets.len() == BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+}
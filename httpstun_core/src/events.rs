@@ -0,0 +1,45 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+use tokio::sync::broadcast;
+
+use crate::syslog::Event;
+
+// Connection events as JSON for subscribers to the admin API's /events, published wherever
+// they go to the system logger. Only set up with the admin API; nothing is kept for
+// subscribers that aren't listening.
+static CHANNEL: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+// Events a slow subscriber may fall behind by before it misses some
+const CAPACITY: usize = 256;
+
+pub fn enable() {
+    CHANNEL.get_or_init(|| broadcast::channel(CAPACITY).0);
+}
+
+pub fn subscribe() -> Option<broadcast::Receiver<String>> {
+    CHANNEL.get().map(broadcast::Sender::subscribe)
+}
+
+pub fn publish(event: &Event) {
+    let Some(channel) = CHANNEL.get() else {
+        return;
+    };
+    if channel.receiver_count() == 0 {
+        return;
+    }
+    let mut fields = Map::new();
+    fields.insert("event".to_string(), event.name().into());
+    fields.insert("timestamp".to_string(), SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).into());
+    for (key, value) in event.fields() {
+        fields.insert(key.to_string(), value.into());
+    }
+    // fails only when the last subscriber just left
+    let _ = channel.send(Value::Object(fields).to_string());
+}
+
+// What a subscriber that fell behind gets in place of the events it missed
+pub fn lagged(missed: u64) -> String {
+    json!({ "event": "lagged", "missed": missed }).to_string()
+}
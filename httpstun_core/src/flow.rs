@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_channel::{Receiver, Sender, TrySendError};
+use etherparse::{SlicedPacket, TransportSlice};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use crate::Args;
+use crate::stats::{self, Stats};
+
+// Records waiting for the exporter; beyond this they are dropped rather than stall the data path
+const EXPORT_QUEUE: usize = 4096;
+// Records encoded per exporter wakeup
+const MAX_BATCH: usize = 256;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlowFormat {
+    /// IPFIX (RFC 7011) messages
+    #[default]
+    Ipfix,
+    /// One JSON object per datagram
+    Json,
+}
+
+// Why a flow record was exported, numbered as IPFIX flowEndReason
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EndReason {
+    IdleTimeout = 1,
+    ActiveTimeout = 2,
+    EndOfFlow = 3,
+    ForcedEnd = 4,
+    LackOfResources = 5,
+}
+
+// Unidirectional 5-tuple. ICMP flows carry type and code in the destination port, as NetFlow
+// does; packets without a parsed transport header (non-initial fragments) have ports 0.
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+struct FlowKey {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    src_port: u16,
+    dst_port: u16,
+}
+
+impl FlowKey {
+    // The flow a packet belongs to, and its TCP flags
+    fn of(pkt: &SlicedPacket) -> Option<(Self, u8)> {
+        let (src, dst) = match &pkt.net {
+            Some(etherparse::NetSlice::Ipv4(ip)) => (IpAddr::V4(ip.header().source_addr()), IpAddr::V4(ip.header().destination_addr())),
+            Some(etherparse::NetSlice::Ipv6(ip)) => (IpAddr::V6(ip.header().source_addr()), IpAddr::V6(ip.header().destination_addr())),
+            _ => return None,
+        };
+        let protocol = pkt.net.as_ref().and_then(|net| net.ip_payload_ref())?.ip_number.0;
+        let (src_port, dst_port, flags) = match &pkt.transport {
+            Some(TransportSlice::Tcp(tcp)) => {
+                let flags = [tcp.fin(), tcp.syn(), tcp.rst(), tcp.psh(), tcp.ack(), tcp.urg(), tcp.ece(), tcp.cwr()]
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |acc, (bit, set)| acc | ((*set as u8) << bit));
+                (tcp.source_port(), tcp.destination_port(), flags)
+            }
+            Some(TransportSlice::Udp(udp)) => (udp.source_port(), udp.destination_port(), 0),
+            Some(TransportSlice::Icmpv4(icmp)) => (0, u16::from_be_bytes([icmp.type_u8(), icmp.code_u8()]), 0),
+            Some(TransportSlice::Icmpv6(icmp)) => (0, u16::from_be_bytes([icmp.type_u8(), icmp.code_u8()]), 0),
+            None => (0, 0, 0),
+        };
+        Some((FlowKey { src, dst, protocol, src_port, dst_port }, flags))
+    }
+}
+
+const TCP_FIN: u8 = 0x01;
+const TCP_RST: u8 = 0x04;
+
+struct Flow {
+    first: SystemTime,
+    last: SystemTime,
+    started: Instant,
+    last_seen: Instant,
+    bytes: u64,
+    packets: u64,
+    tcp_flags: u8,
+    // position in FlowTable::order
+    touched: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FlowRecord {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    src_port: u16,
+    dst_port: u16,
+    tcp_flags: u8,
+    bytes: u64,
+    packets: u64,
+    start_ms: u64,
+    end_ms: u64,
+    end_reason: EndReason,
+}
+
+impl FlowRecord {
+    fn new(key: &FlowKey, flow: &Flow, end_reason: EndReason) -> Self {
+        FlowRecord {
+            src: key.src,
+            dst: key.dst,
+            protocol: key.protocol,
+            src_port: key.src_port,
+            dst_port: key.dst_port,
+            tcp_flags: flow.tcp_flags,
+            bytes: flow.bytes,
+            packets: flow.packets,
+            start_ms: epoch_ms(flow.first),
+            end_ms: epoch_ms(flow.last),
+            end_reason,
+        }
+    }
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// Active flows of tunneled traffic, owned by the TUN handler. Flows end after
+// --flow-idle-timeout without packets or on a TCP FIN/RST, long-running ones are reported
+// every --flow-active-timeout, and when the table is full the least recently active flow is
+// exported early to make room.
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    // flows by last packet, oldest first
+    order: BTreeMap<u64, FlowKey>,
+    next_touch: u64,
+    max_flows: usize,
+    idle_timeout: Duration,
+    active_timeout: Duration,
+    export: Sender<FlowRecord>,
+    stats: Arc<Stats>,
+}
+
+impl FlowTable {
+    pub fn record(&mut self, pkt: &SlicedPacket, bytes: usize) {
+        let Some((key, flags)) = FlowKey::of(pkt) else {
+            return;
+        };
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let touch = self.next_touch;
+        self.next_touch += 1;
+        if let Some(flow) = self.flows.get_mut(&key) {
+            self.order.remove(&flow.touched);
+            flow.touched = touch;
+            flow.last = wall;
+            flow.last_seen = now;
+            flow.bytes += bytes as u64;
+            flow.packets += 1;
+            flow.tcp_flags |= flags;
+        } else {
+            if self.flows.len() >= self.max_flows {
+                self.evict_oldest();
+            }
+            self.flows.insert(key, Flow {
+                first: wall,
+                last: wall,
+                started: now,
+                last_seen: now,
+                bytes: bytes as u64,
+                packets: 1,
+                tcp_flags: flags,
+                touched: touch,
+            });
+        }
+        self.order.insert(touch, key);
+        if flags & (TCP_FIN | TCP_RST) != 0 {
+            self.end(&key, EndReason::EndOfFlow);
+        }
+    }
+
+    // Called about once a second: ends idle flows and reports long-running ones
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some((_, key)) = self.order.first_key_value() {
+            let key = *key;
+            match self.flows.get(&key) {
+                Some(flow) if now.duration_since(flow.last_seen) < self.idle_timeout => break,
+                Some(_) => self.end(&key, EndReason::IdleTimeout),
+                None => {
+                    self.order.pop_first();
+                }
+            }
+        }
+        let wall = SystemTime::now();
+        let mut reports = Vec::new();
+        for (key, flow) in self.flows.iter_mut() {
+            if now.duration_since(flow.started) < self.active_timeout {
+                continue;
+            }
+            if flow.packets > 0 {
+                reports.push(FlowRecord::new(key, flow, EndReason::ActiveTimeout));
+            }
+            flow.first = wall;
+            flow.started = now;
+            flow.bytes = 0;
+            flow.packets = 0;
+            flow.tcp_flags = 0;
+        }
+        for record in reports {
+            self.send(record);
+        }
+    }
+
+    // Export every active flow, on shutdown
+    pub fn flush(&mut self) {
+        self.order.clear();
+        for (key, flow) in std::mem::take(&mut self.flows) {
+            if flow.packets > 0 {
+                self.send(FlowRecord::new(&key, &flow, EndReason::ForcedEnd));
+            }
+        }
+    }
+
+    fn end(&mut self, key: &FlowKey, reason: EndReason) {
+        if let Some(flow) = self.flows.remove(key) {
+            self.order.remove(&flow.touched);
+            if flow.packets > 0 {
+                self.send(FlowRecord::new(key, &flow, reason));
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.order.first_key_value() {
+            let key = *key;
+            stats::bump(&self.stats.flows.evicted);
+            self.end(&key, EndReason::LackOfResources);
+        }
+    }
+
+    fn send(&self, record: FlowRecord) {
+        if let Err(TrySendError::Full(_)) = self.export.try_send(record) {
+            stats::bump(&self.stats.flows.queue_dropped);
+        }
+    }
+}
+
+// Connect to the collector and start the exporter; None when flow export is off
+pub async fn start(args: &Args, stats: Arc<Stats>) -> io::Result<Option<FlowTable>> {
+    let Some(collector) = args.flow_collector else {
+        return Ok(None);
+    };
+    let local: SocketAddr = match collector {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(collector).await?;
+    let (tx, rx) = async_channel::bounded(EXPORT_QUEUE);
+    info!("Exporting flow records to {} as {:?}", collector, args.flow_format);
+    tokio::spawn(run_exporter(socket, rx, args.flow_format, stats.clone()));
+    Ok(Some(FlowTable {
+        flows: HashMap::new(),
+        order: BTreeMap::new(),
+        next_touch: 0,
+        max_flows: args.flow_max_flows,
+        idle_timeout: Duration::from_secs(args.flow_idle_timeout),
+        active_timeout: Duration::from_secs(args.flow_active_timeout),
+        export: tx,
+        stats,
+    }))
+}
+
+async fn run_exporter(socket: UdpSocket, records: Receiver<FlowRecord>, format: FlowFormat, stats: Arc<Stats>) {
+    let mut ipfix = IpfixEncoder::default();
+    let mut failing = false;
+    while let Ok(first) = records.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH
+            && let Ok(record) = records.try_recv() {
+            batch.push(record);
+        }
+        let datagrams = match format {
+            FlowFormat::Ipfix => ipfix.encode(&batch),
+            FlowFormat::Json => batch.iter().filter_map(|r| serde_json::to_vec(r).ok().map(|d| (d, 1))).collect(),
+        };
+        for (datagram, count) in datagrams {
+            match socket.send(&datagram).await {
+                Ok(_) => {
+                    if failing {
+                        info!("Flow export to the collector recovered");
+                        failing = false;
+                    }
+                    stats.flows.exported.fetch_add(count as u64, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    // a collector that isn't listening answers with ICMP, failing every send
+                    if !failing {
+                        warn!("Failed to export flow records: {}", e);
+                        failing = true;
+                    }
+                    stats.flows.send_failed.fetch_add(count as u64, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+const IPFIX_VERSION: u16 = 10;
+const IPFIX_HEADER_LEN: usize = 16;
+const IPFIX_SET_HEADER_LEN: usize = 4;
+const TEMPLATE_SET_ID: u16 = 2;
+const OBSERVATION_DOMAIN: u32 = 1;
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+// Templates are resent this often, so a restarted collector can decode again
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(30);
+// Stay below common path MTUs so messages aren't fragmented
+const MAX_MESSAGE_LEN: usize = 1400;
+
+// (information element, length) pairs, in the order write_record emits them
+const FIELDS_V4: &[(u16, u16)] = &[
+    (8, 4),    // sourceIPv4Address
+    (12, 4),   // destinationIPv4Address
+    (7, 2),    // sourceTransportPort
+    (11, 2),   // destinationTransportPort
+    (4, 1),    // protocolIdentifier
+    (6, 2),    // tcpControlBits
+    (1, 8),    // octetDeltaCount
+    (2, 8),    // packetDeltaCount
+    (152, 8),  // flowStartMilliseconds
+    (153, 8),  // flowEndMilliseconds
+    (136, 1),  // flowEndReason
+];
+const FIELDS_V6: &[(u16, u16)] = &[
+    (27, 16),  // sourceIPv6Address
+    (28, 16),  // destinationIPv6Address
+    (7, 2),
+    (11, 2),
+    (4, 1),
+    (6, 2),
+    (1, 8),
+    (2, 8),
+    (152, 8),
+    (153, 8),
+    (136, 1),
+];
+
+fn record_len(fields: &[(u16, u16)]) -> usize {
+    fields.iter().map(|(_, len)| *len as usize).sum()
+}
+
+fn template_set() -> Vec<u8> {
+    let mut set = Vec::new();
+    for (id, fields) in [(TEMPLATE_V4, FIELDS_V4), (TEMPLATE_V6, FIELDS_V6)] {
+        set.extend(id.to_be_bytes());
+        set.extend((fields.len() as u16).to_be_bytes());
+        for (element, len) in fields {
+            set.extend(element.to_be_bytes());
+            set.extend(len.to_be_bytes());
+        }
+    }
+    let mut out = Vec::with_capacity(IPFIX_SET_HEADER_LEN + set.len());
+    out.extend(TEMPLATE_SET_ID.to_be_bytes());
+    out.extend(((IPFIX_SET_HEADER_LEN + set.len()) as u16).to_be_bytes());
+    out.extend(set);
+    out
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &FlowRecord) {
+    for ip in [record.src, record.dst] {
+        match ip {
+            IpAddr::V4(ip) => buf.extend(ip.octets()),
+            IpAddr::V6(ip) => buf.extend(ip.octets()),
+        }
+    }
+    buf.extend(record.src_port.to_be_bytes());
+    buf.extend(record.dst_port.to_be_bytes());
+    buf.push(record.protocol);
+    buf.extend((record.tcp_flags as u16).to_be_bytes());
+    buf.extend(record.bytes.to_be_bytes());
+    buf.extend(record.packets.to_be_bytes());
+    buf.extend(record.start_ms.to_be_bytes());
+    buf.extend(record.end_ms.to_be_bytes());
+    buf.push(record.end_reason as u8);
+}
+
+#[derive(Default)]
+struct IpfixEncoder {
+    // data records sent so far, for the message header
+    sequence: u32,
+    templates_sent: Option<Instant>,
+}
+
+impl IpfixEncoder {
+    // IPFIX messages for the records, each with the number of records it carries
+    fn encode(&mut self, records: &[FlowRecord]) -> Vec<(Vec<u8>, usize)> {
+        let templates = template_set();
+        let mut messages = Vec::new();
+        for (template, fields) in [(TEMPLATE_V4, FIELDS_V4), (TEMPLATE_V6, FIELDS_V6)] {
+            let family: Vec<&FlowRecord> = records.iter().filter(|r| r.src.is_ipv4() == (template == TEMPLATE_V4)).collect();
+            // leave room for the templates in every message, whether or not they are due
+            let per_message = (MAX_MESSAGE_LEN - IPFIX_HEADER_LEN - templates.len() - IPFIX_SET_HEADER_LEN) / record_len(fields);
+            for chunk in family.chunks(per_message) {
+                let mut message = vec![0u8; IPFIX_HEADER_LEN];
+                if self.templates_sent.is_none_or(|sent| sent.elapsed() >= TEMPLATE_REFRESH) {
+                    message.extend(&templates);
+                    self.templates_sent = Some(Instant::now());
+                }
+                message.extend(template.to_be_bytes());
+                message.extend(((IPFIX_SET_HEADER_LEN + chunk.len() * record_len(fields)) as u16).to_be_bytes());
+                for record in chunk {
+                    write_record(&mut message, record);
+                }
+                let export_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+                let mut header = Vec::with_capacity(IPFIX_HEADER_LEN);
+                header.extend(IPFIX_VERSION.to_be_bytes());
+                header.extend((message.len() as u16).to_be_bytes());
+                header.extend(export_time.to_be_bytes());
+                header.extend(self.sequence.to_be_bytes());
+                header.extend(OBSERVATION_DOMAIN.to_be_bytes());
+                message[..IPFIX_HEADER_LEN].copy_from_slice(&header);
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+                messages.push((message, chunk.len()));
+            }
+        }
+        messages
+    }
+}
@@ -0,0 +1,481 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Config;
+
+// How the NAT rule treats client source ports. Keeping ports is more predictable for
+// port-sensitive protocols but more clients behind one address can collide and exhaust
+// ports; randomizing avoids collisions and port-prediction at the cost of predictability.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatPortMode {
+    /// Keep the client's source port when it is free (kernel default)
+    #[default]
+    Preserve,
+    /// Randomize the source port only on collision (--random)
+    Random,
+    /// Always randomize the source port (--random-fully)
+    RandomFully,
+}
+
+// Which tool installs the NAT rule and the egress marking
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirewallBackend {
+    /// iptables, with rules tagged by a comment naming the tunnel
+    Iptables,
+    /// nft, with the rules in a table of their own per tunnel
+    Nftables,
+}
+
+impl FirewallBackend {
+    pub fn binary(self) -> &'static str {
+        match self {
+            FirewallBackend::Iptables => "iptables",
+            FirewallBackend::Nftables => "nft",
+        }
+    }
+
+    // The configured backend, or without one, iptables if it is installed and nft otherwise
+    pub fn select(configured: Option<FirewallBackend>) -> Result<FirewallBackend, String> {
+        match configured {
+            Some(backend) if binary_exists(backend.binary()) => Ok(backend),
+            Some(backend) => Err(format!("{} is not installed, but --firewall-backend asks for it", backend.binary())),
+            None => [FirewallBackend::Iptables, FirewallBackend::Nftables]
+                .into_iter()
+                .find(|backend| binary_exists(backend.binary()))
+                .ok_or_else(|| "Neither iptables nor nft is installed, one of them is needed for the NAT rule".to_string()),
+        }
+    }
+}
+
+fn masquerade_rule_args(tun_if_name: &str, external_if_name: &str, port_mode: NatPortMode, snat_address: Option<IpAddr>) -> Vec<String> {
+    let mut args: Vec<String> = ["-t", "nat", "-A", "POSTROUTING", "-o", external_if_name]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    match snat_address {
+        // SNAT to a fixed address, mapping each client to the same source address
+        Some(addr) => args.extend(["-j".to_string(), "SNAT".to_string(), "--to-source".to_string(), addr.to_string(), "--persistent".to_string()]),
+        None => args.extend(["-j".to_string(), "MASQUERADE".to_string()]),
+    }
+    match port_mode {
+        NatPortMode::Preserve => {}
+        NatPortMode::Random => args.push("--random".to_string()),
+        NatPortMode::RandomFully => args.push("--random-fully".to_string()),
+    }
+    args.extend([
+        "-m".to_string(),
+        "comment".to_string(),
+        "--comment".to_string(),
+        format!("httpstun_masquerade_{}", tun_if_name),
+    ]);
+    args
+}
+
+// Address family of tunneled traffic, each NATed by its own iptables binary and nat table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: IpAddr) -> Family {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    pub fn iptables(self) -> &'static str {
+        match self {
+            Family::V4 => "iptables",
+            Family::V6 => "ip6tables",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        }
+    }
+}
+
+// One rule per family; --snat-address only applies to its own family, the other masquerades
+pub fn create_masquerade_rule(tun_if_name: &str, external_if_name: &str, port_mode: NatPortMode, snat_address: Option<IpAddr>, families: &[Family]) -> Result<(), String> {
+    for &family in families {
+        let binary = family.iptables();
+        let snat_address = snat_address.filter(|addr| Family::of(*addr) == family);
+        let output = std::process::Command::new(binary)
+            .args(masquerade_rule_args(tun_if_name, external_if_name, port_mode, snat_address))
+            .output()
+            .map_err(|e| format!("Failed to execute {} command: {}", binary, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to add {} masquerade rule: {}",
+                family.name(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+    }
+    Ok(())
+}
+
+const COMMENT_PREFIX: &str = "httpstun_masquerade_";
+
+// Chains httpstun installs rules in, as (table, chain)
+const TAGGED_CHAINS: [(&str, &str); 2] = [("nat", "POSTROUTING"), ("mangle", "PREROUTING")];
+
+// Delete every rule of `table`/`chain` accepted by `matches` using the given iptables binary,
+// whatever interface or target it was created with. Returns the deleted rules.
+fn remove_rules_matching(binary: &str, table: &str, chain: &str, matches: impl Fn(&[&str]) -> bool) -> Result<Vec<String>, String> {
+    let output = std::process::Command::new(binary)
+        .args(["-t", table, "-S", chain])
+        .output()
+        .map_err(|e| format!("Failed to execute {} command: {}", binary, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to list masquerade rules: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let mut removed = Vec::new();
+    for rule in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut args: Vec<&str> = rule.split_whitespace().collect();
+        if args.first() != Some(&"-A") || !matches(&args) {
+            continue;
+        }
+        args[0] = "-D";
+        let output = std::process::Command::new(binary)
+            .args(["-t", table])
+            .args(&args)
+            .output()
+            .map_err(|e| format!("Failed to execute {} command: {}", binary, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to remove existing masquerade rule: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        removed.push(rule.to_string());
+    }
+    Ok(removed)
+}
+
+// Delete every rule tagged with this tunnel's comment (NAT and egress marking). Returns how
+// many rules were removed. iptables is always searched, since the egress marking is IPv4
+// whatever the tunnel carries; ip6tables when the tunnel carries IPv6.
+pub fn remove_existing_masquerade_rules_with_comment(tun_if_name: &str, families: &[Family]) -> Result<usize, String> {
+    let comment = format!("{}{}", COMMENT_PREFIX, tun_if_name);
+    let mut removed = 0;
+    let mut binaries = vec![Family::V4.iptables()];
+    if families.contains(&Family::V6) {
+        binaries.push(Family::V6.iptables());
+    }
+    for binary in binaries {
+        for (table, chain) in TAGGED_CHAINS {
+            removed += remove_rules_matching(binary, table, chain, |args| args.contains(&comment.as_str()))?.len();
+        }
+    }
+    Ok(removed)
+}
+
+// Delete httpstun-tagged rules for any tunnel from every installed iptables family, and the
+// httpstun tables from nftables. Returns the removed rules per binary; binaries that aren't
+// installed are skipped.
+pub fn remove_all_httpstun_rules() -> Vec<(&'static str, Result<Vec<String>, String>)> {
+    let mut results: Vec<_> = ["iptables", "ip6tables"]
+        .into_iter()
+        .filter(|binary| binary_exists(binary))
+        .map(|binary| {
+            let mut removed = Vec::new();
+            for (table, chain) in TAGGED_CHAINS {
+                match remove_rules_matching(binary, table, chain, |args| args.iter().any(|a| a.starts_with(COMMENT_PREFIX))) {
+                    Ok(rules) => removed.extend(rules),
+                    Err(e) => return (binary, Err(e)),
+                }
+            }
+            (binary, Ok(removed))
+        })
+        .collect();
+    if binary_exists("nft") {
+        results.push(("nft", crate::nft::remove_all()));
+    }
+    results
+}
+
+// An uplink for client traffic. With several configured, new connections are spread across
+// them in proportion to their weights.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Egress {
+    pub interface: String,
+    // next hop on this uplink; may be omitted for point-to-point links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// Each uplink gets a connmark and a routing table holding its default route
+pub const MAX_EGRESS: usize = 16;
+const EGRESS_MARK_BASE: u32 = 0x4854_0000;
+const EGRESS_TABLE_BASE: u32 = 48540;
+
+fn run_ip(args: &[String]) -> Result<String, String> {
+    let output = std::process::Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("ip {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn run_iptables(args: &[String]) -> Result<(), String> {
+    let output = std::process::Command::new("iptables")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute iptables command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Failed to add egress rule: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
+
+pub fn egress_mark(index: usize) -> u32 {
+    EGRESS_MARK_BASE + index as u32
+}
+
+// The chance of each uplink's marking rule taking a new connection. The rules are sequential,
+// each taking its share of what the previous ones left unmarked, and the last one takes the
+// rest (None).
+pub fn egress_shares(egress: &[Egress]) -> Vec<Option<f64>> {
+    let mut remaining: u32 = egress.iter().map(|e| e.weight).sum();
+    egress.iter().enumerate().map(|(i, uplink)| {
+        let share = (i + 1 < egress.len()).then(|| uplink.weight as f64 / remaining as f64);
+        remaining -= uplink.weight;
+        share
+    }).collect()
+}
+
+// Give each uplink a routing table whose default route leaves through it, selected by the
+// uplink's mark
+pub fn create_egress_routing(egress: &[Egress]) -> Result<(), String> {
+    remove_egress_routing()?;
+    for (i, uplink) in egress.iter().enumerate() {
+        let mark = format!("{:#x}", egress_mark(i));
+        let table = (EGRESS_TABLE_BASE + i as u32).to_string();
+        let mut route: Vec<String> = ["route", "replace", "default"].iter().map(|a| a.to_string()).collect();
+        if let Some(gateway) = uplink.gateway {
+            route.extend(["via".to_string(), gateway.to_string()]);
+        }
+        route.extend(["dev".to_string(), uplink.interface.clone(), "table".to_string(), table.clone()]);
+        run_ip(&route)?;
+        run_ip(&["rule", "add", "fwmark", &mark, "table", &table].map(String::from))?;
+    }
+    Ok(())
+}
+
+// Spread client egress over several uplinks: new connections from the tunnel get a connmark
+// picked at random by weight, the mark selects a routing table whose default route leaves
+// through that uplink, and each uplink masquerades what it sends. Only IPv4 is spread; IPv6
+// leaves as the main routing table says, and is masqueraded by whichever uplink that is.
+pub fn create_egress_rules(tun_if_name: &str, egress: &[Egress], port_mode: NatPortMode, families: &[Family]) -> Result<(), String> {
+    create_egress_routing(egress)?;
+    let comment = format!("{}{}", COMMENT_PREFIX, tun_if_name);
+    for ((i, uplink), share) in egress.iter().enumerate().zip(egress_shares(egress)) {
+        let mark = format!("{:#x}", egress_mark(i));
+        let mut args: Vec<String> = ["-t", "mangle", "-A", "PREROUTING", "-i", tun_if_name,
+            "-m", "conntrack", "--ctstate", "NEW", "-m", "connmark", "--mark", "0"]
+            .iter().map(|a| a.to_string()).collect();
+        if let Some(probability) = share {
+            args.extend(["-m", "statistic", "--mode", "random", "--probability"].map(String::from));
+            args.push(format!("{:.5}", probability));
+        }
+        args.extend(["-j", "CONNMARK", "--set-mark", &mark, "-m", "comment", "--comment", &comment].map(String::from));
+        run_iptables(&args)?;
+
+        create_masquerade_rule(tun_if_name, &uplink.interface, port_mode, None, families)?;
+    }
+    // copy the connection's mark to each packet so routing can see it
+    run_iptables(&["-t", "mangle", "-A", "PREROUTING", "-i", tun_if_name, "-j", "CONNMARK", "--restore-mark",
+        "-m", "comment", "--comment", &comment].map(String::from))
+}
+
+// Remove the policy routing rules and tables installed by create_egress_rules. Returns how
+// many routing rules were removed.
+pub fn remove_egress_routing() -> Result<usize, String> {
+    let tables = EGRESS_TABLE_BASE..EGRESS_TABLE_BASE + MAX_EGRESS as u32;
+    let mut removed = 0;
+    for line in run_ip(&["rule".to_string(), "show".to_string()])?.lines() {
+        let mut words = line.split_whitespace();
+        let Some(priority) = words.next().and_then(|p| p.strip_suffix(':')) else {
+            continue;
+        };
+        let ours = line.split_whitespace()
+            .skip_while(|w| *w != "lookup")
+            .nth(1)
+            .and_then(|t| t.parse::<u32>().ok())
+            .is_some_and(|t| tables.contains(&t));
+        if ours {
+            run_ip(&["rule", "del", "priority", priority].map(String::from))?;
+            removed += 1;
+        }
+    }
+    for table in tables {
+        // flushing an empty table fails harmlessly
+        let _ = run_ip(&["route".to_string(), "flush".to_string(), "table".to_string(), table.to_string()]);
+    }
+    Ok(removed)
+}
+
+// Linux interface names must fit IFNAMSIZ (16 bytes including the NUL)
+const MAX_INTERFACE_NAME: usize = 15;
+
+// Reject names the kernel would refuse, mirroring dev_valid_name(), so the user gets a clear
+// message instead of an opaque error from deep inside TUN creation or iptables
+pub fn validate_interface_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Interface name is empty".to_string());
+    }
+    if name.len() > MAX_INTERFACE_NAME {
+        return Err(format!("Interface name {} is {} bytes long, Linux allows at most {}", name, name.len(), MAX_INTERFACE_NAME));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("Interface name {} is reserved", name));
+    }
+    if let Some(c) = name.chars().find(|c| *c == '/' || *c == ':' || c.is_whitespace()) {
+        return Err(format!("Interface name {:?} contains invalid character {:?}", name, c));
+    }
+    Ok(())
+}
+
+// An interface the NAT rules name must exist, or iptables happily installs a rule that never
+// matches while nft refuses with an error that doesn't say why. Unchecked where sysfs isn't there
+// to ask.
+pub fn require_interface(role: &str, name: &str) -> Result<(), String> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/net") else {
+        return Ok(());
+    };
+    let mut available: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    if available.iter().any(|interface| interface == name) {
+        return Ok(());
+    }
+    available.sort();
+    Err(format!("{} interface {} not found, available interfaces: {}", role, name, available.join(", ")))
+}
+
+pub fn binary_exists(binary: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| dir.join(binary).is_file())
+    })
+}
+
+// TUN devices whose name matches `pattern` (a trailing `*` matches any suffix)
+pub fn find_tun_interfaces(pattern: &str) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/net") else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+        .filter(|name| std::path::Path::new("/sys/class/net").join(name).join("tun_flags").exists())
+        .collect()
+}
+
+// Route networks behind clients into the TUN device, so the kernel hands the server their traffic
+pub fn route_to_tun(tun_if_name: &str, nets: &[IpNet]) -> Result<(), String> {
+    for net in nets {
+        run_ip(&["route", "replace", &net.to_string(), "dev", tun_if_name].map(String::from))?;
+    }
+    Ok(())
+}
+
+pub fn unroute_from_tun(tun_if_name: &str, nets: &[IpNet]) -> Result<(), String> {
+    for net in nets {
+        run_ip(&["route", "del", &net.to_string(), "dev", tun_if_name].map(String::from))?;
+    }
+    Ok(())
+}
+
+pub fn set_mtu(if_name: &str, mtu: u16) -> Result<(), String> {
+    run_ip(&["link", "set", "dev", if_name, "mtu", &mtu.to_string()].map(String::from)).map(|_| ())
+}
+
+pub fn delete_interface(if_name: &str) -> Result<(), String> {
+    let output = std::process::Command::new("ip")
+        .args(["link", "delete", if_name])
+        .output()
+        .map_err(|e| format!("Failed to execute ip command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to delete interface {}: {}",
+            if_name,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+// Install the NAT rule, or the per-uplink marking, routing and NAT rules when several
+// egress interfaces are configured
+pub fn install_firewall(tun_if_name: &str, config: &Config) -> Result<(), String> {
+    let (args, egress) = (&config.server_args, &config.egress);
+    match FirewallBackend::select(args.firewall_backend)? {
+        FirewallBackend::Iptables if egress.is_empty() => {
+            create_masquerade_rule(tun_if_name, &args.external_interface_name, args.nat_port_mode, args.snat_address, &nat_families(config))
+        }
+        FirewallBackend::Iptables => create_egress_rules(tun_if_name, egress, args.nat_port_mode, &nat_families(config)),
+        FirewallBackend::Nftables => {
+            crate::nft::install(tun_if_name, &args.external_interface_name, args.nat_port_mode, args.snat_address, egress)
+        }
+    }
+}
+
+// The address families clients send from: the tunnel subnet's, and those of the networks
+// behind clients. A tunnel with both is dual-stack and gets a NAT rule for each.
+pub fn nat_families(config: &Config) -> Vec<Family> {
+    let mut families: Vec<Family> = std::iter::once(config.server_args.server_ip)
+        .chain(config.clients().iter().flat_map(|c| c.allowed_ips.iter().map(IpNet::addr)))
+        .map(Family::of)
+        .collect();
+    families.sort();
+    families.dedup();
+    families
+}
+
+// The interfaces install_firewall would NAT out of, which only the host can tell exist
+pub fn check_external_interfaces(config: &Config) -> Result<(), String> {
+    if config.egress.is_empty() {
+        return require_interface("External", &config.server_args.external_interface_name);
+    }
+    for uplink in &config.egress {
+        require_interface("Egress", &uplink.interface)?;
+    }
+    Ok(())
+}
+
+// Remove the NAT rule and egress marking of a tunnel. Returns how many rules were removed.
+pub fn remove_firewall(tun_if_name: &str, config: &Config) -> Result<usize, String> {
+    match FirewallBackend::select(config.server_args.firewall_backend)? {
+        FirewallBackend::Iptables => remove_existing_masquerade_rules_with_comment(tun_if_name, &nat_families(config)),
+        FirewallBackend::Nftables => crate::nft::remove(tun_if_name),
+    }
+}
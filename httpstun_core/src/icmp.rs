@@ -0,0 +1,80 @@
+use std::net::IpAddr;
+
+use etherparse::{icmpv4, icmpv6, Icmpv4Type, Icmpv6Type, PacketBuilder, SlicedPacket, TransportSlice};
+
+// An ICMPv6 error must fit the IPv6 minimum MTU, original packet included (RFC 4443 2.4(c))
+const IPV6_MIN_MTU: usize = 1280;
+const IPV4_ERROR_TYPES: [u8; 5] = [3, 4, 5, 11, 12];
+
+// Build a "host unreachable" (ICMPv4 3/1) or "address unreachable" (ICMPv6 1/3) error from
+// `server_ip` back to the sender of `original`. None when no error may be sent for it: for
+// ICMP errors, non-initial fragments and unspecified or multicast sources (RFC 1122 3.2.2,
+// RFC 4443 2.4(e)), or when the server has no address in the packet's family.
+pub fn host_unreachable(original: &[u8], pkt: &SlicedPacket, server_ip: IpAddr) -> Option<Vec<u8>> {
+    error_reply(
+        original,
+        pkt,
+        server_ip,
+        Icmpv4Type::DestinationUnreachable(icmpv4::DestUnreachableHeader::Host),
+        Icmpv6Type::DestinationUnreachable(icmpv6::DestUnreachableCode::Address),
+    )
+}
+
+// Build a "fragmentation needed" (ICMPv4 3/4) or "packet too big" (ICMPv6 2) error carrying
+// `mtu`, which path MTU discovery on the sender of `original` waits for. IPv4 packets without
+// the don't-fragment bit get none (RFC 1191); otherwise as for host_unreachable.
+pub fn too_big(original: &[u8], pkt: &SlicedPacket, server_ip: IpAddr, mtu: usize) -> Option<Vec<u8>> {
+    if let Some(etherparse::NetSlice::Ipv4(ip)) = &pkt.net
+        && !ip.header().dont_fragment() {
+        return None;
+    }
+    error_reply(
+        original,
+        pkt,
+        server_ip,
+        Icmpv4Type::DestinationUnreachable(icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: u16::try_from(mtu).unwrap_or(u16::MAX) }),
+        Icmpv6Type::PacketTooBig { mtu: mtu as u32 },
+    )
+}
+
+// An ICMP error of the given type from `server_ip` back to the sender of `original`, quoting it
+fn error_reply(original: &[u8], pkt: &SlicedPacket, server_ip: IpAddr, v4: Icmpv4Type, v6: Icmpv6Type) -> Option<Vec<u8>> {
+    let payload = pkt.net.as_ref()?.ip_payload_ref()?;
+    if payload.fragmented && pkt.transport.is_none() {
+        return None;
+    }
+    match &pkt.transport {
+        Some(TransportSlice::Icmpv4(icmp)) if IPV4_ERROR_TYPES.contains(&icmp.type_u8()) => return None,
+        Some(TransportSlice::Icmpv6(icmp)) if icmp.type_u8() < 128 => return None,
+        _ => {}
+    }
+    let mut reply = Vec::new();
+    match (pkt.net.as_ref()?, server_ip) {
+        (etherparse::NetSlice::Ipv4(ip), IpAddr::V4(server)) => {
+            let source = ip.header().source_addr();
+            if source.is_unspecified() || source.is_multicast() || source.is_broadcast() {
+                return None;
+            }
+            // the original IP header and the first 8 bytes of its payload (RFC 792)
+            let quoted = &original[..(ip.header().slice().len() + 8).min(original.len())];
+            PacketBuilder::ipv4(server.octets(), source.octets(), 64)
+                .icmpv4(v4)
+                .write(&mut reply, quoted)
+                .ok()?;
+        }
+        (etherparse::NetSlice::Ipv6(ip), IpAddr::V6(server)) => {
+            let source = ip.header().source_addr();
+            if source.is_unspecified() || source.is_multicast() {
+                return None;
+            }
+            // as much of the original as fits the minimum MTU behind a 40 byte IPv6 and 8 byte ICMPv6 header
+            let quoted = &original[..original.len().min(IPV6_MIN_MTU - 48)];
+            PacketBuilder::ipv6(server.octets(), source.octets(), 64)
+                .icmpv6(v6)
+                .write(&mut reply, quoted)
+                .ok()?;
+        }
+        _ => return None,
+    }
+    Some(reply)
+}
@@ -0,0 +1,75 @@
+// The tunnel shared by httpstun_server and httpstun_client, and embeddable in other Actix
+// applications. The wire protocol and packet plumbing are always built; the server's side of
+// the tunnel (its config, authentication, WebSocket endpoint and TUN data plane) comes with
+// the default `server` feature, which the client turns off.
+pub mod compression;
+pub mod control;
+pub mod device;
+pub mod pool;
+pub mod psk;
+pub mod reconnect;
+
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod session;
+
+#[cfg(feature = "server")]
+pub mod accounting;
+#[cfg(feature = "server")]
+pub mod decoy;
+#[cfg(feature = "server")]
+pub mod events;
+#[cfg(feature = "server")]
+pub mod flow;
+#[cfg(feature = "server")]
+pub mod fw;
+#[cfg(feature = "server")]
+pub mod logging;
+#[cfg(feature = "server")]
+pub mod netmask;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+#[cfg(feature = "server")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod syslog;
+#[cfg(feature = "server")]
+pub mod systemd;
+#[cfg(feature = "server")]
+pub mod tun;
+#[cfg(feature = "server")]
+pub mod unauthenticated;
+#[cfg(feature = "server")]
+pub mod ws;
+
+#[cfg(feature = "server")]
+mod config_format;
+#[cfg(feature = "server")]
+mod icmp;
+#[cfg(feature = "server")]
+mod nft;
+#[cfg(feature = "server")]
+mod routing;
+#[cfg(feature = "server")]
+mod session_token;
+#[cfg(feature = "server")]
+mod tls;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+pub use control::{MAX_MTU, MIN_MTU};
+#[cfg(feature = "server")]
+pub use auth::{compute_decoy_hash, hash_password, load_pepper, validate_client, AuthError};
+#[cfg(feature = "server")]
+pub use config::{
+    allocate_ip, check_config, is_valid_ip, lock_config, override_config_with_args, parse_config, validate_server_ip,
+    write_config, Args, Client, Config, ConfigError, ConfigLock, IpConflictPolicy, QueueOverflowPolicy, SighupPolicy,
+    SpecialSource,
+};
+#[cfg(feature = "server")]
+pub use fw::{check_external_interfaces, install_firewall, remove_firewall};
+#[cfg(feature = "server")]
+pub use session::{ClientRegistry, ClientSession, SessionIndex, SessionTraffic, SharedConfig, WsToTunPacket};
@@ -0,0 +1,44 @@
+use std::io::Write;
+
+use log::kv::{Key, Value, VisitSource};
+use serde::{Deserialize, Serialize};
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// env_logger's human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+pub fn init(level: &str, format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = serde_json::Map::new();
+            line.insert("timestamp".to_string(), buf.timestamp_micros().to_string().into());
+            line.insert("level".to_string(), record.level().as_str().into());
+            line.insert("target".to_string(), record.target().into());
+            line.insert("message".to_string(), record.args().to_string().into());
+            // fields like `client` and `ip` that log sites attach
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+    builder.init();
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.entry(key.as_str()).or_insert(value);
+        Ok(())
+    }
+}
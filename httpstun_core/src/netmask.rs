@@ -0,0 +1,84 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+// The server subnet's mask, given either as a prefix length (`24`) or dotted (`255.255.255.0`).
+// A prefix length takes its address family from the server IP.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged, try_from = "NetmaskRepr")]
+pub enum Netmask {
+    Prefix(u8),
+    Mask(IpAddr),
+}
+
+// What a config file may hold: `netmask = 24` or `netmask = "255.255.255.0"` (or `"24"`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NetmaskRepr {
+    Prefix(u8),
+    Text(String),
+}
+
+impl TryFrom<NetmaskRepr> for Netmask {
+    type Error = String;
+
+    fn try_from(repr: NetmaskRepr) -> Result<Self, String> {
+        match repr {
+            NetmaskRepr::Prefix(len) => Ok(Netmask::Prefix(len)),
+            NetmaskRepr::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for Netmask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let s = s.strip_prefix('/').unwrap_or(s);
+        if let Ok(len) = s.parse::<u8>() {
+            return Ok(Netmask::Prefix(len));
+        }
+        s.parse::<IpAddr>()
+            .map(Netmask::Mask)
+            .map_err(|_| format!("{} is neither a prefix length nor a netmask", s))
+    }
+}
+
+impl fmt::Display for Netmask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Netmask::Prefix(len) => write!(f, "/{}", len),
+            Netmask::Mask(mask) => write!(f, "{}", mask),
+        }
+    }
+}
+
+impl Netmask {
+    // The prefix length for a subnet of `ip`, checked against its address family
+    pub fn prefix_len(self, ip: IpAddr) -> Result<u8, String> {
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        match self {
+            Netmask::Prefix(len) if len <= max => Ok(len),
+            Netmask::Prefix(len) => Err(format!("Prefix length {} is longer than the {} bits of server IP {}", len, max, ip)),
+            Netmask::Mask(mask) if mask.is_ipv4() != ip.is_ipv4() => {
+                Err(format!("Netmask {} and server IP {} are of different address families", mask, ip))
+            }
+            Netmask::Mask(mask) => netmask_to_prefix(mask),
+        }
+    }
+}
+
+// The prefix length of a dotted netmask, which must be a contiguous run of leading 1-bits
+pub fn netmask_to_prefix(mask: IpAddr) -> Result<u8, String> {
+    let (bits, ones) = match mask {
+        IpAddr::V4(mask) => (u32::from(mask).count_ones(), u32::from(mask).leading_ones()),
+        IpAddr::V6(mask) => (u128::from(mask).count_ones(), u128::from(mask).leading_ones()),
+    };
+    if bits != ones {
+        return Err(format!("Netmask {} is not contiguous", mask));
+    }
+    Ok(ones as u8)
+}
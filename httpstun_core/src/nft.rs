@@ -0,0 +1,143 @@
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+use crate::fw::{self, Egress, NatPortMode};
+
+// The nftables backend. Each tunnel's rules live in an inet table of their own, so removing
+// them is deleting the table, whatever rules an earlier version put in it.
+const TABLE_PREFIX: &str = "httpstun_";
+
+// Resolution of the numgen draw that spreads new connections across uplinks
+const SHARE_SCALE: f64 = 100_000.0;
+
+// nft identifiers only take letters, digits and a few separators
+fn table_name(tun_if_name: &str) -> String {
+    let name: String = tun_if_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("{}{}", TABLE_PREFIX, name)
+}
+
+fn quoted(if_name: &str) -> Result<String, String> {
+    if if_name.contains(['"', '\\']) {
+        return Err(format!("Interface name {:?} can't be used in an nftables rule", if_name));
+    }
+    Ok(format!("\"{}\"", if_name))
+}
+
+fn nat_statement(port_mode: NatPortMode, snat_address: Option<IpAddr>) -> String {
+    let mut flags = Vec::new();
+    let statement = match snat_address {
+        Some(addr) => {
+            flags.push("persistent");
+            format!("snat {} to {}", if addr.is_ipv4() { "ip" } else { "ip6" }, addr)
+        }
+        None => "masquerade".to_string(),
+    };
+    match port_mode {
+        NatPortMode::Preserve => {}
+        NatPortMode::Random => flags.push("random"),
+        NatPortMode::RandomFully => flags.push("fully-random"),
+    }
+    if flags.is_empty() { statement } else { format!("{} {}", statement, flags.join(",")) }
+}
+
+// Replace the tunnel's table with one masquerading what leaves `external_if_name`, or with
+// several uplinks, marking new connections from the tunnel for policy routing as the
+// iptables backend does and masquerading what leaves each uplink
+pub fn install(tun_if_name: &str, external_if_name: &str, port_mode: NatPortMode, snat_address: Option<IpAddr>, egress: &[Egress]) -> Result<(), String> {
+    let table = table_name(tun_if_name);
+    let tun = quoted(tun_if_name)?;
+    let mut nat = Vec::new();
+    let mut mangle = Vec::new();
+    if egress.is_empty() {
+        nat.push(format!("oifname {} {}", quoted(external_if_name)?, nat_statement(port_mode, snat_address)));
+    } else {
+        fw::create_egress_routing(egress)?;
+        for ((i, uplink), share) in egress.iter().enumerate().zip(fw::egress_shares(egress)) {
+            let draw = match share {
+                Some(share) => format!(" numgen random mod {} < {}", SHARE_SCALE as u32, (share * SHARE_SCALE).round() as u32),
+                None => String::new(),
+            };
+            mangle.push(format!("iifname {} ct state new ct mark 0{} ct mark set {:#x}", tun, draw, fw::egress_mark(i)));
+            nat.push(format!("oifname {} {}", quoted(&uplink.interface)?, nat_statement(port_mode, None)));
+        }
+        // copy the connection's mark to each packet so routing can see it
+        mangle.push(format!("iifname {} meta mark set ct mark", tun));
+    }
+    let mut script = format!("add table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n");
+    if !mangle.is_empty() {
+        script += "    chain prerouting {\n        type filter hook prerouting priority mangle; policy accept;\n";
+        mangle.iter().for_each(|rule| script += &format!("        {}\n", rule));
+        script += "    }\n";
+    }
+    script += "    chain postrouting {\n        type nat hook postrouting priority srcnat; policy accept;\n";
+    nat.iter().for_each(|rule| script += &format!("        {}\n", rule));
+    script += "    }\n}\n";
+    run_script(&script)
+}
+
+// Delete the tunnel's table. Returns how many rules it held.
+pub fn remove(tun_if_name: &str) -> Result<usize, String> {
+    let table = table_name(tun_if_name);
+    let tables = list_tables()?;
+    if !tables.contains(&table) {
+        return Ok(0);
+    }
+    let listing = run(&["-a", "list", "table", "inet", &table])?;
+    // rules, unlike the table and chain lines, don't open a block
+    let rules = listing.lines()
+        .map(str::trim)
+        .filter(|line| line.contains("# handle ") && !line.contains('{'))
+        .count();
+    run(&["delete", "table", "inet", &table])?;
+    Ok(rules)
+}
+
+// Delete the tables of every tunnel. Returns the deleted tables.
+pub fn remove_all() -> Result<Vec<String>, String> {
+    let mut removed = Vec::new();
+    for table in list_tables()?.into_iter().filter(|t| t.starts_with(TABLE_PREFIX)) {
+        run(&["delete", "table", "inet", &table])?;
+        removed.push(format!("table inet {}", table));
+    }
+    Ok(removed)
+}
+
+// Names of the inet tables
+fn list_tables() -> Result<Vec<String>, String> {
+    Ok(run(&["list", "tables", "inet"])?
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("table inet "))
+        .map(str::to_string)
+        .collect())
+}
+
+fn run(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("nft")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute nft command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("nft {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Apply a ruleset in one transaction, so a failing rule leaves nothing half installed
+fn run_script(script: &str) -> Result<(), String> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute nft command: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes()).map_err(|e| format!("Failed to send rules to nft: {}", e))?;
+    }
+    let output = child.wait_with_output().map_err(|e| format!("Failed to execute nft command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Failed to add nftables rules: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
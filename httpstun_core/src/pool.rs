@@ -0,0 +1,44 @@
+use bytes::{Bytes, BytesMut};
+
+// Packets carved from one chunk before another has to be allocated
+const CHUNK_PACKETS: usize = 32;
+
+// Buffer that packets on the hot paths are built in. Each packet is split off one shared
+// allocation, so passing it through the channels costs neither a copy nor an allocation; a
+// chunk is reused once every packet cut from it has been dropped.
+pub struct PacketPool {
+    buf: BytesMut,
+    mtu: usize,
+}
+
+impl PacketPool {
+    // `mtu` is the largest packet expected; bigger ones still fit, in a chunk of their own
+    pub fn new(mtu: usize) -> Self {
+        PacketPool { buf: BytesMut::with_capacity(mtu * CHUNK_PACKETS), mtu }
+    }
+
+    fn reserve(&mut self, len: usize) {
+        if self.buf.capacity() < len {
+            self.buf.reserve(len.max(self.mtu) * CHUNK_PACKETS);
+        }
+    }
+
+    // Room for the next packet, to be read into and then claimed with `take`
+    pub fn slot(&mut self) -> &mut [u8] {
+        self.reserve(self.mtu);
+        self.buf.resize(self.mtu, 0);
+        &mut self.buf
+    }
+
+    // The first `len` bytes of the last slot, as a packet of their own
+    pub fn take(&mut self, len: usize) -> Bytes {
+        self.buf.truncate(len);
+        self.buf.split().freeze()
+    }
+
+    pub fn copy(&mut self, packet: &[u8]) -> Bytes {
+        self.reserve(packet.len());
+        self.buf.extend_from_slice(packet);
+        self.buf.split().freeze()
+    }
+}
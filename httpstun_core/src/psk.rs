@@ -0,0 +1,226 @@
+use base64::Engine;
+use bytes::Bytes;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Encryption of tunneled packets under a key configured on both ends (--psk), on top of
+// whatever TLS the WebSocket runs over, so a proxy terminating TLS sees ciphertext only.
+// The key never seals a frame itself: every connection mixes it with a random salt from
+// each side (the client's in the SALT_HEADER of its upgrade request, the server's in the
+// same header of the response) through HKDF-SHA256 into a ChaCha20-Poly1305 key per
+// direction. Every binary frame is sealed under a nonce counted up from zero, and leads with
+// that counter:
+//
+//     counter (8 bytes) | ciphertext | tag (16 bytes)
+//
+// Frames are sealed after compression, so the codec byte is encrypted too. A key per
+// direction means a frame can't be reflected back at its sender, and the receiver's replay
+// window drops frames it has already opened.
+pub const KEY_LEN: usize = 32;
+
+pub const SALT_HEADER: &str = "X-Httpstun-Psk-Salt";
+const SALT_LEN: usize = 16;
+
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+// Bytes a sealed frame has on top of its packet
+pub const OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+
+const CLIENT_TO_SERVER: &[u8] = b"httpstun client-to-server";
+const SERVER_TO_CLIENT: &[u8] = b"httpstun server-to-client";
+
+// The replay window's bitmap; as in WireGuard, one word of it is kept for the counters
+// ahead of the highest, so it reaches this many counters behind
+const WINDOW_WORDS: usize = 32;
+const WINDOW: u64 = (WINDOW_WORDS as u64 - 1) * 64;
+
+#[derive(Clone, Copy)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+pub struct Psk {
+    key: [u8; KEY_LEN],
+}
+
+impl Psk {
+    pub fn load(encoded: Option<&str>) -> Result<Option<Psk>, String> {
+        let Some(encoded) = encoded else {
+            return Ok(None);
+        };
+        let key = base64::engine::general_purpose::STANDARD.decode(encoded.trim())
+            .map_err(|e| format!("--psk is not valid base64: {}", e))?;
+        let key: [u8; KEY_LEN] = key.as_slice().try_into()
+            .map_err(|_| format!("--psk must be {} bytes, not {}; generate one with `head -c 32 /dev/urandom | base64`", KEY_LEN, key.len()))?;
+        Ok(Some(Psk { key }))
+    }
+
+    // This side's salt for a new connection, as sent in SALT_HEADER
+    pub fn salt() -> Result<String, String> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| "the system RNG failed".to_string())?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(salt))
+    }
+
+    // The keys of one connection, from both sides' salts
+    pub fn session(&self, side: Side, client_salt: &str, server_salt: &str) -> Result<(Sealer, Opener), String> {
+        let mut salts = decode_salt(client_salt)?;
+        salts.extend_from_slice(&decode_salt(server_salt)?);
+        let prk = Salt::new(HKDF_SHA256, &salts).extract(&self.key);
+        let key = |label: &[u8]| -> Result<LessSafeKey, String> {
+            let info = [label];
+            let okm = prk.expand(&info, &CHACHA20_POLY1305).map_err(|_| "failed to derive the session key".to_string())?;
+            Ok(LessSafeKey::new(UnboundKey::from(okm)))
+        };
+        let (sealing, opening) = match side {
+            Side::Client => (CLIENT_TO_SERVER, SERVER_TO_CLIENT),
+            Side::Server => (SERVER_TO_CLIENT, CLIENT_TO_SERVER),
+        };
+        Ok((Sealer { key: key(sealing)?, next: 0 }, Opener { key: key(opening)?, window: ReplayWindow::default() }))
+    }
+}
+
+fn decode_salt(encoded: &str) -> Result<Vec<u8>, String> {
+    match base64::engine::general_purpose::STANDARD.decode(encoded.trim()) {
+        Ok(salt) if salt.len() == SALT_LEN => Ok(salt),
+        _ => Err(format!("{} must be {} bytes in base64", SALT_HEADER, SALT_LEN)),
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - COUNTER_LEN..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Seals the frames going out on one connection
+pub struct Sealer {
+    key: LessSafeKey,
+    next: u64,
+}
+
+impl Sealer {
+    pub fn seal(&mut self, frame: &[u8]) -> Result<Bytes, String> {
+        // never reached in practice, but a repeated nonce would give the key away
+        if self.next == u64::MAX {
+            return Err("the session's nonces are used up; reconnect".to_string());
+        }
+        let counter = self.next;
+        self.next += 1;
+        let mut sealed = Vec::with_capacity(frame.len() + OVERHEAD);
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.extend_from_slice(frame);
+        let tag = self.key.seal_in_place_separate_tag(nonce(counter), Aad::empty(), &mut sealed[COUNTER_LEN..])
+            .map_err(|_| "frame too large to seal".to_string())?;
+        sealed.extend_from_slice(tag.as_ref());
+        Ok(Bytes::from(sealed))
+    }
+}
+
+// Opens the frames coming in on one connection
+pub struct Opener {
+    key: LessSafeKey,
+    window: ReplayWindow,
+}
+
+impl Opener {
+    // The frame the other side sealed, or None when it wasn't sealed under this connection's
+    // key or was already opened
+    pub fn open(&mut self, sealed: &[u8]) -> Option<Bytes> {
+        if sealed.len() < OVERHEAD {
+            return None;
+        }
+        let (counter, ciphertext) = sealed.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().ok()?);
+        if !self.window.fresh(counter) {
+            return None;
+        }
+        let mut frame = ciphertext.to_vec();
+        let len = self.key.open_in_place(nonce(counter), Aad::empty(), &mut frame).ok()?.len();
+        // only authentic frames move the window, so forged counters can't push it ahead
+        self.window.mark(counter);
+        frame.truncate(len);
+        Some(Bytes::from(frame))
+    }
+}
+
+// The counters received lately, so each is accepted once even when frames arrive out of order
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    bits: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    fn fresh(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return true;
+        }
+        if self.highest - counter >= WINDOW {
+            return false;
+        }
+        self.bits[((counter / 64) % WINDOW_WORDS as u64) as usize] & (1 << (counter % 64)) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        let word = counter / 64;
+        if counter > self.highest {
+            // clear the words the window slides past
+            let current = self.highest / 64;
+            for skipped in 1..=(word - current).min(WINDOW_WORDS as u64) {
+                self.bits[((current + skipped) % WINDOW_WORDS as u64) as usize] = 0;
+            }
+            self.highest = counter;
+        }
+        self.bits[(word % WINDOW_WORDS as u64) as usize] |= 1 << (counter % 64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Sealer, Opener, Sealer, Opener) {
+        let psk = Psk::load(Some(&base64::engine::general_purpose::STANDARD.encode([7u8; KEY_LEN]))).unwrap().unwrap();
+        let (client_salt, server_salt) = (Psk::salt().unwrap(), Psk::salt().unwrap());
+        let (client_sealer, client_opener) = psk.session(Side::Client, &client_salt, &server_salt).unwrap();
+        let (server_sealer, server_opener) = psk.session(Side::Server, &client_salt, &server_salt).unwrap();
+        (client_sealer, client_opener, server_sealer, server_opener)
+    }
+
+    #[test]
+    fn frames_open_once_and_only_in_their_direction() {
+        let (mut client_sealer, mut client_opener, mut server_sealer, mut server_opener) = pair();
+        let sealed = client_sealer.seal(b"packet").unwrap();
+        // a key per direction: the client can't open its own frame reflected back at it
+        assert!(client_opener.open(&sealed).is_none());
+        assert_eq!(server_opener.open(&sealed).as_deref(), Some(&b"packet"[..]));
+        assert!(server_opener.open(&sealed).is_none(), "a replayed frame was opened");
+        let reply = server_sealer.seal(b"reply").unwrap();
+        assert_eq!(client_opener.open(&reply).as_deref(), Some(&b"reply"[..]));
+    }
+
+    #[test]
+    fn connections_get_their_own_keys() {
+        let (mut client_sealer, ..) = pair();
+        let (.., mut server_opener) = pair();
+        assert!(server_opener.open(&client_sealer.seal(b"packet").unwrap()).is_none());
+    }
+
+    #[test]
+    fn window_accepts_reordered_counters_once_and_drops_old_ones() {
+        let mut window = ReplayWindow::default();
+        for counter in [0, 5, 3, 4, 2, 1] {
+            assert!(window.fresh(counter), "counter {} was refused", counter);
+            window.mark(counter);
+        }
+        assert!((0..=5).all(|counter| !window.fresh(counter)));
+        window.mark(5 + WINDOW);
+        assert!(!window.fresh(5), "a counter behind the window was accepted");
+        assert!(window.fresh(6));
+        assert!(!window.fresh(5 + WINDOW));
+        assert!(window.fresh(4 + WINDOW));
+    }
+}
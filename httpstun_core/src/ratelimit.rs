@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Share of the bucket below which the limiter considers the link contended and starts
+// holding heavy clients to their fair share
+const CONTENDED_FRACTION: f64 = 0.5;
+const MIN_BURST: f64 = crate::MAX_MTU as f64;
+
+// Server-wide token bucket over tunneled bytes in both directions. It is owned by the TUN
+// handler's loop, so it needs no locking on the per-packet path.
+pub struct GlobalLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    // bytes admitted per client since `window_start`, for sharing the cap under contention
+    window_start: Instant,
+    usage: HashMap<IpAddr, u64>,
+    window_bytes: u64,
+}
+
+impl GlobalLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let now = Instant::now();
+        let rate = bytes_per_sec as f64;
+        // one second of traffic so short bursts pass untouched, but always room for a full packet
+        let burst = rate.max(MIN_BURST);
+        GlobalLimiter {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: now,
+            window_start: now,
+            usage: HashMap::new(),
+            window_bytes: 0,
+        }
+    }
+
+    // Whether a packet of `bytes` to or from `client` fits under the cap. When the bucket runs
+    // low, clients that already used more than an equal split of the rate this window are
+    // shed first so one heavy client can't starve the rest.
+    pub fn admit(&mut self, client: IpAddr, bytes: usize) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+        let bytes = bytes as u64;
+        if (bytes as f64) > self.tokens {
+            return false;
+        }
+        if self.tokens < self.burst * CONTENDED_FRACTION {
+            let fair_share = self.rate / self.usage.len().max(1) as f64;
+            if self.usage.get(&client).is_some_and(|&used| used as f64 > fair_share) {
+                return false;
+            }
+        }
+        self.tokens -= bytes as f64;
+        *self.usage.entry(client).or_insert(0) += bytes;
+        self.window_bytes += bytes;
+        true
+    }
+
+    // Start a new fairness window, returning the admitted throughput of the finished one in
+    // bytes per second. The TUN handler calls this once a second.
+    pub fn roll_window(&mut self) -> u64 {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        self.window_start = Instant::now();
+        self.usage.clear();
+        let bytes = std::mem::take(&mut self.window_bytes);
+        if elapsed > 0.0 { (bytes as f64 / elapsed) as u64 } else { 0 }
+    }
+}
+
+// Token bucket over one direction of one session, for per-client rate limits. It is owned by
+// the session's task and created with the session, so a reconnect starts with a full bucket.
+// Rather than dropping, it says how long to hold a packet, which backs the client off through
+// the WebSocket's TCP connection.
+pub struct SessionLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl SessionLimiter {
+    // None for 0, which means unlimited
+    pub fn new(bits_per_sec: u64) -> Option<Self> {
+        if bits_per_sec == 0 {
+            return None;
+        }
+        let rate = bits_per_sec as f64 / 8.0;
+        let burst = rate.max(MIN_BURST);
+        Some(SessionLimiter { rate, burst, tokens: burst, last_refill: Instant::now() })
+    }
+
+    // Charge `bytes` to the bucket, returning how long to wait before passing them on. The
+    // bucket may go into debt, so the wait of a packet larger than what is left is exact.
+    pub fn delay(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-self.tokens / self.rate) }
+    }
+}
+
+// Allows at most `per_second` events in each one-second window, for things that must not
+// be triggerable at line rate (e.g. ICMP errors generated by the server)
+pub struct EventLimiter {
+    per_second: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl EventLimiter {
+    pub fn new(per_second: u32) -> Self {
+        EventLimiter { per_second, window_start: Instant::now(), count: 0 }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        if self.window_start.elapsed().as_secs() >= 1 {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        if self.count >= self.per_second {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+// Source addresses tracked before expired entries are pruned
+const MAX_AUTH_SOURCES: usize = 4096;
+
+struct AuthFailures {
+    window_start: Instant,
+    count: u32,
+    banned_until: Option<Instant>,
+}
+
+// Failed logins per source address. After `max_failures` failures within `window` the
+// address is refused for `ban`, before any Argon2 work is spent on it. Shared by the request
+// handlers; a `max_failures` of 0 turns it off.
+pub struct AuthLimiter {
+    max_failures: u32,
+    window: Duration,
+    ban: Duration,
+    sources: Mutex<HashMap<IpAddr, AuthFailures>>,
+}
+
+impl AuthLimiter {
+    pub fn new(max_failures: u32, window: Duration, ban: Duration) -> Self {
+        AuthLimiter { max_failures, window, ban, sources: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn ban(&self) -> Duration {
+        self.ban
+    }
+
+    // How much longer `source` is banned, if it is
+    pub fn banned_for(&self, source: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        self.sources.lock().unwrap().get(&source)
+            .and_then(|f| f.banned_until)
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|left| !left.is_zero())
+    }
+
+    // Count a failed login. True when it got `source` banned.
+    pub fn fail(&self, source: IpAddr) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        if sources.len() >= MAX_AUTH_SOURCES {
+            sources.retain(|_, f| {
+                now.duration_since(f.window_start) < self.window || f.banned_until.is_some_and(|until| until > now)
+            });
+        }
+        let failures = sources.entry(source).or_insert(AuthFailures { window_start: now, count: 0, banned_until: None });
+        if now.duration_since(failures.window_start) >= self.window {
+            failures.window_start = now;
+            failures.count = 0;
+        }
+        failures.count += 1;
+        if failures.count < self.max_failures {
+            return false;
+        }
+        failures.banned_until = Some(now + self.ban);
+        failures.count = 0;
+        true
+    }
+
+    pub fn succeed(&self, source: IpAddr) {
+        self.sources.lock().unwrap().remove(&source);
+    }
+}
@@ -0,0 +1,141 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Packets held for the other end while the tunnel is down: by the client for the server
+// while it reconnects, and by the server for a client whose session dropped. Flushing them
+// after a short outage saves TCP a retransmission timeout; the buffer is kept small and drops
+// the oldest packets first, and anything older than `max_age` is discarded rather than
+// confusing TCP with long-stale segments.
+pub struct ReconnectBuffer {
+    packets: VecDeque<(Instant, Bytes)>,
+    capacity: usize,
+    max_age: Duration,
+    // packets evicted since the last `take_evicted`, for being over capacity or too old
+    evicted_full: u64,
+    evicted_stale: u64,
+}
+
+impl ReconnectBuffer {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        ReconnectBuffer {
+            packets: VecDeque::with_capacity(capacity),
+            capacity,
+            max_age,
+            evicted_full: 0,
+            evicted_stale: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn push(&mut self, packet: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        // expired packets go first so they don't push out fresh ones
+        self.expire();
+        if self.packets.len() == self.capacity {
+            self.packets.pop_front();
+            self.evicted_full += 1;
+        }
+        self.packets.push_back((Instant::now(), packet));
+    }
+
+    // Drop the packets that have grown too old to be worth sending
+    pub fn expire(&mut self) {
+        while self.packets.front().is_some_and(|(at, _)| at.elapsed() > self.max_age) {
+            self.packets.pop_front();
+            self.evicted_stale += 1;
+        }
+    }
+
+    // The packets still fresh, oldest first, leaving the buffer empty
+    pub fn drain_fresh(&mut self) -> Vec<Bytes> {
+        self.expire();
+        self.packets.drain(..).map(|(_, packet)| packet).collect()
+    }
+
+    // Packets evicted for being over capacity and for being too old since the last call
+    pub fn take_evicted(&mut self) -> (u64, u64) {
+        let evicted = (self.evicted_full, self.evicted_stale);
+        self.evicted_full = 0;
+        self.evicted_stale = 0;
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(bytes: &'static [u8]) -> Bytes {
+        Bytes::from_static(bytes)
+    }
+
+    #[test]
+    fn full_buffer_evicts_the_oldest_packets() {
+        let mut buffer = ReconnectBuffer::new(2, Duration::from_secs(60));
+        for bytes in [b"one", b"two", b"thr"] {
+            buffer.push(packet(bytes));
+        }
+        assert_eq!(buffer.take_evicted(), (1, 0));
+        assert_eq!(buffer.drain_fresh(), vec![packet(b"two"), packet(b"thr")]);
+        // counts start over once taken
+        assert_eq!(buffer.take_evicted(), (0, 0));
+    }
+
+    #[test]
+    fn stale_packets_are_evicted_on_push_and_drain() {
+        let mut buffer = ReconnectBuffer::new(4, Duration::from_millis(20));
+        buffer.push(packet(b"old"));
+        std::thread::sleep(Duration::from_millis(30));
+        buffer.push(packet(b"new"));
+        assert_eq!(buffer.take_evicted(), (0, 1));
+        assert_eq!(buffer.packets.len(), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(buffer.drain_fresh().is_empty());
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.take_evicted(), (0, 1));
+    }
+
+    #[test]
+    fn stale_packets_make_room_before_fresh_ones_are_evicted() {
+        let mut buffer = ReconnectBuffer::new(2, Duration::from_millis(20));
+        buffer.push(packet(b"old"));
+        std::thread::sleep(Duration::from_millis(30));
+        buffer.push(packet(b"one"));
+        buffer.push(packet(b"two"));
+        assert_eq!(buffer.take_evicted(), (0, 1));
+        assert_eq!(buffer.drain_fresh(), vec![packet(b"one"), packet(b"two")]);
+    }
+
+    #[test]
+    fn expire_drops_only_stale_packets() {
+        let mut buffer = ReconnectBuffer::new(4, Duration::from_millis(20));
+        buffer.push(packet(b"old"));
+        std::thread::sleep(Duration::from_millis(30));
+        buffer.push(packet(b"new"));
+        buffer.expire();
+        assert_eq!(buffer.take_evicted(), (0, 1));
+        assert_eq!(buffer.drain_fresh(), vec![packet(b"new")]);
+    }
+
+    #[test]
+    fn zero_capacity_buffers_nothing() {
+        let mut buffer = ReconnectBuffer::new(0, Duration::from_secs(60));
+        buffer.push(packet(b"one"));
+        assert!(buffer.drain_fresh().is_empty());
+        assert_eq!(buffer.take_evicted(), (0, 0));
+    }
+}
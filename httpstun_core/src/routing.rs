@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::Client;
+
+// Which client packets for an address go to, built from the client list so the data plane
+// doesn't scan every client and its allowed_ips for each packet. Client addresses are looked
+// up directly, then allowed_ips one prefix length at a time, longest first.
+#[derive(Default, Debug, Clone)]
+pub struct ClientRoutes {
+    hosts: HashMap<IpAddr, usize>,
+    // per family, longest prefix first; values index the client list
+    v4: Vec<(u8, HashMap<IpNet, usize>)>,
+    v6: Vec<(u8, HashMap<IpNet, usize>)>,
+}
+
+impl ClientRoutes {
+    pub fn new(clients: &[Client]) -> Self {
+        let mut routes = ClientRoutes::default();
+        for (i, client) in clients.iter().enumerate() {
+            routes.hosts.entry(client.ip).or_insert(i);
+            for net in &client.allowed_ips {
+                let table = if net.addr().is_ipv4() { &mut routes.v4 } else { &mut routes.v6 };
+                let len = net.prefix_len();
+                let slot = match table.iter().position(|(l, _)| *l == len) {
+                    Some(slot) => slot,
+                    None => {
+                        table.push((len, HashMap::new()));
+                        table.len() - 1
+                    }
+                };
+                table[slot].1.entry(net.trunc()).or_insert(i);
+            }
+        }
+        routes.v4.sort_by_key(|(len, _)| std::cmp::Reverse(*len));
+        routes.v6.sort_by_key(|(len, _)| std::cmp::Reverse(*len));
+        routes
+    }
+
+    // Index of the client with this address, or else of the one whose allowed_ips hold it
+    // with the longest prefix
+    pub fn lookup(&self, ip: &IpAddr) -> Option<usize> {
+        if let Some(i) = self.hosts.get(ip) {
+            return Some(*i);
+        }
+        let table = if ip.is_ipv4() { &self.v4 } else { &self.v6 };
+        table.iter().find_map(|(len, nets)| {
+            let net = IpNet::new(*ip, *len).ok()?.trunc();
+            nets.get(&net).copied()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(ip: &str, allowed_ips: &[&str]) -> Client {
+        serde_json::from_value(serde_json::json!({ "name": ip, "token": "", "ip": ip, "allowed_ips": allowed_ips })).unwrap()
+    }
+
+    #[test]
+    fn client_addresses_win_over_networks() {
+        let clients = [client("10.0.0.2", &["10.0.0.0/24"]), client("10.0.0.3", &[])];
+        let routes = ClientRoutes::new(&clients);
+        assert_eq!(routes.lookup(&"10.0.0.3".parse().unwrap()), Some(1));
+        assert_eq!(routes.lookup(&"10.0.0.9".parse().unwrap()), Some(0));
+        assert_eq!(routes.lookup(&"10.0.1.9".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let clients = [
+            client("10.0.0.2", &["192.168.0.0/16", "fd00::/48"]),
+            client("10.0.0.3", &["192.168.7.0/24", "fd00:0:0:7::/64"]),
+        ];
+        let routes = ClientRoutes::new(&clients);
+        assert_eq!(routes.lookup(&"192.168.7.1".parse().unwrap()), Some(1));
+        assert_eq!(routes.lookup(&"192.168.8.1".parse().unwrap()), Some(0));
+        assert_eq!(routes.lookup(&"fd00:0:0:7::1".parse().unwrap()), Some(1));
+        assert_eq!(routes.lookup(&"fd00:0:0:8::1".parse().unwrap()), Some(0));
+        assert_eq!(routes.lookup(&"fd01::1".parse().unwrap()), None);
+    }
+}
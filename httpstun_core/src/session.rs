@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::{stats, ws, Config};
+
+// Map client IP -> live session of the connected client
+pub type ClientRegistry = std::sync::Arc<tokio::sync::RwLock<HashMap<IpAddr, std::sync::Arc<ClientSession>>> >;
+
+// The running config, shared by the request handlers, the TUN handler and the prompt. A
+// reload swaps in the client list of the re-read config file; server settings only change
+// on restart.
+pub type SharedConfig = std::sync::Arc<std::sync::RwLock<Config>>;
+
+// Sessions per client name, which the per-client session limit is counted against; the
+// registry only holds the newest session per IP. Only changed while holding the registry's
+// write lock, so checking the limit and registering can't race.
+pub type SessionIndex = std::sync::Arc<std::sync::Mutex<HashMap<String, Vec<std::sync::Weak<ClientSession>>>>>;
+
+// A connected client's outbound channel to WS plus the handle needed to close it
+pub struct ClientSession {
+    pub name: String,
+    pub ip: IpAddr,
+    pub tx: async_channel::Sender<bytes::Bytes>,
+    // the queue's other end, to drop its oldest packet under --client-queue-overflow drop-oldest
+    pub rx: async_channel::Receiver<bytes::Bytes>,
+    pub session: actix_ws::Session,
+    // set when the client negotiated a separate control connection
+    pub control: Option<ws::ControlLink>,
+    pub connected_at: Instant,
+    pub last_activity: std::sync::Mutex<Instant>,
+    // last frame of any kind, including pongs to the server's keepalive pings
+    pub last_frame: std::sync::Mutex<Instant>,
+    // set once the client has sent its first tunneled packet
+    pub sent_packet: std::sync::atomic::AtomicBool,
+    pub traffic: SessionTraffic,
+    // the client's traffic across all its sessions, for /metrics
+    pub totals: std::sync::Arc<SessionTraffic>,
+    // set once the accounting stop record has been written
+    pub accounted: std::sync::atomic::AtomicBool,
+    // the client's rate limit when the session started, in bit/s per direction; 0 is unlimited
+    pub rate_limit_bps: u64,
+}
+
+// Tunneled traffic of one session, counted at the WebSocket
+#[derive(Default, Debug)]
+pub struct SessionTraffic {
+    pub bytes_from_client: std::sync::atomic::AtomicU64,
+    pub bytes_to_client: std::sync::atomic::AtomicU64,
+    pub packets_from_client: std::sync::atomic::AtomicU64,
+    pub packets_to_client: std::sync::atomic::AtomicU64,
+    // with --protocol-stats, counted by the TUN handler into the client's totals only
+    pub protocols: stats::ProtocolTraffic,
+    // packets the destination allowlist dropped, likewise only in the totals
+    pub filtered_by_acl: std::sync::atomic::AtomicU64,
+}
+
+impl ClientSession {
+    pub fn new(name: String, ip: IpAddr, tx: async_channel::Sender<bytes::Bytes>, rx: async_channel::Receiver<bytes::Bytes>, session: actix_ws::Session, control: Option<ws::ControlLink>, totals: std::sync::Arc<SessionTraffic>) -> Self {
+        let now = Instant::now();
+        ClientSession {
+            name,
+            ip,
+            tx,
+            rx,
+            session,
+            control,
+            connected_at: now,
+            last_activity: std::sync::Mutex::new(now),
+            last_frame: std::sync::Mutex::new(now),
+            sent_packet: std::sync::atomic::AtomicBool::new(false),
+            traffic: SessionTraffic::default(),
+            totals,
+            accounted: std::sync::atomic::AtomicBool::new(false),
+            rate_limit_bps: 0,
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    pub fn heard(&self) {
+        *self.last_frame.lock().unwrap() = Instant::now();
+    }
+
+    pub fn unheard_for(&self) -> Duration {
+        self.last_frame.lock().unwrap().elapsed()
+    }
+
+    pub fn count_from_client(&self, bytes: usize) {
+        for traffic in [&self.traffic, &*self.totals] {
+            traffic.bytes_from_client.fetch_add(bytes as u64, std::sync::atomic::Ordering::Relaxed);
+            stats::bump(&traffic.packets_from_client);
+        }
+    }
+
+    pub fn count_to_client(&self, bytes: usize) {
+        for traffic in [&self.traffic, &*self.totals] {
+            traffic.bytes_to_client.fetch_add(bytes as u64, std::sync::atomic::Ordering::Relaxed);
+            stats::bump(&traffic.packets_to_client);
+        }
+    }
+}
+
+// Message from a WebSocket client headed to the TUN device
+#[derive(Clone, Debug)]
+pub struct WsToTunPacket {
+    pub client_ip: IpAddr,
+    pub data: bytes::Bytes,
+    // the sending client's totals
+    pub totals: std::sync::Arc<SessionTraffic>,
+}
@@ -0,0 +1,70 @@
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use blake2::Blake2sMac256;
+use blake2::digest::{KeyInit, Mac};
+
+use crate::{Client, Config};
+
+// Random per process, so a restart invalidates every token and clients log in with their
+// password again
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+fn key() -> &'static [u8; 32] {
+    KEY.get_or_init(|| {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Covers the client's password hash too, so changing the password revokes its tokens
+fn mac(name: &str, expires: u64, hash: &str) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(key()).expect("32 byte key fits BLAKE2s");
+    mac.update(&(name.len() as u64).to_be_bytes());
+    mac.update(name.as_bytes());
+    mac.update(&expires.to_be_bytes());
+    mac.update(hash.as_bytes());
+    mac
+}
+
+// `hex(name).expiry.hex(mac)`; the name is hex encoded since client names may contain dots
+pub fn issue(client: &Client, lifetime: Duration) -> String {
+    let expires = now() + lifetime.as_secs();
+    let tag = mac(&client.name, expires, &client.token).finalize().into_bytes();
+    format!("{}.{}.{}", hex(client.name.as_bytes()), expires, hex(&tag))
+}
+
+// The client a token was issued to, if it is unexpired, correctly signed, and the client is
+// still configured with the same password. Removed clients find no entry, which revokes theirs.
+pub fn verify<'a>(token: &str, config: &'a Config) -> Option<&'a Client> {
+    let mut parts = token.split('.');
+    let (name, expires, tag) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let name = String::from_utf8(unhex(name)?).ok()?;
+    let expires: u64 = expires.parse().ok()?;
+    if expires <= now() {
+        return None;
+    }
+    let client = config.clients().iter().find(|c| c.name == name)?;
+    mac(&client.name, expires, &client.token).verify_slice(&unhex(tag)?).ok()?;
+    Some(client)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
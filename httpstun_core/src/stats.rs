@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use etherparse::{IpNumber, SlicedPacket};
+use serde::Serialize;
+
+use crate::SessionTraffic;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum L4Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Other,
+}
+
+impl L4Protocol {
+    pub const ALL: [L4Protocol; 4] = [L4Protocol::Tcp, L4Protocol::Udp, L4Protocol::Icmp, L4Protocol::Other];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            L4Protocol::Tcp => "tcp",
+            L4Protocol::Udp => "udp",
+            L4Protocol::Icmp => "icmp",
+            L4Protocol::Other => "other",
+        }
+    }
+
+    // Classified by the IP protocol number rather than the parsed transport header, so every
+    // fragment of a datagram is counted alike (only the first one carries the L4 header)
+    pub fn of(pkt: &SlicedPacket) -> Self {
+        match pkt.net.as_ref().and_then(|net| net.ip_payload_ref()).map(|p| p.ip_number) {
+            Some(IpNumber::TCP) => L4Protocol::Tcp,
+            Some(IpNumber::UDP) => L4Protocol::Udp,
+            Some(IpNumber::ICMP) | Some(IpNumber::IPV6_ICMP) => L4Protocol::Icmp,
+            _ => L4Protocol::Other,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, Serialize)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct ProtocolBreakdown {
+    pub tcp: Counter,
+    pub udp: Counter,
+    pub icmp: Counter,
+    pub other: Counter,
+}
+
+// Traffic of one client (or all clients), split by direction
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct DirectionalTraffic {
+    pub from_client: ProtocolBreakdown,
+    pub to_client: ProtocolBreakdown,
+}
+
+// by client name
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct TrafficSnapshot {
+    pub total: DirectionalTraffic,
+    pub clients: BTreeMap<String, DirectionalTraffic>,
+}
+
+// Packets and bytes of each protocol in one direction, indexed by L4Protocol
+#[derive(Default, Debug)]
+pub struct ProtocolCounters {
+    packets: [AtomicU64; 4],
+    bytes: [AtomicU64; 4],
+}
+
+impl ProtocolCounters {
+    fn add(&self, proto: L4Protocol, bytes: usize) {
+        bump(&self.packets[proto as usize]);
+        self.bytes[proto as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, proto: L4Protocol) -> Counter {
+        Counter { packets: load(&self.packets[proto as usize]), bytes: load(&self.bytes[proto as usize]) }
+    }
+
+    fn breakdown(&self) -> ProtocolBreakdown {
+        ProtocolBreakdown {
+            tcp: self.get(L4Protocol::Tcp),
+            udp: self.get(L4Protocol::Udp),
+            icmp: self.get(L4Protocol::Icmp),
+            other: self.get(L4Protocol::Other),
+        }
+    }
+}
+
+// Per-protocol traffic of one client, or of all of them
+#[derive(Default, Debug)]
+pub struct ProtocolTraffic {
+    pub from_client: ProtocolCounters,
+    pub to_client: ProtocolCounters,
+}
+
+impl ProtocolTraffic {
+    fn snapshot(&self) -> DirectionalTraffic {
+        DirectionalTraffic { from_client: self.from_client.breakdown(), to_client: self.to_client.breakdown() }
+    }
+}
+
+// Per-protocol accounting of tunneled packets, updated from the TUN handler into the server's
+// totals and the client's, which its sessions hold. When disabled, recording is a no-op so the
+// data path pays nothing for it.
+pub struct TrafficStats {
+    enabled: bool,
+    total: ProtocolTraffic,
+}
+
+impl TrafficStats {
+    pub fn new(enabled: bool) -> Self {
+        TrafficStats { enabled, total: ProtocolTraffic::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn record_from_client(&self, client: &SessionTraffic, pkt: &SlicedPacket, bytes: usize) {
+        if !self.enabled {
+            return;
+        }
+        let proto = L4Protocol::of(pkt);
+        self.total.from_client.add(proto, bytes);
+        client.protocols.from_client.add(proto, bytes);
+    }
+
+    pub fn record_to_client(&self, client: &SessionTraffic, pkt: &SlicedPacket, bytes: usize) {
+        if !self.enabled {
+            return;
+        }
+        let proto = L4Protocol::of(pkt);
+        self.total.to_client.add(proto, bytes);
+        client.protocols.to_client.add(proto, bytes);
+    }
+
+    pub fn snapshot(&self, clients: &ClientTotals) -> TrafficSnapshot {
+        TrafficSnapshot {
+            total: self.total.snapshot(),
+            clients: clients.snapshot().into_iter().map(|(name, traffic)| (name, traffic.protocols.snapshot())).collect(),
+        }
+    }
+}
+
+pub fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+pub fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// Sessions closed by the sweep, by reason
+#[derive(Default, Debug)]
+pub struct SessionCounters {
+    pub task_ended: AtomicU64,
+    pub idle_timeout: AtomicU64,
+    pub max_lifetime: AtomicU64,
+    pub first_packet_timeout: AtomicU64,
+    pub unresponsive: AtomicU64,
+}
+
+// Fragmented packets forwarded by the TUN handler
+#[derive(Default, Debug)]
+pub struct FragmentCounters {
+    pub fragments: AtomicU64,
+}
+
+// Flow records exported to the collector, and those lost on the way
+#[derive(Default, Debug)]
+pub struct FlowCounters {
+    pub exported: AtomicU64,
+    // flows ended early to make room in a full table; still exported
+    pub evicted: AtomicU64,
+    pub queue_dropped: AtomicU64,
+    pub send_failed: AtomicU64,
+}
+
+// Packets let past the anti-spoofing check by a client's special_sources
+#[derive(Default, Debug)]
+pub struct SpecialSourceCounters {
+    pub dhcp: AtomicU64,
+    pub link_local: AtomicU64,
+}
+
+// ICMP errors the server sends: destination unreachable for packets to disconnected clients,
+// and fragmentation needed / packet too big for packets over an MTU
+#[derive(Default, Debug)]
+pub struct IcmpCounters {
+    pub unreachable_sent: AtomicU64,
+    pub unreachable_rate_limited: AtomicU64,
+    pub too_big_sent: AtomicU64,
+    pub too_big_rate_limited: AtomicU64,
+}
+
+// Requests to the tunnel and control endpoints that failed authentication
+#[derive(Default, Debug)]
+pub struct AuthCounters {
+    pub failures: AtomicU64,
+    // refused with 429 while their source was banned
+    pub rate_limited: AtomicU64,
+}
+
+// Failed reads and writes of the TUN device, and whether it is up
+#[derive(Default, Debug)]
+pub struct TunCounters {
+    pub read_errors: AtomicU64,
+    pub write_errors: AtomicU64,
+    // set while the TUN handler is passing packets
+    pub up: AtomicBool,
+}
+
+// Traffic per client name across sessions, so it survives reconnects. Each session holds its
+// client's entry and counts into it directly, keeping the lock off the data path.
+#[derive(Default)]
+pub struct ClientTotals {
+    inner: Mutex<HashMap<String, Arc<SessionTraffic>>>,
+}
+
+impl ClientTotals {
+    pub fn of(&self, name: &str) -> Arc<SessionTraffic> {
+        self.inner.lock().unwrap().entry(name.to_string()).or_default().clone()
+    }
+
+    // without adding an entry for a client that never connected
+    pub fn get(&self, name: &str) -> Option<Arc<SessionTraffic>> {
+        self.inner.lock().unwrap().get(name).cloned()
+    }
+
+    // sorted by name, for stable output
+    pub fn snapshot(&self) -> Vec<(String, Arc<SessionTraffic>)> {
+        let mut totals: Vec<_> = self.inner.lock().unwrap().iter().map(|(name, t)| (name.clone(), t.clone())).collect();
+        totals.sort_by(|a, b| a.0.cmp(&b.0));
+        totals
+    }
+}
+
+// Why the data plane dropped a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    ParseError,
+    Truncated,
+    UnsupportedLayer,
+    UnassignedDestination,
+    NoActiveSession,
+    ClientGone,
+    ClientQueueFull,
+    Spoofed,
+    FilteredByAcl,
+    ClientToClient,
+    OverMtu,
+    TunWriteFailed,
+    GlobalRateLimited,
+    Undecryptable,
+    ReconnectBufferFull,
+    ReconnectBufferExpired,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 16] = [
+        DropReason::ParseError,
+        DropReason::Truncated,
+        DropReason::UnsupportedLayer,
+        DropReason::UnassignedDestination,
+        DropReason::NoActiveSession,
+        DropReason::ClientGone,
+        DropReason::ClientQueueFull,
+        DropReason::Spoofed,
+        DropReason::FilteredByAcl,
+        DropReason::ClientToClient,
+        DropReason::OverMtu,
+        DropReason::TunWriteFailed,
+        DropReason::GlobalRateLimited,
+        DropReason::Undecryptable,
+        DropReason::ReconnectBufferFull,
+        DropReason::ReconnectBufferExpired,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DropReason::ParseError => "parse_error",
+            DropReason::Truncated => "truncated",
+            DropReason::UnsupportedLayer => "unsupported_layer",
+            DropReason::UnassignedDestination => "unassigned_destination",
+            DropReason::NoActiveSession => "no_active_session",
+            DropReason::ClientGone => "client_gone",
+            DropReason::ClientQueueFull => "client_queue_full",
+            DropReason::Spoofed => "spoofed",
+            DropReason::FilteredByAcl => "filtered_by_acl",
+            DropReason::ClientToClient => "client_to_client",
+            DropReason::OverMtu => "over_mtu",
+            DropReason::TunWriteFailed => "tun_write_failed",
+            DropReason::GlobalRateLimited => "global_rate_limited",
+            DropReason::Undecryptable => "undecryptable",
+            DropReason::ReconnectBufferFull => "reconnect_buffer_full",
+            DropReason::ReconnectBufferExpired => "reconnect_buffer_expired",
+        }
+    }
+}
+
+// Dropped packets by reason, indexed in `DropReason::ALL` order
+#[derive(Default, Debug)]
+pub struct DropCounters {
+    counts: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl DropCounters {
+    pub fn record(&self, reason: DropReason) {
+        bump(&self.counts[reason as usize]);
+    }
+
+    pub fn add(&self, reason: DropReason, packets: u64) {
+        self.counts[reason as usize].fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        load(&self.counts[reason as usize])
+    }
+
+    pub fn snapshot(&self) -> Vec<(DropReason, u64)> {
+        DropReason::ALL.iter().map(|&reason| (reason, self.get(reason))).collect()
+    }
+}
+
+// Server-wide throughput cap (0 when unlimited) and the rate admitted over the last second
+#[derive(Default, Debug)]
+pub struct ThroughputStats {
+    pub limit_bytes_per_sec: u64,
+    pub last_second_bytes: AtomicU64,
+}
+
+// All server-side counters, shared between the HTTP handlers, the TUN handler and the sweep
+pub struct Stats {
+    pub traffic: TrafficStats,
+    pub sessions: SessionCounters,
+    pub fragments: FragmentCounters,
+    pub drops: DropCounters,
+    pub throughput: ThroughputStats,
+    pub icmp: IcmpCounters,
+    pub special_sources: SpecialSourceCounters,
+    pub flows: FlowCounters,
+    pub auth: AuthCounters,
+    pub tun: TunCounters,
+    pub clients: ClientTotals,
+    pub started: Instant,
+}
+
+impl Stats {
+    pub fn new(protocol_stats: bool, max_throughput: u64) -> Self {
+        Stats {
+            traffic: TrafficStats::new(protocol_stats),
+            sessions: SessionCounters::default(),
+            fragments: FragmentCounters::default(),
+            drops: DropCounters::default(),
+            throughput: ThroughputStats { limit_bytes_per_sec: max_throughput, ..Default::default() },
+            icmp: IcmpCounters::default(),
+            special_sources: SpecialSourceCounters::default(),
+            flows: FlowCounters::default(),
+            auth: AuthCounters::default(),
+            tun: TunCounters::default(),
+            clients: ClientTotals::default(),
+            started: Instant::now(),
+        }
+    }
+}
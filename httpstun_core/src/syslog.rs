@@ -0,0 +1,187 @@
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Args;
+
+// Connection lifecycle events sent to the system logger, apart from the regular log output,
+// so the host can alert on them. Events carry client names and addresses, never credentials.
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+// Longest client name logged; names from failed logins are whatever the peer sent
+const MAX_NAME_LEN: usize = 64;
+const IDENTIFIER: &str = env!("CARGO_PKG_NAME");
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    /// The syslog socket, /dev/log
+    Syslog,
+    /// journald's native socket, with structured fields
+    Journald,
+}
+
+impl Target {
+    fn path(self) -> &'static str {
+        match self {
+            Target::Syslog => "/dev/log",
+            Target::Journald => "/run/systemd/journal/socket",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Facility {
+    #[default]
+    Auth,
+    Authpriv,
+    Daemon,
+    User,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Authpriv => 10,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+// syslog severities
+const WARNING: u8 = 4;
+const NOTICE: u8 = 5;
+const INFO: u8 = 6;
+
+pub enum Event<'a> {
+    Connect { client: &'a str, ip: IpAddr, peer: Option<SocketAddr> },
+    Disconnect { client: &'a str, ip: IpAddr, peer: Option<SocketAddr>, reason: &'a str },
+    AuthFailure { client: &'a str, peer: Option<SocketAddr> },
+    // authenticated, but turned away
+    Rejected { client: &'a str, ip: IpAddr, reason: &'a str },
+    // closed by the server
+    Kick { client: &'a str, ip: IpAddr, reason: &'a str },
+}
+
+impl Event<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Connect { .. } => "connect",
+            Event::Disconnect { .. } => "disconnect",
+            Event::AuthFailure { .. } => "auth-failure",
+            Event::Rejected { .. } => "rejected",
+            Event::Kick { .. } => "kick",
+        }
+    }
+
+    fn severity(&self) -> u8 {
+        match self {
+            Event::Connect { .. } | Event::Disconnect { .. } => INFO,
+            Event::Rejected { .. } | Event::Kick { .. } => NOTICE,
+            Event::AuthFailure { .. } => WARNING,
+        }
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let peer = |peer: &Option<SocketAddr>| peer.map(|p| p.to_string()).unwrap_or("unknown".to_string());
+        match self {
+            Event::Connect { client, ip, peer: p } => vec![("client", clean(client)), ("ip", ip.to_string()), ("peer", peer(p))],
+            Event::AuthFailure { client, peer: p } => vec![("client", clean(client)), ("peer", peer(p))],
+            Event::Disconnect { client, ip, peer: p, reason } => vec![("client", clean(client)), ("ip", ip.to_string()), ("peer", peer(p)), ("reason", clean(reason))],
+            Event::Rejected { client, ip, reason }
+            | Event::Kick { client, ip, reason } => vec![("client", clean(client)), ("ip", ip.to_string()), ("reason", clean(reason))],
+        }
+    }
+}
+
+// Strip anything that could forge extra lines or fields, and cap the length
+fn clean(value: &str) -> String {
+    let value: String = value.chars().take(MAX_NAME_LEN).map(|c| if c.is_control() { '?' } else { c }).collect();
+    if value.is_empty() { "-".to_string() } else { value }
+}
+
+struct Sink {
+    target: Target,
+    facility: Facility,
+    socket: UnixDatagram,
+    // set after a failed send, so an absent logger is reported once rather than per event
+    failing: AtomicBool,
+}
+
+pub fn init(args: &Args) -> Result<(), String> {
+    let Some(target) = args.connection_log else {
+        return Ok(());
+    };
+    if !std::path::Path::new(target.path()).exists() {
+        return Err(format!("Connection log target {} is not available", target.path()));
+    }
+    let socket = UnixDatagram::unbound().map_err(|e| format!("Failed to create the connection log socket: {}", e))?;
+    // a stalled logger must not hold up the request handlers
+    socket.set_nonblocking(true).map_err(|e| format!("Failed to set up the connection log socket: {}", e))?;
+    SINK.set(Sink { target, facility: args.syslog_facility, socket, failing: AtomicBool::new(false) })
+        .map_err(|_| "Connection log already set up".to_string())
+}
+
+pub fn record(event: Event) {
+    // admin API subscribers get every event, whether or not it goes to the system logger
+    crate::events::publish(&event);
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let fields = event.fields();
+    let message = std::iter::once(event.name().to_string())
+        .chain(fields.iter().map(|(key, value)| format!("{}={}", key, value)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let datagram = match sink.target {
+        Target::Syslog => format!(
+            "<{}>{}[{}]: {}",
+            sink.facility.code() * 8 + event.severity(),
+            IDENTIFIER,
+            std::process::id(),
+            message,
+        ),
+        Target::Journald => {
+            let mut lines = vec![
+                format!("MESSAGE={}", message),
+                format!("PRIORITY={}", event.severity()),
+                format!("SYSLOG_FACILITY={}", sink.facility.code()),
+                format!("SYSLOG_IDENTIFIER={}", IDENTIFIER),
+                format!("HTTPSTUN_EVENT={}", event.name()),
+            ];
+            lines.extend(fields.iter().map(|(key, value)| format!("HTTPSTUN_{}={}", key.to_ascii_uppercase(), value)));
+            lines.join("\n") + "\n"
+        }
+    };
+    match sink.socket.send_to(datagram.as_bytes(), sink.target.path()) {
+        Ok(_) => sink.failing.store(false, Ordering::Relaxed),
+        Err(e) => {
+            if !sink.failing.swap(true, Ordering::Relaxed) {
+                warn!("Failed to send connection event to {}: {}", sink.target.path(), e);
+            }
+        }
+    }
+}
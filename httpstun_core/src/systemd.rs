@@ -0,0 +1,118 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::Args;
+
+// sd_notify(3) for running as a Type=notify service with --systemd: READY=1 once both the
+// TUN device is up and the HTTP server listens, WATCHDOG=1 at half of WatchdogSec, and
+// STOPPING=1 on shutdown. Without --systemd, or outside systemd, every call does nothing.
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+// Parts of the server that must be up before it counts as ready
+#[derive(Clone, Copy)]
+pub enum Component {
+    Tun = 1,
+    Http = 2,
+}
+
+const ALL_READY: u8 = Component::Tun as u8 | Component::Http as u8;
+
+// Components up so far, tracked with or without systemd since dropping privileges waits on them too
+static READY: AtomicU8 = AtomicU8::new(0);
+static ALL_UP: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+pub fn init(args: &Args) -> Result<(), String> {
+    if !args.systemd {
+        return Ok(());
+    }
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        warn!("--systemd is set but NOTIFY_SOCKET isn't; not running as a Type=notify service?");
+        return Ok(());
+    };
+    let path = path.to_string_lossy().into_owned();
+    // a leading @ names a socket in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    }
+    .map_err(|e| format!("Invalid NOTIFY_SOCKET {}: {}", path, e))?;
+    let socket = UnixDatagram::unbound().map_err(|e| format!("Failed to create the systemd notify socket: {}", e))?;
+    NOTIFIER.set(Notifier { socket, addr })
+        .map_err(|_| "systemd notification already set up".to_string())
+}
+
+fn notify(state: &str) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    if let Err(e) = notifier.socket.send_to_addr(state.as_bytes(), &notifier.addr) {
+        warn!("Failed to notify systemd ({}): {}", state.lines().next().unwrap_or_default(), e);
+    }
+}
+
+// Record that a component is up; the last one to come up tells systemd the server is ready
+pub fn component_ready(component: Component) {
+    let before = READY.fetch_or(component as u8, Ordering::Relaxed);
+    if before == ALL_READY || before | component as u8 != ALL_READY {
+        return;
+    }
+    ALL_UP.notify_waiters();
+    if NOTIFIER.get().is_some() {
+        info!("Notifying systemd that the server is ready");
+        notify(&format!("READY=1\nSTATUS=Serving\nMAINPID={}", std::process::id()));
+    }
+}
+
+// Wait until every component is up
+pub async fn all_ready() {
+    let up = ALL_UP.notified();
+    if READY.load(Ordering::Relaxed) != ALL_READY {
+        up.await;
+    }
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+// Before a restart: the re-executed process, which keeps this PID, sends READY=1 again
+pub fn reloading() {
+    notify("RELOADING=1");
+}
+
+// Half of WatchdogSec, when systemd expects keep-alive pings from this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // set when the watchdog is meant for another process, e.g. a wrapper script
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+// Ping the service watchdog for as long as the runtime keeps scheduling tasks
+pub async fn run_watchdog() {
+    if NOTIFIER.get().is_none() {
+        return;
+    }
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    debug!("Pinging the systemd watchdog every {:?}", interval);
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, trace, warn};
+use tappers::{AddAddressV4, AddAddressV6, DeviceState, Interface, Tun};
+use async_channel::{Receiver, TrySendError};
+use actix_ws::{CloseCode, CloseReason};
+use bytes::Bytes;
+use crate::{Args, ClientRegistry, ClientSession, Config, QueueOverflowPolicy, SharedConfig, SpecialSource, WsToTunPacket};
+use crate::syslog::{self, Event};
+use crate::device::{AsyncTun, TunDevice, BATCH_SIZE};
+use crate::pool::PacketPool;
+use crate::reconnect::ReconnectBuffer;
+use etherparse::{NetSlice, SlicedPacket};
+use etherparse::err::packet::SliceError;
+use crate::ratelimit::{EventLimiter, GlobalLimiter};
+use crate::stats::{self, DropReason, Stats};
+
+pub async fn run_tun(wsrx: Receiver<WsToTunPacket>, registry: ClientRegistry, stats: Arc<Stats>, config: SharedConfig) -> io::Result<()> {
+    let startup = config.read().unwrap().clone();
+    let tap_name = Interface::new(startup.server_args.tun_interface_name.clone())?;
+    let mut tun = Tun::new_named(tap_name)?;
+    let tun_mtu = setup_tun(&mut tun, &startup)?;
+    stats.tun.up.store(true, Ordering::Relaxed);
+    crate::systemd::component_ready(crate::systemd::Component::Tun);
+    let result = run_device(tun, tun_mtu, wsrx, registry, stats.clone(), &config).await;
+    stats.tun.up.store(false, Ordering::Relaxed);
+    result
+}
+
+async fn run_device(tun: Tun, tun_mtu: usize, wsrx: Receiver<WsToTunPacket>, registry: ClientRegistry, stats: Arc<Stats>, config: &SharedConfig) -> io::Result<()> {
+    #[cfg(feature = "io-uring")]
+    if config.read().unwrap().server_args.io_uring {
+        info!("Using io_uring for TUN I/O");
+        let ring_stats = stats.clone();
+        let tap = crate::uring::UringTun::new(tun, move |e| {
+            ring_stats.drops.record(DropReason::TunWriteFailed);
+            stats::bump(&ring_stats.tun.write_errors);
+            warn!("io_uring write to TUN failed: {}", e);
+        })?;
+        return run_data_plane(&tap, tun_mtu, wsrx, registry, stats, config).await;
+    }
+    let tap = AsyncTun::new(tun)?;
+    run_data_plane(&tap, tun_mtu, wsrx, registry, stats, config).await
+}
+
+// Install the NAT rule, address the interface and bring it up. Returns the device MTU.
+fn setup_tun(tap: &mut Tun, config: &Config) -> io::Result<usize> {
+    // a crashed or killed server leaves its rules behind; clear them rather than stack duplicates
+    let tun_if_name = &config.server_args.tun_interface_name;
+    match crate::remove_firewall(tun_if_name, config) {
+        Ok(0) => {}
+        Ok(n) => warn!("Removed {} stale firewall rule(s) for {} left by a previous run", n, tun_if_name),
+        Err(e) => {
+            error!("Failed to remove stale firewall rules for {}: {}", tun_if_name, e);
+            return Err(io::Error::other("Failed to remove stale firewall rules"));
+        }
+    }
+    // create the NAT rule
+    if let Err(e) = crate::install_firewall(&config.server_args.tun_interface_name, config) {
+        error!("Failed to create the NAT rule: {}", e);
+        return Err(io::Error::other("Failed to create the NAT rule"));
+    }
+    // On exit, remove the NAT rule
+    //set tun interface IP address
+    let prefix_len = config.server_args.subnet()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .prefix_len();
+    match config.server_args.server_ip {
+        IpAddr::V4(ipv4) => {
+            let mut add_addr = AddAddressV4::new(ipv4);
+            add_addr.set_netmask(prefix_len);
+            tap.add_addr(add_addr)?;
+        }
+        IpAddr::V6(ipv6) => {
+            let mut add_addr = AddAddressV6::new(ipv6);
+            add_addr.set_netmask(prefix_len);
+            tap.add_addr(add_addr)?;
+        }
+    }
+    if let Some(mtu) = config.server_args.mtu {
+        crate::fw::set_mtu(&config.server_args.tun_interface_name, mtu).map_err(io::Error::other)?;
+    }
+    // Set the interface up
+    tap.set_state(DeviceState::Up)?;
+    crate::fw::route_to_tun(tun_if_name, &config.client_networks()).map_err(io::Error::other)?;
+    Ok(tap.mtu().unwrap_or(1500))
+}
+
+// Truncated packets per minute after which a hint about size misconfiguration is logged
+const TRUNCATION_HINT_THRESHOLD: u32 = 10;
+
+// Counts truncated packets over one-minute windows to spot a steady stream of them
+struct TruncationWatch {
+    window_start: Instant,
+    count: u32,
+}
+
+impl TruncationWatch {
+    fn new() -> Self {
+        TruncationWatch { window_start: Instant::now(), count: 0 }
+    }
+
+    // true once per window, when the threshold is reached
+    fn record(&mut self) -> bool {
+        if self.window_start.elapsed() > Duration::from_secs(60) {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count == TRUNCATION_HINT_THRESHOLD
+    }
+}
+
+// Dropped packets logged at trace level between two warnings
+const DROP_WARN_EVERY: u64 = 1000;
+
+// Logs a kind of dropped packet a misbehaving client can send at line rate, such as ones that
+// can't be parsed: the first one as a warning, then one warning with the running count per
+// DROP_WARN_EVERY, and the rest at trace level. `stats` counts every one of them by reason.
+struct DropLog {
+    // what the packets are, in the plural
+    what: &'static str,
+    count: u64,
+}
+
+impl DropLog {
+    fn new(what: &'static str) -> Self {
+        DropLog { what, count: 0 }
+    }
+
+    fn log(&mut self, message: std::fmt::Arguments) {
+        self.count += 1;
+        if self.count == 1 {
+            warn!("{}. Further {} are logged at trace level.", message, self.what);
+        } else if self.count.is_multiple_of(DROP_WARN_EVERY) {
+            warn!("{} ({} {} dropped so far)", message, self.count, self.what);
+        } else {
+            trace!("{}", message);
+        }
+    }
+}
+
+// A packet shorter than its IP header claims was cut somewhere on the way (an MTU or frame
+// size mismatch), which is worth telling apart from a malformed one
+fn record_parse_failure(stats: &Stats, truncations: &mut TruncationWatch, unparseable: &mut DropLog, err: &SliceError, origin: &str) {
+    let SliceError::Len(len) = err else {
+        stats.drops.record(DropReason::ParseError);
+        unparseable.log(format_args!("Failed to parse packet from {}: {:?}", origin, err));
+        return;
+    };
+    stats.drops.record(DropReason::Truncated);
+    unparseable.log(format_args!("Truncated packet from {}: {} bytes where the {} needs {}", origin, len.len, len.layer, len.required_len));
+    if truncations.record() {
+        warn!(
+            "{} truncated packets in the last minute. Check that the TUN MTU on both ends is at most 9000 bytes \
+             and that no proxy between client and server limits or splits WebSocket frames.",
+            TRUNCATION_HINT_THRESHOLD
+        );
+    }
+}
+
+// Packets for clients whose session dropped, held per client until it reconnects
+// (--reconnect-buffer). Packets evicted for being over the count or age bound are counted as
+// drops, and the buffers of clients removed from the config are dropped with their packets.
+struct AwayBuffers {
+    capacity: usize,
+    max_age: Duration,
+    buffers: HashMap<IpAddr, ReconnectBuffer>,
+}
+
+impl AwayBuffers {
+    fn new(args: &Args) -> Self {
+        AwayBuffers {
+            capacity: args.reconnect_buffer,
+            max_age: Duration::from_secs(args.reconnect_buffer_max_age),
+            buffers: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    // Hold a packet for a client without a session; false when buffering is off
+    fn hold(&mut self, client_ip: IpAddr, packet: Bytes, stats: &Stats) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let buffer = self.buffers.entry(client_ip).or_insert_with(|| ReconnectBuffer::new(self.capacity, self.max_age));
+        buffer.push(packet);
+        record_evictions(buffer, stats);
+        true
+    }
+
+    // Deliver what was held for a client while it was away, ahead of anything newer
+    fn flush(&mut self, session: &ClientSession, policy: QueueOverflowPolicy, stats: &Stats) {
+        let Some(mut buffer) = self.buffers.remove(&session.ip) else {
+            return;
+        };
+        let held = buffer.drain_fresh();
+        record_evictions(&mut buffer, stats);
+        debug!("Delivering {} packet(s) held for {} while it was away", held.len(), session.ip);
+        for packet in held {
+            if deliver(session, packet.clone(), policy, stats)
+                && let Ok(pkt) = SlicedPacket::from_ip(&packet) {
+                stats.traffic.record_to_client(&session.totals, &pkt, packet.len());
+            }
+        }
+    }
+
+    fn holds(&self, client_ip: IpAddr) -> bool {
+        self.buffers.contains_key(&client_ip)
+    }
+
+    // Clients with packets held for them
+    fn waiting(&self) -> Vec<IpAddr> {
+        self.buffers.keys().copied().collect()
+    }
+
+    // Drop packets grown too old, and forget emptied buffers and those of removed clients
+    fn expire(&mut self, config: &SharedConfig, stats: &Stats) {
+        let config = config.read().unwrap();
+        self.buffers.retain(|ip, buffer| {
+            buffer.expire();
+            record_evictions(buffer, stats);
+            if !config.clients().iter().any(|c| c.ip == *ip) {
+                stats.drops.add(DropReason::NoActiveSession, buffer.drain_fresh().len() as u64);
+                return false;
+            }
+            !buffer.is_empty()
+        });
+    }
+}
+
+fn record_evictions(buffer: &mut ReconnectBuffer, stats: &Stats) {
+    let (full, stale) = buffer.take_evicted();
+    stats.drops.add(DropReason::ReconnectBufferFull, full);
+    stats.drops.add(DropReason::ReconnectBufferExpired, stale);
+}
+
+// The ICMP error that lets the sender of a packet over an MTU find the path's MTU instead of
+// having its packets vanish, unless none may be sent for it or the rate limit is reached
+fn too_big_reply(limiter: &mut EventLimiter, stats: &Stats, original: &[u8], pkt: &SlicedPacket, server_ip: IpAddr, mtu: usize) -> Option<Vec<u8>> {
+    let reply = crate::icmp::too_big(original, pkt, server_ip, mtu)?;
+    if !limiter.allow() {
+        stats::bump(&stats.icmp.too_big_rate_limited);
+        return None;
+    }
+    Some(reply)
+}
+
+// Queue an ICMP error for the client whose packet caused it
+async fn reply_to_sender(registry: &ClientRegistry, client_ip: IpAddr, reply: Vec<u8>, policy: QueueOverflowPolicy, stats: &Stats) {
+    let session = { registry.read().await.get(&client_ip).cloned() };
+    if let Some(session) = session
+        && deliver(&session, reply.into(), policy, stats) {
+        stats::bump(&stats.icmp.too_big_sent);
+    }
+}
+
+// Queue a packet for a client. This never waits: one slow client would stall every other one.
+// A full queue is handled by --client-queue-overflow.
+fn deliver(client: &ClientSession, packet: Bytes, policy: QueueOverflowPolicy, stats: &Stats) -> bool {
+    let packet = match client.tx.try_send(packet) {
+        Ok(()) => return true,
+        Err(TrySendError::Full(packet)) => packet,
+        Err(e) => {
+            stats.drops.record(DropReason::ClientGone);
+            warn!(client = client.name.as_str(), ip:% = client.ip; "Failed to send packet to client {}: {}", client.ip, e);
+            return false;
+        }
+    };
+    stats.drops.record(DropReason::ClientQueueFull);
+    match policy {
+        QueueOverflowPolicy::DropNewest => {
+            debug!(client = client.name.as_str(), ip:% = client.ip; "Queue of client {} is full, dropping packet", client.ip);
+            false
+        }
+        QueueOverflowPolicy::DropOldest => {
+            debug!(client = client.name.as_str(), ip:% = client.ip; "Queue of client {} is full, dropping its oldest packet", client.ip);
+            // the session's send task may have made room meanwhile
+            let _ = client.rx.try_recv();
+            client.tx.try_send(packet).is_ok()
+        }
+        QueueOverflowPolicy::Disconnect => {
+            warn!(client = client.name.as_str(), ip:% = client.ip; "Queue of client {} ({}) is full, closing its session", client.name, client.ip);
+            syslog::record(Event::Kick { client: &client.name, ip: client.ip, reason: "send queue overflow" });
+            // closing the channel stops the session's send task, which tears down the rest
+            client.tx.close();
+            let session = client.session.clone();
+            tokio::spawn(async move {
+                let _ = session.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("send queue overflow".to_string()),
+                })).await;
+            });
+            false
+        }
+    }
+}
+
+// Route packets between the TUN device and the connected clients until either side closes.
+// Client entries are looked up per packet, so a reload applies to traffic right away.
+pub async fn run_data_plane<D: TunDevice>(tap: &D, tun_mtu: usize, wsrx: Receiver<WsToTunPacket>, registry: ClientRegistry, stats: Arc<Stats>, config: &SharedConfig) -> io::Result<()> {
+    let args = config.read().unwrap().server_args.clone();
+    //listen for packets from the tap interface and forward them to the correct websocket client
+    let mut pool = PacketPool::new(tun_mtu);
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut limiter = match stats.throughput.limit_bytes_per_sec {
+        0 => None,
+        rate => Some(GlobalLimiter::new(rate)),
+    };
+    let mut window_tick = tokio::time::interval(Duration::from_secs(1));
+    let mut truncations = TruncationWatch::new();
+    let mut unparseable = DropLog::new("unparseable packets");
+    // counted per client in its totals
+    let mut filtered = DropLog::new("packets to destinations outside allowlists");
+    let mut icmp_limiter = args.icmp_unreachable
+        .then(|| EventLimiter::new(args.icmp_unreachable_rate));
+    let mut too_big_limiter = EventLimiter::new(args.icmp_unreachable_rate);
+    let mut away = AwayBuffers::new(&args);
+    let mut flows = crate::flow::start(&args, stats.clone()).await?;
+    loop {
+        tokio::select! {
+            _ = window_tick.tick(), if limiter.is_some() || flows.is_some() || !away.is_empty() => {
+                if let Some(limiter) = limiter.as_mut() {
+                    stats.throughput.last_second_bytes.store(limiter.roll_window(), Ordering::Relaxed);
+                }
+                if let Some(flows) = flows.as_mut() {
+                    flows.expire();
+                }
+                // a client that reconnected without sending anything yet still gets its packets
+                away.expire(config, &stats);
+                for client_ip in away.waiting() {
+                    let session = { registry.read().await.get(&client_ip).cloned() };
+                    if let Some(session) = session {
+                        away.flush(&session, args.client_queue_overflow, &stats);
+                    }
+                }
+            }
+            result = tap.recv_batch(&mut pool, &mut batch) => {
+                if let Err(e) = result {
+                    stats::bump(&stats.tun.read_errors);
+                    error!("Error receiving from TUN: {:?}", e);
+                    if let Some(flows) = flows.as_mut() {
+                        flows.flush();
+                    }
+                    return Err(e);
+                }
+                for packet in batch.drain(..) {
+                    let size = packet.len();
+                    debug!("Received packet from TUN: {:?}", &packet[..]);
+                    //parse dst IP to determine which client to send to
+                    let pkt = match etherparse::SlicedPacket::from_ip(&packet) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            record_parse_failure(&stats, &mut truncations, &mut unparseable, &e, "TUN");
+                            continue;
+                        }
+                    };
+                    let dst = match &pkt.net {
+                        Some(NetSlice::Ipv4(header)) => IpAddr::V4(Ipv4Addr::from(header.header().destination())),
+                        Some(NetSlice::Ipv6(header)) => IpAddr::V6(Ipv6Addr::from(header.header().destination())),
+                        _ => {
+                            stats.drops.record(DropReason::UnsupportedLayer);
+                            unparseable.log(format_args!("Unsupported network layer in packet from TUN"));
+                            continue;
+                        }
+                    };
+                    // the client with this address, or with the network holding it
+                    let target = config.read().unwrap().client_for(&dst).map(|c| (c.ip, c.mtu));
+                    let Some((client_ip, client_mtu)) = target else {
+                        stats.drops.record(DropReason::UnassignedDestination);
+                        warn!("Destination IP {} is not assigned to any client, dropping packet", dst);
+                        continue;
+                    };
+                    // a client with an MTU override can't take packets larger than it
+                    if let Some(mtu) = client_mtu
+                        && size > mtu as usize {
+                        stats.drops.record(DropReason::OverMtu);
+                        debug!("Packet of {} bytes exceeds MTU of client {}, dropping", size, client_ip);
+                        if let Some(reply) = too_big_reply(&mut too_big_limiter, &stats, &packet, &pkt, args.server_ip, mtu as usize) {
+                            if let Err(e) = tap.send(reply.into()).await {
+                                stats::bump(&stats.tun.write_errors);
+                                warn!("Failed to send ICMP too big for {}: {}", dst, e);
+                            } else {
+                                stats::bump(&stats.icmp.too_big_sent);
+                            }
+                        }
+                        continue;
+                    }
+                    // route to the correct client's channel if present
+                    let session = { registry.read().await.get(&client_ip).cloned() };
+                    if let Some(session) = session {
+                        if let Some(limiter) = limiter.as_mut()
+                            && !limiter.admit(client_ip, size) {
+                            stats.drops.record(DropReason::GlobalRateLimited);
+                            debug!("Server throughput cap reached, dropping packet to {}", dst);
+                            continue;
+                        }
+                        away.flush(&session, args.client_queue_overflow, &stats);
+                        if deliver(&session, packet.clone(), args.client_queue_overflow, &stats) {
+                            stats.traffic.record_to_client(&session.totals, &pkt, size);
+                            if let Some(flows) = flows.as_mut() {
+                                flows.record(&pkt, size);
+                            }
+                        }
+                    } else if away.hold(client_ip, packet.clone(), &stats) {
+                        debug!("No active session for {} ({}), holding packet until it reconnects", client_ip, dst);
+                    } else {
+                        // client not currently connected
+                        stats.drops.record(DropReason::NoActiveSession);
+                        debug!("No active session for {} ({}), dropping packet", client_ip, dst);
+                        // tell the sender right away instead of letting it time out
+                        if let Some(icmp_limiter) = icmp_limiter.as_mut()
+                            && let Some(reply) = crate::icmp::host_unreachable(&packet, &pkt, args.server_ip) {
+                            if !icmp_limiter.allow() {
+                                stats::bump(&stats.icmp.unreachable_rate_limited);
+                            } else if let Err(e) = tap.send(reply.into()).await {
+                                stats::bump(&stats.tun.write_errors);
+                                warn!("Failed to send ICMP unreachable for {}: {}", dst, e);
+                            } else {
+                                stats::bump(&stats.icmp.unreachable_sent);
+                            }
+                        }
+                    }
+                }
+            }
+            ws_result = wsrx.recv() => {
+                match ws_result {
+                    Ok(ws_packet) => {
+                        debug!("Received packet from WebSocket for {}: {} bytes", ws_packet.client_ip, ws_packet.data.len());
+                        // a reconnected client is sent what was held for it as soon as it is heard from
+                        if away.holds(ws_packet.client_ip) {
+                            let session = { registry.read().await.get(&ws_packet.client_ip).cloned() };
+                            if let Some(session) = session {
+                                away.flush(&session, args.client_queue_overflow, &stats);
+                            }
+                        }
+                        //parse source IP to determine if it's from a valid client
+                        let pkt = match etherparse::SlicedPacket::from_ip(&ws_packet.data) {
+                            Ok(p) => p,
+                            Err(e) => {
+                                record_parse_failure(&stats, &mut truncations, &mut unparseable, &e, &format!("client {}", ws_packet.client_ip));
+                                continue;
+                            }
+                        };
+                        let (src, dst) = match &pkt.net {
+                            Some(NetSlice::Ipv4(header)) => (
+                                IpAddr::V4(Ipv4Addr::from(header.header().source())),
+                                IpAddr::V4(Ipv4Addr::from(header.header().destination())),
+                            ),
+                            Some(NetSlice::Ipv6(header)) => (
+                                IpAddr::V6(Ipv6Addr::from(header.header().source())),
+                                IpAddr::V6(Ipv6Addr::from(header.header().destination())),
+                            ),
+                            _ => {
+                                stats.drops.record(DropReason::UnsupportedLayer);
+                                unparseable.log(format_args!("Unsupported network layer in packet from client {}", ws_packet.client_ip));
+                                continue;
+                            }
+                        };
+                        // the client's entry decides both checks below; no lock is held across awaits
+                        let (own_source, special_source, permitted, peer) = {
+                            let config = config.read().unwrap();
+                            let client = config.clients().iter().find(|c| c.ip == ws_packet.client_ip);
+                            (
+                                // its own address, or one of its networks no other client has more specifically
+                                config.client_for(&src).is_some_and(|c| c.ip == ws_packet.client_ip),
+                                client.and_then(|c| c.special_source(src, &pkt)),
+                                client.is_some_and(|c| c.may_reach(&dst)),
+                                // Some((the peer's IP, its MTU override)) when the destination is another client
+                                config.client_for(&dst).map(|c| (c.ip, c.mtu)),
+                            )
+                        };
+                        // strict check: source must belong to the authenticated client, unless the
+                        // client may bootstrap with a well-known special source
+                        if !own_source {
+                            match special_source {
+                                Some(SpecialSource::Dhcp) => stats::bump(&stats.special_sources.dhcp),
+                                Some(SpecialSource::LinkLocal) => stats::bump(&stats.special_sources.link_local),
+                                None => {
+                                    stats.drops.record(DropReason::Spoofed);
+                                    warn!(ip:% = ws_packet.client_ip; "Spoofed packet: src {} != authenticated {}. Dropping.", src, ws_packet.client_ip);
+                                    continue;
+                                }
+                            }
+                        }
+                        // oversized packets would be rejected or mangled by the TUN device
+                        if ws_packet.data.len() > tun_mtu {
+                            stats.drops.record(DropReason::OverMtu);
+                            debug!("Packet of {} bytes from {} exceeds TUN MTU {}, dropping", ws_packet.data.len(), ws_packet.client_ip, tun_mtu);
+                            if let Some(reply) = too_big_reply(&mut too_big_limiter, &stats, &ws_packet.data, &pkt, args.server_ip, tun_mtu) {
+                                reply_to_sender(&registry, ws_packet.client_ip, reply, args.client_queue_overflow, &stats).await;
+                            }
+                            continue;
+                        }
+                        if pkt.net.as_ref().and_then(|net| net.ip_payload_ref()).is_some_and(|p| p.fragmented) {
+                            stats::bump(&stats.fragments.fragments);
+                        }
+                        // destination allowlist: only forward to networks the client may reach.
+                        // It only looks at the destination address, which every fragment carries,
+                        // so all fragments of a datagram get the same verdict.
+                        if !permitted {
+                            stats.drops.record(DropReason::FilteredByAcl);
+                            stats::bump(&ws_packet.totals.filtered_by_acl);
+                            filtered.log(format_args!("Client {} is not permitted to reach {}, dropping", ws_packet.client_ip, dst));
+                            continue;
+                        }
+                        if let Some(limiter) = limiter.as_mut()
+                            && !limiter.admit(ws_packet.client_ip, ws_packet.data.len()) {
+                            stats.drops.record(DropReason::GlobalRateLimited);
+                            debug!("Server throughput cap reached, dropping packet from {}", ws_packet.client_ip);
+                            continue;
+                        }
+                        // traffic between clients never leaves through the TUN device, where it
+                        // could be routed out of the external interface
+                        if let Some((peer_ip, peer_mtu)) = peer {
+                            if !args.allow_client_to_client {
+                                stats.drops.record(DropReason::ClientToClient);
+                                debug!("Client {} may not reach client {} ({}), dropping packet", ws_packet.client_ip, peer_ip, dst);
+                                continue;
+                            }
+                            if let Some(mtu) = peer_mtu
+                                && ws_packet.data.len() > mtu as usize {
+                                stats.drops.record(DropReason::OverMtu);
+                                debug!("Packet of {} bytes exceeds MTU of client {}, dropping", ws_packet.data.len(), peer_ip);
+                                if let Some(reply) = too_big_reply(&mut too_big_limiter, &stats, &ws_packet.data, &pkt, args.server_ip, mtu as usize) {
+                                    reply_to_sender(&registry, ws_packet.client_ip, reply, args.client_queue_overflow, &stats).await;
+                                }
+                                continue;
+                            }
+                            let session = { registry.read().await.get(&peer_ip).cloned() };
+                            let Some(peer) = session else {
+                                if away.hold(peer_ip, ws_packet.data.clone(), &stats) {
+                                    stats.traffic.record_from_client(&ws_packet.totals, &pkt, ws_packet.data.len());
+                                    debug!("No active session for {} ({}), holding packet from {} until it reconnects", peer_ip, dst, ws_packet.client_ip);
+                                } else {
+                                    stats.drops.record(DropReason::NoActiveSession);
+                                    debug!("No active session for {} ({}), dropping packet from {}", peer_ip, dst, ws_packet.client_ip);
+                                }
+                                continue;
+                            };
+                            away.flush(&peer, args.client_queue_overflow, &stats);
+                            if deliver(&peer, ws_packet.data.clone(), args.client_queue_overflow, &stats) {
+                                stats.traffic.record_from_client(&ws_packet.totals, &pkt, ws_packet.data.len());
+                                stats.traffic.record_to_client(&peer.totals, &pkt, ws_packet.data.len());
+                                if let Some(flows) = flows.as_mut() {
+                                    flows.record(&pkt, ws_packet.data.len());
+                                }
+                            }
+                            continue;
+                        }
+
+                        if let Err(e) = tap.send(ws_packet.data.clone()).await {
+                            stats.drops.record(DropReason::TunWriteFailed);
+                            stats::bump(&stats.tun.write_errors);
+                            eprintln!("Failed to send packet to TUN: {:?}", e);
+                        } else {
+                            stats.traffic.record_from_client(&ws_packet.totals, &pkt, ws_packet.data.len());
+                            if let Some(flows) = flows.as_mut() {
+                                flows.record(&pkt, ws_packet.data.len());
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        // every sender lives in the HTTP server, so this only happens once it is gone
+                        info!("WebSocket channel closed, stopping TUN handler");
+                        if let Some(flows) = flows.as_mut() {
+                            flows.flush();
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,72 @@
+use actix_web::{http::{header, StatusCode}, web::Bytes, HttpResponse};
+
+use crate::Args;
+
+// What requests failing authentication get back. The default bare 404 gives away little, but
+// operators may prefer to pass for an ordinary site: a landing page, or a redirect to one.
+#[derive(Debug, Clone)]
+pub struct UnauthenticatedResponse {
+    status: StatusCode,
+    location: Option<header::HeaderValue>,
+    content_type: &'static str,
+    body: Bytes,
+}
+
+impl UnauthenticatedResponse {
+    pub fn load(args: &Args) -> Result<Self, String> {
+        let location = match &args.unauthenticated_redirect {
+            Some(url) if !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with('/')) => {
+                return Err(format!("Unauthenticated redirect {} must be an http(s) URL or an absolute path", url));
+            }
+            Some(url) => Some(header::HeaderValue::from_str(url)
+                .map_err(|_| format!("Unauthenticated redirect {} is not a valid header value", url))?),
+            None => None,
+        };
+        let default_status = if location.is_some() { 302 } else { 404 };
+        let code = args.unauthenticated_status.unwrap_or(default_status);
+        let status = StatusCode::from_u16(code)
+            .ok()
+            .filter(|s| !s.is_informational())
+            .ok_or_else(|| format!("Unauthenticated status {} is not a final HTTP status code", code))?;
+        if status.is_redirection() != location.is_some() {
+            return Err("--unauthenticated-redirect needs a 3xx --unauthenticated-status and vice versa".to_string());
+        }
+        let (content_type, body) = match &args.unauthenticated_body {
+            Some(path) => {
+                let body = std::fs::read(path).map_err(|e| format!("Failed to read unauthenticated body {}: {}", path, e))?;
+                (content_type(path), Bytes::from(body))
+            }
+            None => ("text/plain; charset=utf-8", Bytes::new()),
+        };
+        // these statuses must not carry a body; actix would silently drop it
+        if !body.is_empty() && matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) {
+            return Err(format!("Unauthenticated status {} can't have a body", code));
+        }
+        Ok(UnauthenticatedResponse { status, location, content_type, body })
+    }
+
+    pub fn respond(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status);
+        if let Some(location) = &self.location {
+            res.insert_header((header::LOCATION, location.clone()));
+        }
+        if self.body.is_empty() {
+            return res.finish();
+        }
+        res.content_type(self.content_type).body(self.body.clone())
+    }
+}
+
+pub fn content_type(path: &str) -> &'static str {
+    let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        "css" => "text/css",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
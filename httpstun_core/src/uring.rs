@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+use bytes::Bytes;
+use io_uring::{opcode, squeue, types, IoUring};
+use log::error;
+use nix::sys::eventfd::{EfdFlags, EventFd};
+
+use crate::device::{TunDevice, BATCH_SIZE};
+use crate::pool::PacketPool;
+
+const RING_ENTRIES: u32 = 256;
+// reads kept in flight so the kernel always has a buffer for the next packet
+const READS_IN_FLIGHT: u64 = 32;
+const PACKET_BUF: usize = 9000;
+// writes queued for the ring thread before `send` starts refusing them
+const WRITE_QUEUE: usize = 1024;
+
+// user_data layout: the top bits say what completed, the rest index the buffer or write
+const WAKE: u64 = 0;
+const READ_TAG: u64 = 1 << 62;
+const WRITE_TAG: u64 = 2 << 62;
+const TAG_MASK: u64 = 3 << 62;
+
+// TUN I/O through io_uring. A dedicated thread owns the ring, keeps several reads in flight
+// on the device and submits writes queued by `send`, being woken for those via an eventfd.
+// `send` fails when the queue is full; errors of writes already queued surface after it
+// returned, so the ring thread hands them to the callback given to `new`.
+pub struct UringTun {
+    packets: async_channel::Receiver<io::Result<Bytes>>,
+    writes: mpsc::SyncSender<Bytes>,
+    wake: Arc<Waker>,
+}
+
+// Wakes the ring thread for queued writes, coalescing wake-ups until it has drained them
+struct Waker {
+    fd: EventFd,
+    pending: AtomicBool,
+}
+
+impl Waker {
+    fn wake(&self) -> io::Result<()> {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            self.fd.write(1).map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+impl UringTun {
+    // `device` must be a blocking, packet-oriented fd (a TUN device or a datagram socket)
+    pub fn new<D, E>(device: D, on_write_error: E) -> io::Result<Self>
+    where
+        D: AsRawFd + Send + 'static,
+        E: Fn(io::Error) + Send + 'static,
+    {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let wake = Arc::new(Waker {
+            fd: EventFd::from_flags(EfdFlags::EFD_CLOEXEC).map_err(io::Error::from)?,
+            pending: AtomicBool::new(false),
+        });
+        let (packet_tx, packets) = async_channel::bounded(1024);
+        let (writes, write_rx) = mpsc::sync_channel(WRITE_QUEUE);
+        let thread_wake = wake.clone();
+        std::thread::Builder::new()
+            .name("httpstun-uring".to_string())
+            .spawn(move || {
+                if let Err(e) = run_ring(ring, device, &thread_wake, packet_tx, write_rx, on_write_error) {
+                    error!("io_uring TUN thread failed: {}", e);
+                }
+            })?;
+        Ok(UringTun { packets, writes, wake })
+    }
+}
+
+impl Drop for UringTun {
+    fn drop(&mut self) {
+        // the ring thread notices the closed write queue on its next wake-up
+        let _ = self.wake.fd.write(1);
+    }
+}
+
+impl TunDevice for UringTun {
+    fn send(&self, packet: Bytes) -> impl Future<Output = io::Result<usize>> + Send {
+        let len = packet.len();
+        let queued = match self.writes.try_send(packet) {
+            Ok(()) => self.wake.wake().map(|_| len),
+            // the ring thread is behind; waiting here would stall every client, so drop it
+            Err(mpsc::TrySendError::Full(_)) => Err(io::Error::new(io::ErrorKind::WouldBlock, "io_uring write queue full")),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "io_uring thread stopped")),
+        };
+        async move { queued }
+    }
+
+    // the ring thread already hands over pooled packets, so the data plane's pool goes unused
+    async fn recv_batch(&self, _pool: &mut PacketPool, packets: &mut Vec<Bytes>) -> io::Result<()> {
+        let stopped = || io::Error::new(io::ErrorKind::BrokenPipe, "io_uring thread stopped");
+        if packets.len() < BATCH_SIZE {
+            packets.push(self.packets.recv().await.map_err(|_| stopped())??);
+        }
+        while packets.len() < BATCH_SIZE {
+            match self.packets.try_recv() {
+                Ok(packet) => packets.push(packet?),
+                // a stopped thread is reported by the next call
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+    loop {
+        // SAFETY: every buffer referenced by an entry lives in `RingBuffers`, which outlives
+        // the operation: write buffers stay in `pending_writes` until their completion is
+        // reaped and the rest is never freed while the ring may still use it.
+        if unsafe { ring.submission().push(entry) }.is_ok() {
+            return Ok(());
+        }
+        ring.submit()?;
+    }
+}
+
+// Memory the kernel may write into or read from while operations are in flight
+struct RingBuffers {
+    reads: Vec<Vec<u8>>,
+    wake: [u8; 8],
+    pending_writes: HashMap<u64, Bytes>,
+}
+
+fn run_ring<D: AsRawFd>(
+    mut ring: IoUring,
+    device: D,
+    wake: &Waker,
+    packet_tx: async_channel::Sender<io::Result<Bytes>>,
+    write_rx: mpsc::Receiver<Bytes>,
+    on_write_error: impl Fn(io::Error),
+) -> io::Result<()> {
+    let mut bufs = Box::new(RingBuffers {
+        reads: (0..READS_IN_FLIGHT).map(|_| vec![0u8; PACKET_BUF]).collect(),
+        wake: [0u8; 8],
+        pending_writes: HashMap::new(),
+    });
+    let result = ring_loop(&mut ring, types::Fd(device.as_raw_fd()), wake, &mut bufs, packet_tx, write_rx, on_write_error);
+    // operations still in flight are only cancelled asynchronously after the ring is
+    // closed, so their buffers are leaked rather than freed under the kernel
+    drop(ring);
+    std::mem::forget(bufs);
+    result
+}
+
+fn ring_loop(
+    ring: &mut IoUring,
+    fd: types::Fd,
+    wake: &Waker,
+    bufs: &mut RingBuffers,
+    packet_tx: async_channel::Sender<io::Result<Bytes>>,
+    write_rx: mpsc::Receiver<Bytes>,
+    on_write_error: impl Fn(io::Error),
+) -> io::Result<()> {
+    let wake_fd = types::Fd(wake.fd.as_raw_fd() as RawFd);
+    let mut next_write: u64 = 0;
+    // read buffers go straight back to the kernel, so completed packets are copied out
+    let mut pool = PacketPool::new(PACKET_BUF);
+
+    let read_entry = |buf: &mut Vec<u8>, idx: u64| {
+        opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32).build().user_data(READ_TAG | idx)
+    };
+    for (idx, buf) in bufs.reads.iter_mut().enumerate() {
+        push(ring, &read_entry(buf, idx as u64))?;
+    }
+    let wake_entry = opcode::Read::new(wake_fd, bufs.wake.as_mut_ptr(), 8).build().user_data(WAKE);
+    push(ring, &wake_entry)?;
+
+    loop {
+        ring.submit_and_wait(1)?;
+        let completions: Vec<(u64, i32)> = ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect();
+        for (user_data, result) in completions {
+            match user_data & TAG_MASK {
+                READ_TAG => {
+                    let idx = user_data & !TAG_MASK;
+                    let buf = &mut bufs.reads[idx as usize];
+                    let packet = if result >= 0 {
+                        Ok(pool.copy(&buf[..result as usize]))
+                    } else {
+                        Err(io::Error::from_raw_os_error(-result))
+                    };
+                    let failed = packet.is_err();
+                    // a closed channel means the data plane is gone
+                    if packet_tx.send_blocking(packet).is_err() || failed {
+                        return Ok(());
+                    }
+                    push(ring, &read_entry(buf, idx))?;
+                }
+                WRITE_TAG => {
+                    bufs.pending_writes.remove(&(user_data & !TAG_MASK));
+                    if result < 0 {
+                        on_write_error(io::Error::from_raw_os_error(-result));
+                    }
+                }
+                _ => {
+                    // cleared before draining so a write queued meanwhile wakes us again
+                    wake.pending.store(false, Ordering::Release);
+                    loop {
+                        match write_rx.try_recv() {
+                            Ok(packet) => {
+                                let id = next_write;
+                                next_write = (next_write + 1) & !TAG_MASK;
+                                let entry = opcode::Write::new(fd, packet.as_ptr(), packet.len() as u32)
+                                    .build()
+                                    .user_data(WRITE_TAG | id);
+                                bufs.pending_writes.insert(id, packet);
+                                push(ring, &entry)?;
+                            }
+                            Err(mpsc::TryRecvError::Empty) => break,
+                            Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                        }
+                    }
+                    push(ring, &wake_entry)?;
+                }
+            }
+        }
+    }
+}
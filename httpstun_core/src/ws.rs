@@ -0,0 +1,940 @@
+use actix_web::{http::header, rt, web, Error, HttpRequest, HttpResponse};
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use bytes::Bytes;
+use ipnet::IpNet;
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Message, MessageStream, ProtocolError};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use futures_util::{future::Either, StreamExt as _};
+use log::{error, warn, debug, info};
+
+use crate::{Args, AuthError, Client, ClientRegistry, ClientSession, Config, IpConflictPolicy, SessionIndex, SharedConfig, WsToTunPacket};
+use crate::control::{negotiate_features, ClientMessage, Pong, Redirect, ServerMessage, SessionConfig, SessionStats, TunAddress, CONTROL_CHANNEL, SESSION_ID_HEADER, SESSION_TOKEN_HEADER, WS_SUBPROTOCOL};
+use crate::accounting::Accounting;
+use crate::compression::{Codec, COMPRESSION_HEADER};
+use crate::decoy::{is_upgrade, Decoy};
+use crate::pool::PacketPool;
+use crate::psk::{self, Opener, Psk, Sealer, Side};
+use crate::ratelimit::{AuthLimiter, SessionLimiter};
+use crate::session_token;
+use crate::unauthenticated::UnauthenticatedResponse;
+use crate::stats::{self, DropReason, SessionCounters, Stats};
+use crate::syslog::{self, Event};
+
+// Client name and password from the auth headers. Proxies that strip custom headers can be
+// worked around with --allow-query-auth, which accepts `name` and `password` query parameters
+// instead, but only on TLS connections since URLs routinely end up in logs.
+fn credentials(req: &HttpRequest, config: &Config) -> (String, String) {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    if let Some(name) = header("X-Httpstun-Client-Name") {
+        return (name, header("X-Httpstun-Client-Password").unwrap_or_default());
+    }
+    let Ok(mut query) = web::Query::<HashMap<String, String>>::from_query(req.query_string()) else {
+        return Default::default();
+    };
+    if !query.contains_key("name") {
+        return Default::default();
+    }
+    if !config.server_args.allow_query_auth {
+        debug!("Ignoring query string credentials, --allow-query-auth is not set");
+        return Default::default();
+    }
+    // honours X-Forwarded-Proto, so a TLS-terminating proxy in front works too
+    if req.connection_info().scheme() != "https" {
+        warn!("Rejecting query string credentials sent without TLS");
+        return Default::default();
+    }
+    (query.remove("name").unwrap_or_default(), query.remove("password").unwrap_or_default())
+}
+
+// The response to requests that fail authentication, as configured at startup. Counts the
+// failure, also against the source's --auth-max-failures.
+fn unauthenticated(req: &HttpRequest) -> HttpResponse {
+    if let Some(stats) = server_stats(req) {
+        stats::bump(&stats.auth.failures);
+    }
+    if let (Some(limiter), Some(peer)) = (req.app_data::<web::Data<AuthLimiter>>(), req.peer_addr())
+        && limiter.fail(peer.ip()) {
+        warn!("Refusing {} for {}s after too many failed logins", peer.ip(), limiter.ban().as_secs());
+    }
+    match req.app_data::<web::Data<UnauthenticatedResponse>>() {
+        Some(response) => response.respond(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// 429 for a source banned after too many failed logins, checked before spending any Argon2
+// work on its request
+fn auth_banned(req: &HttpRequest) -> Option<HttpResponse> {
+    let limiter = req.app_data::<web::Data<AuthLimiter>>()?;
+    let left = limiter.banned_for(req.peer_addr()?.ip())?;
+    if let Some(stats) = server_stats(req) {
+        stats::bump(&stats.auth.rate_limited);
+    }
+    Some(HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, left.as_secs().max(1).to_string()))
+        .finish())
+}
+
+// A successful login clears the source's failures
+fn auth_succeeded(req: &HttpRequest) {
+    if let (Some(limiter), Some(peer)) = (req.app_data::<web::Data<AuthLimiter>>(), req.peer_addr()) {
+        limiter.succeed(peer.ip());
+    }
+}
+
+fn log_auth_failure(req: &HttpRequest, client_name: &str, err: &AuthError, on: &str) {
+    let peer = req.peer_addr().map(|a| a.to_string()).unwrap_or("unknown".to_string());
+    match err {
+        AuthError::InvalidCredentials => warn!(client = client_name; "Invalid client name or password{} from {}", on, peer),
+        // the client can't log in until the config is fixed, so this is for the operator
+        AuthError::CorruptHash(_) => error!(client = client_name; "Client {}{} from {} can't be authenticated: {}", client_name, on, peer, err),
+    }
+    syslog::record(Event::AuthFailure { client: client_name, peer: req.peer_addr() });
+}
+
+// The client a request logs in as: by a valid session token, which skips the Argon2 work, else
+// by name and password. Also says whether the password was used, as only those logins are
+// issued a token, so a token's expiry forces a password login again.
+fn authenticate<'a>(req: &HttpRequest, config: &'a Config, on: &str) -> Result<(&'a Client, bool), HttpResponse> {
+    if let Some(token) = req.headers().get(SESSION_TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+        match session_token::verify(token, config) {
+            Some(client) => {
+                auth_succeeded(req);
+                return Ok((client, false));
+            }
+            None => debug!("Ignoring invalid or expired session token{}", on),
+        }
+    }
+    let (client_name, client_password) = credentials(req, config);
+    match crate::validate_client(&client_name, &client_password, config) {
+        Ok(client) => {
+            auth_succeeded(req);
+            Ok((client, true))
+        }
+        Err(e) => {
+            // 404 (or the configured response) against RFC to avoid leaking info
+            log_auth_failure(req, &client_name, &e, on);
+            Err(unauthenticated(req))
+        }
+    }
+}
+
+// A fresh session token for the client after a password login, unless tokens are disabled
+fn insert_session_token(res: &mut HttpResponse, client: &Client, config: &Config) {
+    let lifetime = config.server_args.session_token_lifetime;
+    if lifetime == 0 {
+        return;
+    }
+    let token = session_token::issue(client, Duration::from_secs(lifetime));
+    res.headers_mut().insert(
+        header::HeaderName::from_bytes(SESSION_TOKEN_HEADER.as_bytes()).expect("valid header name"),
+        header::HeaderValue::from_str(&token).expect("tokens are hex and digits"),
+    );
+}
+
+// Serve the tunnel at --ws-path, with the control connection below it. The app must provide
+// SharedConfig, the Sender<WsToTunPacket> of the data plane, ClientRegistry, SessionIndex and
+// Arc<Accounting> as app data; Arc<Stats>, Decoy and AuthLimiter are optional.
+pub fn configure(cfg: &mut web::ServiceConfig, ws_path: &str) {
+    cfg.service(web::resource(ws_path).route(web::get().to(tun_service)))
+        .service(web::resource(control_path(ws_path)).route(web::get().to(control_service)));
+}
+
+fn control_path(ws_path: &str) -> String {
+    format!("{}/control", ws_path.trim_end_matches('/'))
+}
+
+pub fn validate_path(args: &Args) -> Result<(), String> {
+    let path = &args.ws_path;
+    if !path.starts_with('/') || path.contains(['?', '#']) || path.chars().any(char::is_whitespace) {
+        return Err(format!("--ws-path {:?} must be an absolute URL path", path));
+    }
+    // registered first, these would be answered by the tunnel instead
+    let taken = [(args.metrics, "/metrics"), (args.health_check, "/healthz")];
+    if let Some((_, other)) = taken.iter().find(|(on, other)| *on && (path == other || control_path(path) == *other)) {
+        return Err(format!("--ws-path {} conflicts with {}", path, other));
+    }
+    Ok(())
+}
+
+fn server_stats(req: &HttpRequest) -> Option<&Arc<Stats>> {
+    req.app_data::<web::Data<Arc<Stats>>>().map(|stats| stats.get_ref())
+}
+
+async fn tun_service(req: HttpRequest, stream: web::Payload, web_tx: web::Data<async_channel::Sender<WsToTunPacket>>, registry: web::Data<ClientRegistry>, sessions: web::Data<SessionIndex>, accounting: web::Data<Arc<Accounting>>, config : web::Data<SharedConfig>) -> Result<HttpResponse, Error> {
+    // page loads get the decoy site, without looking at credentials
+    if let Some(decoy) = req.app_data::<web::Data<Decoy>>()
+        && !is_upgrade(&req)
+        && let Some(page) = decoy.index().await {
+        return Ok(page);
+    }
+    if let Some(response) = auth_banned(&req) {
+        return Ok(response);
+    }
+    // a snapshot, so a reload mid-handshake can't mix old and new client entries
+    let config = config.read().unwrap().clone();
+    // ahead of authentication, which spends an Argon2 verification on every password login
+    if let Some(response) = server_full(&req, &config, &registry).await {
+        return Ok(response);
+    }
+    let (client, password_login) = match authenticate(&req, &config, "") {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let client_name = client.name.as_str();
+    let (client_ip, client_mtu, session_limit) = (client.ip, client.mtu, client.session_limit(&config.server_args));
+    let offered: Vec<String> = req.headers().get("X-Httpstun-Features")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').map(|f| f.trim().to_string()).filter(|f| !f.is_empty()).collect())
+        .unwrap_or_default();
+    let negotiated = negotiate_features(&offered, &config.server_args.require_feature);
+    let offers_subprotocol = offers_subprotocol(&req);
+    if !offers_subprotocol && config.server_args.require_subprotocol {
+        warn!(client = client_name, ip:% = client_ip; "Client {} did not offer WebSocket subprotocol {}, rejecting", client_name, WS_SUBPROTOCOL);
+        return Ok(HttpResponse::BadRequest().body(format!("expected WebSocket subprotocol {}", WS_SUBPROTOCOL)));
+    }
+    // the key was checked when the config was loaded
+    let psk = match Psk::load(config.server_args.psk.as_deref()) {
+        Ok(psk) => psk,
+        Err(e) => {
+            error!("Refusing client {}: {}", client_name, e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    // the connection's keys mix the client's salt with ours
+    let psk = match psk {
+        Some(psk) => {
+            let client_salt = req.headers().get(psk::SALT_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+            let keys = Psk::salt().and_then(|server_salt| {
+                let keys = psk.session(Side::Server, client_salt, &server_salt)?;
+                Ok((keys, server_salt))
+            });
+            match keys {
+                Ok(keys) => Some(keys),
+                Err(e) => {
+                    warn!(client = client_name, ip:% = client_ip; "Refusing client {} without a usable {}: {}", client_name, psk::SALT_HEADER, e);
+                    return Ok(HttpResponse::BadRequest().body(format!("this server requires --psk: {}", e)));
+                }
+            }
+        }
+        None => None,
+    };
+    let (mut res, session, stream) = actix_ws::handle(&req, stream)?;
+    // only echoed when offered: a client that asked for no subprotocol must not get one
+    if offers_subprotocol {
+        res.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, header::HeaderValue::from_static(WS_SUBPROTOCOL));
+    }
+    if password_login {
+        insert_session_token(&mut res, client, &config);
+    }
+    let psk = psk.map(|(keys, server_salt)| {
+        res.headers_mut().insert(
+            header::HeaderName::from_bytes(psk::SALT_HEADER.as_bytes()).expect("valid header name"),
+            header::HeaderValue::from_str(&server_salt).expect("base64 is a valid header value"),
+        );
+        keys
+    });
+    // clients that don't ask for compression get plain packets, with no codec byte
+    let codec = req.headers().get(COMPRESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Codec::negotiate);
+    if let Some(codec) = codec {
+        res.headers_mut().insert(
+            header::HeaderName::from_bytes(COMPRESSION_HEADER.as_bytes()).expect("valid header name"),
+            header::HeaderValue::from_static(codec.name()),
+        );
+        debug!(client = client_name, ip:% = client_ip; "Client {} uses {} compression", client_name, codec.name());
+    }
+
+    // each message carries one packet, so anything much bigger is a misbehaving client
+    let max_frame_size = config.server_args.max_frame_size;
+    let stream = stream
+        .max_frame_size(max_frame_size)
+        .aggregate_continuations()
+        .max_continuation_size(max_frame_size);
+
+    let features = match negotiated {
+        Ok(features) => features,
+        Err(missing) => {
+            warn!(client = client_name, ip:% = client_ip; "Client {} lacks required features {:?}, closing", client_name, missing);
+            rt::spawn(async move {
+                let _ = session.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(format!("missing required features: {}", missing.join(","))),
+                })).await;
+            });
+            return Ok(res);
+        }
+    };
+    let args = &config.server_args;
+    let ping_interval = Duration::from_secs(args.ping_interval);
+    let hello = ServerMessage::SessionConfig(SessionConfig {
+        // the subnet was checked when the config was loaded
+        address: args.subnet().ok().map(|server| TunAddress::for_client(args.addressing_mode, client_ip, server)),
+        mtu: client_mtu.or(args.mtu),
+        routes: args.push_route.iter().map(IpNet::trunc).collect(),
+        dns_servers: args.dns_server.clone(),
+        dns_domains: args.dns_domain.clone(),
+        features: features.clone(),
+    }).to_json();
+    let control = features.iter().any(|f| f == CONTROL_CHANNEL).then(|| ControlLink::new(hello.clone()));
+    if let Some(link) = &control {
+        res.headers_mut().insert(
+            header::HeaderName::from_bytes(SESSION_ID_HEADER.as_bytes()).expect("valid header name"),
+            header::HeaderValue::from_str(&link.id).expect("session ids are hex"),
+        );
+    }
+    // Create per-client channel and register before answering, so a control connection
+    // opened right after the upgrade finds the session
+    let (client_tx, client_rx) = async_channel::bounded::<Bytes>(args.client_queue);
+    let totals = server_stats(&req).map(|stats| stats.clients.of(client_name)).unwrap_or_default();
+    let client_session = Arc::new(ClientSession {
+        rate_limit_bps: client.rate_limit(&config.server_args),
+        ..ClientSession::new(client_name.to_string(), client_ip, client_tx, client_rx.clone(), session.clone(), control, totals)
+    });
+    // the pings go out before the registry is locked, so a slow connection can't hold up the
+    // data plane; a failed one closes its session, which is all the checks below look at
+    prune_sessions(&sessions, client_name).await;
+    let holder = registry.read().await.get(&client_ip).filter(|s| s.name != client_name).cloned();
+    if let Some(holder) = holder
+        && !is_live(&holder).await {
+        holder.tx.close();
+    }
+    {
+        // held across the checks so concurrent connects of one client can't both pass
+        let mut map = registry.write().await;
+        // another client may have taken the last slot since server_full looked
+        let max_clients = config.server_args.max_clients;
+        if max_clients > 0 && !map.contains_key(&client_ip) && map.len() >= max_clients {
+            drop(map);
+            warn!(client = client_name, ip:% = client_ip; "Client {} connected while the server is full ({} clients), rejecting", client_name, max_clients);
+            syslog::record(Event::Rejected { client: client_name, ip: client_ip, reason: "server full" });
+            rt::spawn(async move {
+                let _ = session.close(Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some("server full".to_string()),
+                })).await;
+            });
+            return Ok(res);
+        }
+        // a client at its limit is most likely reconnecting over a connection the server hasn't
+        // seen drop yet, so its oldest sessions make room instead of the new one being refused
+        for old in sessions_to_replace(&sessions, client_name, session_limit) {
+            info!(client = client_name, ip:% = client_ip; "Client {} is at its limit of {} sessions, replacing its oldest", client_name, session_limit);
+            if map.get(&old.ip).is_some_and(|s| Arc::ptr_eq(s, &old)) {
+                map.remove(&old.ip);
+            }
+            accounting.stop(&old, "replaced by a new session");
+            syslog::record(Event::Kick { client: &old.name, ip: old.ip, reason: "replaced by a new session" });
+            old.tx.close();
+            rt::spawn(async move {
+                let _ = old.session.clone().close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("replaced by a new session".to_string()),
+                })).await;
+            });
+        }
+        // the registry is keyed by IP, so inserting would silently steal another client's traffic
+        if let Some(holder) = map.get(&client_ip).filter(|s| s.name != client_name && !s.tx.is_closed()).cloned() {
+            match config.server_args.ip_conflict_policy {
+                IpConflictPolicy::Reject => {
+                    drop(map);
+                    error!(client = client_name, ip:% = client_ip; "Client {} was assigned {}, which connected client {} holds; rejecting. Give them distinct IPs.", client_name, client_ip, holder.name);
+                    syslog::record(Event::Rejected { client: client_name, ip: client_ip, reason: "address in use by another client" });
+                    rt::spawn(async move {
+                        let _ = session.close(Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("address in use by another client".to_string()),
+                        })).await;
+                    });
+                    return Ok(res);
+                }
+                IpConflictPolicy::Evict => {
+                    error!(client = client_name, ip:% = client_ip; "Client {} was assigned {}, which connected client {} holds; evicting {}. Give them distinct IPs.", client_name, client_ip, holder.name, holder.name);
+                    accounting.stop(&holder, "evicted by address conflict");
+                    syslog::record(Event::Kick { client: &holder.name, ip: holder.ip, reason: "evicted by address conflict" });
+                    holder.tx.close();
+                    rt::spawn(async move {
+                        let _ = holder.session.clone().close(Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("address taken over by another client".to_string()),
+                        })).await;
+                    });
+                }
+            }
+        }
+        sessions.lock().unwrap().entry(client_name.to_string()).or_default().push(Arc::downgrade(&client_session));
+        map.insert(client_ip, client_session.clone());
+        debug!(client = client_name, ip:% = client_ip; "Registered client {}", client_ip);
+    }
+    accounting.start(&client_session);
+    syslog::record(Event::Connect { client: client_name, ip: client_ip, peer: req.peer_addr() });
+
+    // start task but don't wait for it
+    let registry_for_task = registry.clone();
+    let framing = Framing { codec, psk, stats: server_stats(&req).cloned() };
+    let peer = req.peer_addr();
+    rt::spawn(async move {
+        // Push the session config before any packets flow, unless it goes over the control connection
+        if client_session.control.is_none() && session.clone().text(hello).await.is_err() {
+            debug!("Client {} went away before session config was sent", client_ip);
+        } else {
+            run_data_session(&client_session, session, stream, client_rx, web_tx.get_ref().clone(), ping_interval, framing).await;
+        }
+        client_session.tx.close();
+        accounting.stop(&client_session, "disconnected");
+        syslog::record(Event::Disconnect { client: &client_session.name, ip: client_ip, peer, reason: "connection closed" });
+        {
+            let mut map = registry_for_task.write().await;
+            // a reconnect may already have replaced our entry
+            if map.get(&client_ip).is_some_and(|s| Arc::ptr_eq(s, &client_session)) {
+                map.remove(&client_ip);
+                debug!("Unregistered client {}", client_ip);
+            }
+        }
+    });
+
+    // respond immediately with response connected to WS session
+    Ok(res)
+}
+
+// 503 once --max-clients are connected, unless the client the request claims to be holds one
+// of the slots: a reconnect takes over its own registry entry rather than adding one. The
+// claim is checked by authentication right after.
+async fn server_full(req: &HttpRequest, config: &Config, registry: &ClientRegistry) -> Option<HttpResponse> {
+    let max_clients = config.server_args.max_clients;
+    if max_clients == 0 {
+        return None;
+    }
+    let map = registry.read().await;
+    if map.len() < max_clients {
+        return None;
+    }
+    let claimed = match req.headers().get(SESSION_TOKEN_HEADER).and_then(|v| v.to_str().ok()).and_then(|token| session_token::verify(token, config)) {
+        Some(client) => Some(client),
+        None => {
+            let (name, _) = credentials(req, config);
+            config.clients().iter().find(|c| c.name == name)
+        }
+    };
+    if let Some(client) = claimed
+        && map.get(&client.ip).is_some_and(|session| session.name == client.name) {
+        return None;
+    }
+    drop(map);
+    let peer = req.peer_addr().map(|a| a.to_string()).unwrap_or("unknown".to_string());
+    warn!("Refusing connection from {}: the server is full ({} clients)", peer, max_clients);
+    Some(HttpResponse::ServiceUnavailable().insert_header((header::RETRY_AFTER, "5")).finish())
+}
+
+// Ping a client's sessions, closing those whose connection is gone, and forget the closed ones
+async fn prune_sessions(index: &SessionIndex, name: &str) {
+    let known: Vec<Arc<ClientSession>> = index.lock().unwrap().get(name)
+        .map(|list| list.iter().filter_map(|s| s.upgrade()).collect())
+        .unwrap_or_default();
+    for client in known {
+        if !is_live(&client).await {
+            client.tx.close();
+        }
+    }
+    if let Some(list) = index.lock().unwrap().get_mut(name) {
+        list.retain(|s| s.upgrade().is_some_and(|s| !s.tx.is_closed()));
+    }
+}
+
+// The oldest of a client's open sessions that have to go for a new one to fit in `limit`,
+// taken out of the index
+fn sessions_to_replace(index: &SessionIndex, name: &str, limit: u32) -> Vec<Arc<ClientSession>> {
+    let mut index = index.lock().unwrap();
+    let Some(list) = index.get_mut(name) else {
+        return Vec::new();
+    };
+    // kept in the order they connected
+    list.retain(|s| s.upgrade().is_some_and(|s| !s.tx.is_closed()));
+    let excess = (list.len() + 1).saturating_sub(limit as usize);
+    list.drain(..excess.min(list.len())).filter_map(|s| s.upgrade()).collect()
+}
+
+// Whether a session's connection is still up, as far as a ping can tell
+async fn is_live(client: &ClientSession) -> bool {
+    !client.tx.is_closed() && client.session.clone().ping(b"").await.is_ok()
+}
+
+// How a session's packets are carried in binary frames
+struct Framing {
+    codec: Option<Codec>,
+    // this connection's --psk keys
+    psk: Option<(Sealer, Opener)>,
+    // where frames failing decryption are counted
+    stats: Option<Arc<Stats>>,
+}
+
+async fn throttle(limiter: &mut SessionLimiter, bytes: usize) {
+    let delay = limiter.delay(bytes);
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Shuttle packets between a client's data connection and the TUN handler until either side closes
+async fn run_data_session(
+    client_session: &Arc<ClientSession>,
+    session: actix_ws::Session,
+    stream: actix_ws::AggregatedMessageStream,
+    client_rx: async_channel::Receiver<Bytes>,
+    web_tx: async_channel::Sender<WsToTunPacket>,
+    ping_interval: Duration,
+    framing: Framing,
+) {
+    let client_ip = client_session.ip;
+    // Task 1: receive messages from websocket and forward to TUN handler
+    let mut session_clone = session.clone();
+    let mut stream_recv = stream;
+    let activity = client_session.clone();
+    let Framing { codec, psk, stats } = framing;
+    let (mut sealer, mut opener) = psk.unzip();
+    let recv_task = rt::spawn(async move {
+        let mut scratch = Vec::new();
+        // only decompressed packets need memory of their own
+        let mut pool = PacketPool::new(1500);
+        let mut limiter = SessionLimiter::new(activity.rate_limit_bps);
+        let mut undecryptable = 0u64;
+        while let Some(msg) = stream_recv.next().await {
+            activity.heard();
+            // pongs only answer the server's keepalive pings; they don't make a session active
+            if !matches!(msg, Ok(AggregatedMessage::Pong(_))) {
+                activity.touch();
+            }
+            match msg {
+                Ok(AggregatedMessage::Text(text)) => {
+                    on_client_text(&activity, &text).await;
+                }
+                Ok(AggregatedMessage::Binary(bin)) => {
+                    let bin = match &mut opener {
+                        Some(opener) => match opener.open(&bin) {
+                            Some(frame) => frame,
+                            None => {
+                                if let Some(stats) = &stats {
+                                    stats.drops.record(DropReason::Undecryptable);
+                                }
+                                undecryptable += 1;
+                                // a client with another key, or none, fails on every frame
+                                if undecryptable == 1 {
+                                    warn!(client = activity.name.as_str(), ip:% = client_ip; "Dropping frame from {} that failed decryption; does it have the same --psk?", client_ip);
+                                } else {
+                                    debug!("Dropping frame from {} that failed decryption ({} so far)", client_ip, undecryptable);
+                                }
+                                continue;
+                            }
+                        },
+                        None => bin,
+                    };
+                    let data = match codec {
+                        Some(codec) => match codec.decode(&bin, &mut scratch, &mut pool) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                warn!("Dropping undecodable frame from {}: {}", client_ip, e);
+                                continue;
+                            }
+                        },
+                        None => bin,
+                    };
+                    activity.sent_packet.store(true, Ordering::Relaxed);
+                    activity.count_from_client(data.len());
+                    // not reading the socket meanwhile is what slows the client down
+                    if let Some(limiter) = limiter.as_mut() {
+                        throttle(limiter, data.len()).await;
+                    }
+                    // forward binary message to TUN handler with the authenticated client IP
+                    let pkt = WsToTunPacket { client_ip, data, totals: activity.totals.clone() };
+                    if let Err(e) = web_tx.send(pkt).await {
+                        warn!("Failed to send message to TUN handler: {}", e);
+                        return;
+                    }
+                }
+                Ok(AggregatedMessage::Ping(msg)) => {
+                    // respond to PING frame with PONG frame
+                    session_clone.pong(&msg).await.unwrap();
+                }
+                // the stream can't get past a frame it failed to read, such as one over --max-frame-size
+                Err(e) => {
+                    warn!(client = activity.name.as_str(), ip:% = client_ip; "Closing session of {}: {}", client_ip, e);
+                    let code = if matches!(e, ProtocolError::Overflow) { CloseCode::Size } else { CloseCode::Protocol };
+                    let _ = session_clone.close(Some(CloseReason { code, description: Some(e.to_string()) })).await;
+                    return;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // Task 2: receive messages from TUN handler and forward to websocket client
+    let mut session_send = session;
+    let counted = client_session.clone();
+    let send_task = rt::spawn(async move {
+        let pinging = !ping_interval.is_zero();
+        // interval panics on zero; it is never polled then
+        let period = ping_interval.max(Duration::from_secs(1));
+        let mut pings = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut limiter = SessionLimiter::new(counted.rate_limit_bps);
+        loop {
+            tokio::select! {
+                received = client_rx.recv() => {
+                    let Ok(bin) = received else {
+                        return;
+                    };
+                    let len = bin.len();
+                    // packets arriving meanwhile wait in the client's queue, whose overflow policy sheds the excess
+                    if let Some(limiter) = limiter.as_mut() {
+                        throttle(limiter, len).await;
+                    }
+                    let frame = match codec {
+                        Some(codec) => Bytes::from(codec.encode(&bin)),
+                        None => bin,
+                    };
+                    let frame = match &mut sealer {
+                        Some(sealer) => match sealer.seal(&frame) {
+                            Ok(sealed) => sealed,
+                            Err(e) => {
+                                warn!("Dropping packet for {}: {}", client_ip, e);
+                                continue;
+                            }
+                        },
+                        None => frame,
+                    };
+                    if let Err(e) = session_send.binary(frame).await {
+                        warn!("Failed to send binary message to client: {}", e);
+                        return;
+                    }
+                    counted.count_to_client(len);
+                }
+                // answered with a pong, which the sweep's --client-timeout looks for
+                _ = pings.tick(), if pinging => {
+                    if session_send.ping(b"").await.is_err() {
+                        debug!("Client {} went away, keepalive ping failed", client_ip);
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    // Wait for either task to finish, then stop the other one
+    match futures_util::future::select(recv_task, send_task).await {
+        Either::Left((_, send_task)) => send_task.abort(),
+        Either::Right((_, recv_task)) => recv_task.abort(),
+    }
+}
+
+fn offers_subprotocol(req: &HttpRequest) -> bool {
+    req.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim() == WS_SUBPROTOCOL)
+}
+
+// The control connection of a session that negotiated the control-channel feature. The
+// client opens it after the data connection, naming the session by `id`; until then (or if
+// it drops) control messages fall back to the data connection.
+pub struct ControlLink {
+    pub id: String,
+    hello: String,
+    session: std::sync::Mutex<Option<actix_ws::Session>>,
+}
+
+impl ControlLink {
+    fn new(hello: String) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        ControlLink {
+            id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            hello,
+            session: std::sync::Mutex::new(None),
+        }
+    }
+}
+
+fn session_stats(client: &ClientSession) -> ServerMessage {
+    let traffic = &client.traffic;
+    ServerMessage::SessionStats(SessionStats {
+        connected_secs: client.connected_at.elapsed().as_secs(),
+        bytes_from_client: stats::load(&traffic.bytes_from_client),
+        bytes_to_client: stats::load(&traffic.bytes_to_client),
+        packets_from_client: stats::load(&traffic.packets_from_client),
+        packets_to_client: stats::load(&traffic.packets_to_client),
+    })
+}
+
+// Act on a control message a client sent on either connection. Nothing a client sends here
+// ends its session; replies go out like any other control message.
+async fn on_client_text(client: &ClientSession, text: &str) {
+    let reply = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::ClientInfo(info)) => {
+            info!(
+                "Client {} runs {} on {}",
+                client.name,
+                info.version.as_deref().unwrap_or("an unknown version"),
+                info.platform.as_deref().unwrap_or("an unknown platform"),
+            );
+            return;
+        }
+        Ok(ClientMessage::Stats) => session_stats(client),
+        Ok(ClientMessage::Ping(ping)) => ServerMessage::Pong(Pong { nonce: ping.nonce }),
+        Ok(ClientMessage::Unknown) => {
+            debug!("Ignoring control message of unknown type from {}: {}", client.ip, text);
+            return;
+        }
+        Err(e) => {
+            warn!(client = client.name.as_str(), ip:% = client.ip; "Ignoring malformed control message from {}: {}", client.ip, e);
+            return;
+        }
+    };
+    if send_control(client, reply.to_json()).await.is_err() {
+        debug!("Client {} went away before its control message was answered", client.ip);
+    }
+}
+
+// Send a control message to a client, over its control connection when it has one
+async fn send_control(client: &ClientSession, text: String) -> Result<(), actix_ws::Closed> {
+    let control = client.control.as_ref().and_then(|link| link.session.lock().unwrap().clone());
+    if let Some(mut control) = control
+        && control.text(text.clone()).await.is_ok() {
+        return Ok(());
+    }
+    client.session.clone().text(text).await
+}
+
+async fn control_service(req: HttpRequest, stream: web::Payload, registry: web::Data<ClientRegistry>, config: web::Data<SharedConfig>) -> Result<HttpResponse, Error> {
+    if let Some(response) = auth_banned(&req) {
+        return Ok(response);
+    }
+    let config = config.read().unwrap().clone();
+    let (client_name, client_ip) = match authenticate(&req, &config, " on control connection") {
+        Ok((client, _)) => (client.name.clone(), client.ip),
+        Err(response) => return Ok(response),
+    };
+    let session_id = req.headers().get(SESSION_ID_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let client = registry.read().await.get(&client_ip).cloned();
+    // only the client's own, current data session can be controlled
+    let Some(client) = client.filter(|c| c.control.as_ref().is_some_and(|link| link.id == session_id)) else {
+        warn!(client = client_name.as_str(), ip:% = client_ip; "Control connection from {} names no session of its own", client_name);
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let (mut res, session, stream) = actix_ws::handle(&req, stream)?;
+    if offers_subprotocol(&req) {
+        res.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, header::HeaderValue::from_static(WS_SUBPROTOCOL));
+    }
+    let interval = Duration::from_secs(config.server_args.control_stats_interval.max(1));
+    let stream = stream.max_frame_size(config.server_args.max_frame_size);
+    rt::spawn(run_control(client, session, stream, interval));
+    Ok(res)
+}
+
+// Serve a control connection: the session config first, then periodic session stats, until
+// the client closes it or the data session ends
+async fn run_control(client: Arc<ClientSession>, mut session: actix_ws::Session, mut stream: MessageStream, interval: Duration) {
+    let link = client.control.as_ref().expect("control connections are only accepted for sessions with a control link");
+    if session.text(link.hello.clone()).await.is_err() {
+        return;
+    }
+    // a reconnected control connection replaces the old one
+    if let Some(old) = link.session.lock().unwrap().replace(session.clone()) {
+        rt::spawn(old.close(None));
+    }
+    debug!("Control connection attached for {}", client.ip);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Ping(p))) => {
+                    if session.pong(&p).await.is_err() { break; }
+                }
+                Some(Ok(Message::Text(text))) => on_client_text(&client, &text).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = ticker.tick() => {
+                // the data session is over
+                if client.tx.is_closed() {
+                    break;
+                }
+                if session.text(session_stats(&client).to_json()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    debug!("Control connection for {} closed", client.ip);
+    let _ = session.close(None).await;
+}
+
+
+pub struct SessionLimits {
+    pub idle_timeout: Duration,
+    pub max_lifetime: Duration,
+    pub first_packet_timeout: Duration,
+    pub client_timeout: Duration,
+}
+
+#[derive(Clone, Copy)]
+enum SweepReason {
+    TaskEnded,
+    Idle,
+    MaxLifetime,
+    NoFirstPacket,
+    Unresponsive,
+}
+
+impl SweepReason {
+    fn check(client: &ClientSession, limits: &SessionLimits) -> Option<Self> {
+        let age = client.connected_at.elapsed();
+        if client.tx.is_closed() {
+            Some(SweepReason::TaskEnded)
+        } else if !limits.first_packet_timeout.is_zero()
+            && !client.sent_packet.load(Ordering::Relaxed)
+            && age > limits.first_packet_timeout {
+            // half-open: authenticated but never tunneled anything
+            Some(SweepReason::NoFirstPacket)
+        } else if !limits.client_timeout.is_zero() && client.unheard_for() > limits.client_timeout {
+            // not even answering pings: the peer or the path to it is gone
+            Some(SweepReason::Unresponsive)
+        } else if !limits.idle_timeout.is_zero() && client.idle_for() > limits.idle_timeout {
+            Some(SweepReason::Idle)
+        } else if !limits.max_lifetime.is_zero() && age > limits.max_lifetime {
+            Some(SweepReason::MaxLifetime)
+        } else {
+            None
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            SweepReason::TaskEnded => "session task ended",
+            SweepReason::Idle => "idle timeout",
+            SweepReason::MaxLifetime => "max lifetime reached",
+            SweepReason::NoFirstPacket => "no packet sent after connect",
+            SweepReason::Unresponsive => "client unresponsive",
+        }
+    }
+
+    fn counter(self, counters: &SessionCounters) -> &AtomicU64 {
+        match self {
+            SweepReason::TaskEnded => &counters.task_ended,
+            SweepReason::Idle => &counters.idle_timeout,
+            SweepReason::MaxLifetime => &counters.max_lifetime,
+            SweepReason::NoFirstPacket => &counters.first_packet_timeout,
+            SweepReason::Unresponsive => &counters.unresponsive,
+        }
+    }
+}
+
+// Periodically close sessions that exceeded the session limits and drop registry entries
+// whose session tasks are gone. A zero limit disables that check.
+pub async fn sweep_sessions(registry: ClientRegistry, stats: Arc<Stats>, accounting: Arc<Accounting>, interval: Duration, limits: SessionLimits) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut expired = Vec::new();
+        {
+            let map = registry.read().await;
+            for (ip, client) in map.iter() {
+                if let Some(reason) = SweepReason::check(client, &limits) {
+                    expired.push((*ip, client.clone(), reason));
+                }
+            }
+        }
+        if expired.is_empty() {
+            continue;
+        }
+        {
+            let mut map = registry.write().await;
+            for (ip, client, _) in &expired {
+                if map.get(ip).is_some_and(|s| Arc::ptr_eq(s, client)) {
+                    map.remove(ip);
+                }
+            }
+        }
+        for (ip, client, reason) in expired {
+            info!("Sweeping session for {}: {}", ip, reason.description());
+            stats::bump(reason.counter(&stats.sessions));
+            accounting.stop(&client, reason.description());
+            // a session whose task already ended got its disconnect event there
+            if !matches!(reason, SweepReason::TaskEnded) {
+                syslog::record(Event::Kick { client: &client.name, ip, reason: reason.description() });
+            }
+            // closing the channel stops the session's send task, which tears down the rest
+            client.tx.close();
+            let _ = client.session.clone().close(Some(CloseReason {
+                code: CloseCode::Away,
+                description: Some(reason.description().to_string()),
+            })).await;
+        }
+    }
+}
+
+// Close every session of a client, e.g. once it was removed from the config, with `code` telling
+// it whether to reconnect. Returns how many were open.
+pub async fn disconnect_client(registry: &ClientRegistry, sessions: &SessionIndex, accounting: &Accounting, name: &str, code: CloseCode, reason: &str) -> usize {
+    let open: Vec<Arc<ClientSession>> = {
+        let mut map = registry.write().await;
+        let open: Vec<Arc<ClientSession>> = sessions.lock().unwrap().remove(name)
+            .map(|list| list.iter().filter_map(|s| s.upgrade()).collect())
+            .unwrap_or_default();
+        for client in &open {
+            if map.get(&client.ip).is_some_and(|s| Arc::ptr_eq(s, client)) {
+                map.remove(&client.ip);
+            }
+        }
+        open
+    };
+    for client in &open {
+        info!(client = name, ip:% = client.ip; "Disconnecting client {} ({}): {}", name, client.ip, reason);
+        accounting.stop(client, reason);
+        syslog::record(Event::Kick { client: name, ip: client.ip, reason });
+        client.tx.close();
+        let _ = client.session.clone().close(Some(CloseReason {
+            code,
+            description: Some(reason.to_string()),
+        })).await;
+    }
+    open.len()
+}
+
+// Close every open session, for shutdown. Returns how many were closed.
+pub async fn close_all_sessions(registry: &ClientRegistry, sessions: &SessionIndex, accounting: &Accounting, reason: &str) -> usize {
+    let open: Vec<(String, Arc<ClientSession>)> = {
+        let mut map = registry.write().await;
+        map.clear();
+        sessions.lock().unwrap().drain()
+            .flat_map(|(name, list)| list.iter().filter_map(|s| s.upgrade()).map(|s| (name.clone(), s)).collect::<Vec<_>>())
+            .collect()
+    };
+    for (name, client) in &open {
+        info!(client = name.as_str(), ip:% = client.ip; "Closing session of client {} ({}): {}", name, client.ip, reason);
+        accounting.stop(client, reason);
+        syslog::record(Event::Kick { client: name, ip: client.ip, reason });
+        client.tx.close();
+        let _ = client.session.clone().close(Some(CloseReason {
+            code: CloseCode::Away,
+            description: Some(reason.to_string()),
+        })).await;
+    }
+    open.len()
+}
+
+// Ask every connected client to move to `url`. Clients close their session themselves once
+// they have accepted the redirect; ones that refuse it or don't know it stay connected.
+pub async fn redirect_clients(registry: ClientRegistry, url: String) {
+    let sessions: Vec<Arc<ClientSession>> = registry.read().await.values().cloned().collect();
+    let message = ServerMessage::Redirect(Redirect { url: url.clone() }).to_json();
+    let mut sent = 0;
+    for client in sessions {
+        match send_control(&client, message.clone()).await {
+            Ok(()) => sent += 1,
+            Err(_) => debug!("Client {} went away before the redirect was sent", client.ip),
+        }
+    }
+    info!("Redirected {} client(s) to {}", sent, url);
+}
@@ -0,0 +1,184 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::http::StatusCode;
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use httpstun_core::{events, parse_config};
+use crate::{add_client, client_listing, reload_config, remove_live_client, ServerHandles, MIN_PASSWORD_LENGTH};
+
+// Client management over HTTP for control planes, with --admin-port. It listens apart from the
+// tunnel port, on --admin-host (loopback by default), and every request must carry
+// `Authorization: Bearer <--admin-token>`. Changes are written to the config file and applied
+// the way SIGHUP applies them, without a restart.
+struct AdminToken(String);
+
+// A comment line sent to /events subscribers this often, so proxies don't time out a quiet stream
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct NewClient {
+    name: String,
+    #[serde(default)]
+    ip: Option<IpAddr>,
+    password: String,
+}
+
+pub async fn run_admin(server: ServerHandles, address: String, token: String) -> std::io::Result<()> {
+    events::enable();
+    let token = web::Data::new(AdminToken(token));
+    let handles = web::Data::new(server);
+    let admin = HttpServer::new(move || {
+        App::new()
+            .app_data(token.clone())
+            .app_data(handles.clone())
+            .service(list_clients)
+            .service(create_client)
+            .service(delete_client)
+            .service(event_stream)
+    })
+    .disable_signals()
+    .workers(1)
+    .bind(&address)?
+    .run();
+    info!("Admin API listening on http://{}", address);
+    admin.await
+}
+
+// Compares in time independent of where the tokens differ
+fn authorized(req: &HttpRequest, token: &AdminToken) -> bool {
+    let Some(presented) = req.headers().get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    let (presented, expected) = (presented.as_bytes(), token.0.as_bytes());
+    presented.len() == expected.len() && presented.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Bearer"))
+        .json(json!({ "error": "missing or invalid admin token" }))
+}
+
+fn failed(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message.into() }))
+}
+
+// Apply a change written to the config file to the running server
+async fn apply(server: &ServerHandles) -> Result<String, String> {
+    reload_config(server).await.map_err(|e| format!("saved, but not applied; it takes effect on the next reload or restart: {}", e))
+}
+
+#[get("/clients")]
+async fn list_clients(req: HttpRequest, token: web::Data<AdminToken>, server: web::Data<ServerHandles>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return unauthorized();
+    }
+    let config_file = server.config.read().unwrap().server_args.config_file.clone();
+    match parse_config(&config_file) {
+        Ok(config) => HttpResponse::Ok().json(client_listing(&config)),
+        Err(e) => failed(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+#[post("/clients")]
+async fn create_client(req: HttpRequest, token: web::Data<AdminToken>, server: web::Data<ServerHandles>, client: web::Json<NewClient>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return unauthorized();
+    }
+    let NewClient { name, ip, password } = client.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return failed(StatusCode::BAD_REQUEST, "Client name must not be empty.");
+    }
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return failed(StatusCode::BAD_REQUEST, format!("Password must be at least {} characters long.", MIN_PASSWORD_LENGTH));
+    }
+    let args = server.config.read().unwrap().server_args.clone();
+    // hashing the password and writing the file block
+    let added = {
+        let name = name.clone();
+        web::block(move || add_client(&name, &password, ip, &args)).await
+    };
+    let ip = match added {
+        Ok(Ok(ip)) => ip,
+        Ok(Err(e)) => return failed(StatusCode::BAD_REQUEST, e),
+        Err(e) => return failed(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    info!("Admin API added client {} with IP {}", name, ip);
+    match apply(&server).await {
+        Ok(summary) => HttpResponse::Created().json(json!({ "name": name, "ip": ip, "applied": summary })),
+        Err(e) => {
+            error!("Client {} added through the admin API is {}", name, e);
+            HttpResponse::Created().json(json!({ "name": name, "ip": ip, "error": e }))
+        }
+    }
+}
+
+#[delete("/clients/{name}")]
+async fn delete_client(req: HttpRequest, token: web::Data<AdminToken>, server: web::Data<ServerHandles>, name: web::Path<String>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return unauthorized();
+    }
+    let name = name.into_inner();
+    let config_file = server.config.read().unwrap().server_args.config_file.clone();
+    let exists = {
+        let (name, config_file) = (name.clone(), config_file.clone());
+        web::block(move || parse_config(&config_file).map(|config| config.clients().iter().any(|c| c.name == name))).await
+    };
+    match exists {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return failed(StatusCode::NOT_FOUND, format!("Client {} does not exist.", name)),
+        Ok(Err(e)) => return failed(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        Err(e) => return failed(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+    // its sessions are closed before the file changes, so none outlives the entry
+    let closed = match remove_live_client(&server, &name).await {
+        Ok(closed) => closed,
+        Err(e) => return failed(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    info!("Admin API removed client {}, closing {} session(s)", name, closed);
+    match apply(&server).await {
+        Ok(summary) => HttpResponse::Ok().json(json!({ "name": name, "applied": summary })),
+        Err(e) => {
+            error!("Removal of client {} through the admin API is {}", name, e);
+            HttpResponse::Ok().json(json!({ "name": name, "error": e }))
+        }
+    }
+}
+
+// Connection events (connect, disconnect, auth failure, rejection, kick) as server-sent
+// events, one JSON object per `data:` line, for as long as the subscriber stays connected
+#[get("/events")]
+async fn event_stream(req: HttpRequest, token: web::Data<AdminToken>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return unauthorized();
+    }
+    let Some(rx) = events::subscribe() else {
+        return failed(StatusCode::SERVICE_UNAVAILABLE, "connection events are not being published");
+    };
+    let keepalive = tokio::time::interval(EVENTS_KEEPALIVE);
+    let stream = futures_util::stream::unfold((rx, keepalive), |(mut rx, mut keepalive)| async move {
+        let chunk = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => format!("data: {}\n\n", event),
+                Err(RecvError::Lagged(missed)) => format!("data: {}\n\n", events::lagged(missed)),
+                Err(RecvError::Closed) => return None,
+            },
+            // the first tick is immediate, which gets the response headers out
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), (rx, keepalive)))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
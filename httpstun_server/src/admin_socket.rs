@@ -0,0 +1,151 @@
+use std::fs::Permissions;
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use httpstun_core::stats::{self, Stats};
+use crate::{add_client, client_listing, reload_config, remove_live_client, ServerHandles, MIN_PASSWORD_LENGTH};
+
+// Admin commands over a UNIX socket (--control-socket), for tooling around daemonized servers
+// that have no terminal for the prompt. Each line is a command, answered with one line of
+// JSON: its result, or {"error": ...}. Changes are applied as a SIGHUP reload applies them.
+//
+//     add_client <name> <password> [ip]
+//     remove_client <name>
+//     list_clients
+//     stats
+//     reload
+const USAGE: &str = "add_client <name> <password> [ip], remove_client <name>, list_clients, stats, reload";
+
+// Anyone who can connect can manage clients, so only the socket's owner may
+pub fn bind(path: &str) -> std::io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        // a server still listening answers; otherwise the socket is left over from one that didn't exit cleanly
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, "another server is listening on it"));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    info!("Accepting admin commands on {}", path);
+    Ok(listener)
+}
+
+pub async fn run(listener: UnixListener, server: ServerHandles, stats: Arc<Stats>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve(stream, server.clone(), stats.clone()));
+            }
+            Err(e) => warn!("Failed to accept a control socket connection: {}", e),
+        }
+    }
+}
+
+async fn serve(stream: UnixStream, server: ServerHandles, stats: Arc<Stats>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = dispatch(&line, &server, &stats).await.unwrap_or_else(|e| json!({ "error": e }));
+        if write.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn dispatch(line: &str, server: &ServerHandles, stats: &Stats) -> Result<Value, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    match (command, words.collect::<Vec<_>>().as_slice()) {
+        ("add_client", [name, password, ip @ ..]) if ip.len() <= 1 => {
+            let ip = ip.first().map(|ip| ip.parse::<IpAddr>().map_err(|e| format!("Invalid IP {}: {}", ip, e))).transpose()?;
+            if password.len() < MIN_PASSWORD_LENGTH {
+                return Err(format!("Password must be at least {} characters long.", MIN_PASSWORD_LENGTH));
+            }
+            let args = server.config.read().unwrap().server_args.clone();
+            let (name, password) = (name.to_string(), password.to_string());
+            // hashing the password and writing the file block
+            let added = {
+                let name = name.clone();
+                tokio::task::spawn_blocking(move || add_client(&name, &password, ip, &args)).await
+            };
+            let ip = added.map_err(|e| e.to_string())??;
+            info!("Control socket added client {} with IP {}", name, ip);
+            Ok(json!({ "name": name, "ip": ip, "applied": reload_config(server).await? }))
+        }
+        ("remove_client", [name]) => {
+            let closed = remove_live_client(server, name).await?;
+            info!("Control socket removed client {}, closing {} session(s)", name, closed);
+            Ok(json!({ "name": name, "sessions_closed": closed, "applied": reload_config(server).await? }))
+        }
+        ("list_clients", []) => Ok(list_clients(server, stats)),
+        ("stats", []) => Ok(stats_json(stats)),
+        ("reload", []) => Ok(json!({ "applied": reload_config(server).await? })),
+        // the arguments aren't echoed, as they may hold a password
+        _ => Err(format!("Unknown command or wrong arguments for {:?}; commands are {}", command, USAGE)),
+    }
+}
+
+// The clients the server is running with, how many sessions each has open and how many of
+// its packets the destination allowlist dropped
+fn list_clients(server: &ServerHandles, stats: &Stats) -> Value {
+    let config = server.config.read().unwrap().clone();
+    let sessions = server.sessions.lock().unwrap();
+    let mut clients = client_listing(&config);
+    for client in &mut clients {
+        let live = client.get("name").and_then(Value::as_str)
+            .and_then(|name| sessions.get(name))
+            .map_or(0, |list| list.iter().filter_map(|s| s.upgrade()).filter(|s| !s.tx.is_closed()).count());
+        let filtered = client.get("name").and_then(Value::as_str)
+            .and_then(|name| stats.clients.get(name))
+            .map_or(0, |totals| stats::load(&totals.filtered_by_acl));
+        if let Some(fields) = client.as_object_mut() {
+            fields.insert("sessions".to_string(), live.into());
+            fields.insert("filtered_by_acl".to_string(), filtered.into());
+        }
+    }
+    Value::Array(clients)
+}
+
+// What the prompt's `stats` prints
+fn stats_json(stats: &Stats) -> Value {
+    let sessions = &stats.sessions;
+    let drops: serde_json::Map<String, Value> = stats.drops.snapshot().into_iter()
+        .map(|(reason, count)| (reason.name().to_string(), count.into()))
+        .collect();
+    json!({
+        "uptime_secs": stats.started.elapsed().as_secs(),
+        "sessions_closed": {
+            "task_ended": stats::load(&sessions.task_ended),
+            "idle_timeout": stats::load(&sessions.idle_timeout),
+            "max_lifetime": stats::load(&sessions.max_lifetime),
+            "first_packet_timeout": stats::load(&sessions.first_packet_timeout),
+            "unresponsive": stats::load(&sessions.unresponsive),
+        },
+        "fragments_forwarded": stats::load(&stats.fragments.fragments),
+        "icmp_unreachable": {
+            "sent": stats::load(&stats.icmp.unreachable_sent),
+            "rate_limited": stats::load(&stats.icmp.unreachable_rate_limited),
+        },
+        "icmp_too_big": {
+            "sent": stats::load(&stats.icmp.too_big_sent),
+            "rate_limited": stats::load(&stats.icmp.too_big_rate_limited),
+        },
+        "throughput": {
+            "limit_bytes_per_sec": stats.throughput.limit_bytes_per_sec,
+            "last_second_bytes": stats::load(&stats.throughput.last_second_bytes),
+        },
+        "drops": drops,
+        // null without --protocol-stats
+        "traffic": stats.traffic.enabled().then(|| stats.traffic.snapshot(&stats.clients)),
+    })
+}
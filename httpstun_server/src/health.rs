@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use httpstun_core::{ClientRegistry, SharedConfig};
+use httpstun_core::stats::Stats;
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    uptime_secs: u64,
+    connected_clients: usize,
+    // --max-clients, absent without a limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_clients: Option<usize>,
+    tun_up: bool,
+}
+
+// Liveness probe for load balancers, for --health-check. Answers without authentication, with
+// 503 while the TUN device isn't up or --max-clients are connected, so the balancer stops
+// sending clients here.
+#[get("/healthz")]
+async fn health_service(registry: web::Data<ClientRegistry>, stats: web::Data<Arc<Stats>>, config: web::Data<SharedConfig>) -> HttpResponse {
+    let tun_up = stats.tun.up.load(Ordering::Relaxed);
+    let connected_clients = registry.read().await.len();
+    let max_clients = Some(config.read().unwrap().server_args.max_clients).filter(|max| *max > 0);
+    let full = max_clients.is_some_and(|max| connected_clients >= max);
+    let health = Health {
+        status: if !tun_up { "unavailable" } else if full { "full" } else { "ok" },
+        uptime_secs: stats.started.elapsed().as_secs(),
+        connected_clients,
+        max_clients,
+        tun_up,
+    };
+    if tun_up && !full {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
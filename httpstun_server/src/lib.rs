@@ -0,0 +1,1109 @@
+use std::{collections::HashMap, net::IpAddr, sync::OnceLock, time::Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use nix::sys::signal::{SigHandler, SigSet, Signal};
+use ipnet::IpNet;
+
+use actix_web::{http::KeepAlive, web::Data, App, HttpServer};
+use actix_ws::CloseCode;
+use clap::Parser;
+use async_channel::{bounded, Sender, Receiver};
+use httpstun_core::{
+    accounting, control, decoy, fw, logging, ratelimit, stats, syslog, systemd, tun, unauthenticated, ws,
+    allocate_ip, check_config, check_external_interfaces, compute_decoy_hash, hash_password, is_valid_ip, load_pepper,
+    lock_config, override_config_with_args, parse_config, remove_firewall, install_firewall, validate_client,
+    validate_server_ip, write_config, Args, Client, ClientRegistry, Config, ConfigError, SessionIndex, SharedConfig,
+    SighupPolicy, WsToTunPacket,
+};
+mod metrics;
+mod health;
+mod admin;
+mod admin_socket;
+mod privileges;
+
+// The server's command line: the settings of Args, which a config file may also hold, and
+// the client management commands
+#[derive(Parser, Debug, Clone)]
+struct Cli {
+    #[command(flatten)]
+    args: Args,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// Client management without the interactive prompt, for scripts and provisioning. Each edits
+// the config file and exits; a running server picks the change up on SIGHUP.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Add a client to the config file
+    AddClient {
+        #[clap(long)]
+        name: String,
+        /// Address to assign; the next free one in the IP pool if omitted
+        #[clap(long)]
+        ip: Option<IpAddr>,
+        #[command(flatten)]
+        password: PasswordSource,
+    },
+    /// Remove a client from the config file
+    RemoveClient {
+        #[clap(long)]
+        name: String,
+    },
+    /// Print the clients in the config file, without their password hashes
+    ListClients {
+        /// Print a JSON array instead of one line per client
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Where add-client takes the password from; never the command line, which other users can see
+#[derive(clap::Args, Debug, Clone)]
+#[group(required = true, multiple = false)]
+pub struct PasswordSource {
+    /// Read the password from the first line of stdin
+    #[clap(long)]
+    password_stdin: bool,
+    /// Read the password from this environment variable
+    #[clap(long, value_name = "VAR")]
+    password_env: Option<String>,
+}
+
+impl PasswordSource {
+    fn read(&self) -> Result<String, String> {
+        let password = match &self.password_env {
+            Some(var) => std::env::var(var).map_err(|e| format!("Unable to read password from ${}: {}", var, e))?,
+            None => {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).map_err(|e| format!("Unable to read password from stdin: {}", e))?;
+                line
+            }
+        };
+        let password = password.trim_end_matches(['\r', '\n']).to_string();
+        if password.len() < MIN_PASSWORD_LENGTH {
+            return Err(format!("Password must be at least {} characters long.", MIN_PASSWORD_LENGTH));
+        }
+        Ok(password)
+    }
+}
+
+// The config file's clients as JSON objects, without their password hashes
+pub fn client_listing(config: &Config) -> Vec<serde_json::Value> {
+    config.clients().iter()
+        .filter_map(|c| serde_json::to_value(c).ok())
+        .map(|mut c| {
+            if let Some(fields) = c.as_object_mut() {
+                fields.remove("token");
+            }
+            c
+        })
+        .collect()
+}
+
+pub fn run_command(command: &Command, args: &Args) -> Result<(), String> {
+    let config_file = &args.config_file;
+    match command {
+        Command::AddClient { name, ip, password } => {
+            let password = password.read()?;
+            let ip = add_client(name, &password, *ip, args)?;
+            println!("Client {} added with IP {}.", name, ip);
+        }
+        Command::RemoveClient { name } => {
+            remove_client(name, config_file)?;
+            println!("Client {} removed.", name);
+        }
+        Command::ListClients { json } => {
+            let config = parse_config(config_file).map_err(|e| e.to_string())?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&client_listing(&config)).map_err(|e| format!("Failed to serialize clients: {}", e))?);
+            } else {
+                for client in config.clients() {
+                    println!("{} {}", client.name, client.ip);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The config file to add a client to; a missing file starts out empty with the given
+// arguments as its server settings, but one that can't be read or parsed is left alone rather
+// than overwritten
+fn config_for_edit(args: &Args) -> Result<Config, String> {
+    match parse_config(&args.config_file) {
+        Ok(config) => Ok(config),
+        Err(ConfigError::NotFound(_)) => Ok(Config::new(args.clone())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Listen on every address `address` resolves to, set up as HttpServer::bind would
+async fn bind_listeners(address: &str, backlog: u32) -> std::io::Result<Vec<std::net::TcpListener>> {
+    let mut listeners = Vec::new();
+    for addr in tokio::net::lookup_host(address).await? {
+        let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        listeners.push(socket.listen(backlog)?.into_std()?);
+    }
+    Ok(listeners)
+}
+
+// Set by the first restart so concurrent requests (SIGHUP, interactive commands) don't race it
+static RESTARTING: AtomicBool = AtomicBool::new(false);
+
+// Set once a graceful shutdown starts, so the HTTP server stopping isn't taken for a failure
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Handle of the running HTTP server, for stopping it on shutdown
+static HTTP_SERVER: OnceLock<actix_web::dev::ServerHandle> = OnceLock::new();
+
+// The command line the server was started with, which a restart runs again
+static STARTUP_ARGS: OnceLock<Vec<std::ffi::OsString>> = OnceLock::new();
+
+// The command line the restarted process runs with: the original arguments, behind the
+// resolved binary path
+fn restart_argv() -> Result<Vec<std::ffi::CString>, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Unable to locate the server binary: {}", e))?;
+    let exe = std::ffi::CString::new(exe.as_os_str().as_encoded_bytes())
+        .map_err(|e| format!("Invalid server binary path: {}", e))?;
+    let startup = STARTUP_ARGS.get_or_init(|| std::env::args_os().collect());
+    let mut argv = vec![exe];
+    for arg in startup.iter().skip(1) {
+        argv.push(std::ffi::CString::new(arg.as_encoded_bytes())
+            .map_err(|e| format!("Invalid server argument {:?}: {}", arg, e))?);
+    }
+    Ok(argv)
+}
+
+// Validate the config the restarted process would start with, so a broken edit leaves the
+// running server alone instead of replacing it with one that can't start
+fn check_restart() -> Result<Vec<std::ffi::CString>, String> {
+    let argv = restart_argv()?;
+    let args = Cli::try_parse_from(argv.iter().map(|arg| arg.to_string_lossy().into_owned()))
+        .map_err(|e| format!("Invalid restart arguments: {}", e))?.args;
+    let config = check_config(&args)?;
+    check_external_interfaces(&config)?;
+    Ok(argv)
+}
+
+pub fn restart_server(config: &Config) {
+    if RESTARTING.swap(true, Ordering::SeqCst) {
+        println!("Restart already in progress, ignoring request.");
+        return;
+    }
+    // the restarted process couldn't set up the tunnel again
+    if privileges::dropped() {
+        error!("Refusing to restart after dropping privileges (--run-as-user); restart the service instead");
+        RESTARTING.store(false, Ordering::SeqCst);
+        return;
+    }
+    let argv = match check_restart() {
+        Ok(argv) => argv,
+        Err(e) => {
+            error!("Refusing to restart, the server keeps running with its current config: {}", e);
+            RESTARTING.store(false, Ordering::SeqCst);
+            return;
+        }
+    };
+    systemd::reloading();
+    cleanup(config);
+    // The signal mask survives exec, so a SIGHUP arriving before the new process has installed
+    // its handler stays pending instead of killing it. setup_signal_handlers unblocks it again.
+    let mut sighup = SigSet::empty();
+    sighup.add(Signal::SIGHUP);
+    if let Err(e) = sighup.thread_block() {
+        eprintln!("Failed to block SIGHUP across restart: {}", e);
+    }
+    // call exec to restart the server
+    let Err(e) = nix::unistd::execv(&argv[0], &argv);
+    panic!("Failed to restart the server: {}", e);
+}
+
+// Add a client to the config file, allocating it the next free pool address unless one is
+// given. Returns the client's address.
+pub fn add_client(name: &str, password: &str, ip: Option<IpAddr>, args: &Args) -> Result<IpAddr, String> {
+    let config_file_path = &args.config_file;
+    let _lock = lock_config(config_file_path)?;
+    let mut config = config_for_edit(args)?;
+    if config.clients().iter().any(|c| c.name == name) {
+        return Err(format!("Client {} already exists.", name));
+    }
+    let ip = match ip {
+        Some(ip) => ip,
+        None => allocate_ip(args, config.clients())?,
+    };
+    if let Some(holder) = config.clients().iter().find(|c| c.ip == ip) {
+        return Err(format!("IP {} is already assigned to client {}.", ip, holder.name));
+    }
+    let password_hash = hash_password(password);
+    let new_client = Client {
+        name: name.to_string(),
+        token: password_hash,
+        ip,
+        allowed_ips: vec![],
+        allowed_destinations: vec![],
+        mtu: None,
+        max_sessions: None,
+        rate_limit_bps: None,
+        special_sources: vec![],
+    };
+    config.clients_mut().push(new_client);
+    write_config(config_file_path, &config)?;
+    Ok(ip)
+}
+
+pub fn remove_client(name: &str, config_file_path: &str) -> Result<(), String> {
+    let _lock = lock_config(config_file_path)?;
+    // a missing file has no clients to remove
+    let mut config = match parse_config(config_file_path) {
+        Ok(config) => config,
+        Err(ConfigError::NotFound(_)) => return Err(format!("Client {} does not exist.", name)),
+        Err(e) => return Err(e.to_string()),
+    };
+    if  !config.clients().iter().any(|client| client.name == name) {
+        return Err(format!("Client {} does not exist.", name));
+    }
+    config.clients_mut().retain(|client| client.name != name);
+    write_config(config_file_path, &config)
+}
+
+// Remove a client from the running server before its config entry: the live entry goes
+// first so it can't log in again and packets for it stop being routed, then its sessions are
+// closed and unregistered, and only then is the file rewritten. The entry is put back if
+// that fails, so the server keeps agreeing with the file. Returns how many sessions closed.
+pub async fn remove_live_client(server: &ServerHandles, name: &str) -> Result<usize, String> {
+    let removed = {
+        let mut config = server.config.write().unwrap();
+        config.clients().iter().position(|c| c.name == name).map(|i| config.clients_mut().remove(i))
+    };
+    let code = CloseCode::Other(control::CLOSE_CLIENT_REMOVED);
+    let closed = ws::disconnect_client(&server.registry, &server.sessions, &server.accounting, name, code, "removed from config").await;
+    let (config_file, tun_if_name) = {
+        let config = server.config.read().unwrap();
+        (config.server_args.config_file.clone(), config.server_args.tun_interface_name.clone())
+    };
+    let owned = name.to_string();
+    let written = tokio::task::spawn_blocking(move || remove_client(&owned, &config_file)).await
+        .unwrap_or_else(|e| Err(format!("Failed to remove client {}: {}", name, e)));
+    if let Err(e) = written {
+        if let Some(client) = removed {
+            server.config.write().unwrap().clients_mut().push(client);
+        }
+        return Err(e);
+    }
+    // a reload no longer sees the entry, so its routes are left to here
+    if let Some(client) = removed
+        && !client.allowed_ips.is_empty()
+        && let Err(e) = fw::unroute_from_tun(&tun_if_name, &client.allowed_ips) {
+        log::warn!("Failed to remove routes of client {}: {}", name, e);
+    }
+    Ok(closed)
+}
+
+// Reload so the running server picks up a client change written to the config file
+fn reload_after_change(result: Result<String, String>, server: &ServerHandles) {
+    match result {
+        Ok(done) => {
+            println!("{}", done);
+            // the prompt runs on the runtime, which block_on alone would refuse
+            match tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(reload_config(server))) {
+                Ok(summary) => println!("Reloaded: {}", summary),
+                Err(e) => println!("Failed to reload, restart to apply the change: {}", e),
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+// Exercise add_client, validate_client and remove_client against a throwaway config file,
+// without restarting or touching the network. Returns the first failed check.
+pub fn self_test() -> Result<(), String> {
+    let path = std::env::temp_dir().join(format!("httpstun_self_test_{}.toml", std::process::id()));
+    let path_str = path.to_str().ok_or("temporary path is not valid UTF-8")?.to_string();
+    let args = Args { config_file: path_str, ..Args::default() };
+    let result = run_self_test(&args);
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+fn run_self_test(args: &Args) -> Result<(), String> {
+    let path = args.config_file.as_str();
+    let check = |ok: bool, what: &str| {
+        println!("{} {}", if ok { "PASS" } else { "FAIL" }, what);
+        if ok { Ok(()) } else { Err(what.to_string()) }
+    };
+    let (name, password, ip): (&str, &str, IpAddr) = ("self-test", "self-test-password", "10.10.10.2".parse().unwrap());
+
+    check(add_client(name, password, Some(ip), args).is_ok(), "add_client writes the config")?;
+    check(add_client(name, password, None, args).is_err(), "adding a client twice fails")?;
+    check(add_client("self-test-2", password, Some(ip), args).is_err(), "adding a client with a taken IP fails")?;
+    let allocated = add_client("self-test-2", password, None, args);
+    check(allocated == Ok("10.10.10.3".parse().unwrap()), "add_client allocates the next free pool address")?;
+    check(remove_client("self-test-2", path).is_ok(), "remove_client removes the allocated client")?;
+    let config = parse_config(path).map_err(|e| format!("config written by add_client can't be parsed: {}", e))?;
+    check(config.clients().iter().any(|c| c.name == name && c.ip == ip), "added client is in the config")?;
+    check(config.validate().is_ok(), "config with the added client validates")?;
+    check(is_valid_ip(&ip, &config), "added client's IP is accepted")?;
+    check(validate_client(name, password, &config).is_ok(), "added client authenticates")?;
+    check(validate_client(name, "wrong-password", &config).is_err(), "wrong password is rejected")?;
+
+    check(remove_client(name, path).is_ok(), "remove_client writes the config")?;
+    let config = parse_config(path).map_err(|e| format!("config written by remove_client can't be parsed: {}", e))?;
+    check(!config.clients().iter().any(|c| c.name == name), "removed client is gone from the config")?;
+    check(!is_valid_ip(&ip, &config), "removed client's IP is no longer accepted")?;
+    check(validate_client(name, password, &config).is_err(), "removed client no longer authenticates")?;
+    check(remove_client(name, path).is_err(), "removing a missing client fails")
+}
+
+// e.g. 1h02m03s, for how long a session has been up
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+pub fn print_stats(stats: &stats::Stats) {
+    let sessions = &stats.sessions;
+    println!(
+        "Sessions closed: task ended {}, idle {}, max lifetime {}, no first packet {}, unresponsive {}",
+        stats::load(&sessions.task_ended),
+        stats::load(&sessions.idle_timeout),
+        stats::load(&sessions.max_lifetime),
+        stats::load(&sessions.first_packet_timeout),
+        stats::load(&sessions.unresponsive),
+    );
+    println!("Fragments forwarded: {}", stats::load(&stats.fragments.fragments));
+    println!(
+        "Special sources allowed: dhcp {}, link-local {}",
+        stats::load(&stats.special_sources.dhcp),
+        stats::load(&stats.special_sources.link_local),
+    );
+    println!(
+        "Flow records exported: {} ({} flows evicted from a full table, {} records dropped on a full queue, {} lost to send errors)",
+        stats::load(&stats.flows.exported),
+        stats::load(&stats.flows.evicted),
+        stats::load(&stats.flows.queue_dropped),
+        stats::load(&stats.flows.send_failed),
+    );
+    println!(
+        "ICMP unreachable sent: {} ({} suppressed by rate limit)",
+        stats::load(&stats.icmp.unreachable_sent),
+        stats::load(&stats.icmp.unreachable_rate_limited),
+    );
+    println!(
+        "ICMP too big sent: {} ({} suppressed by rate limit)",
+        stats::load(&stats.icmp.too_big_sent),
+        stats::load(&stats.icmp.too_big_rate_limited),
+    );
+    let throughput = &stats.throughput;
+    if throughput.limit_bytes_per_sec > 0 {
+        let used = stats::load(&throughput.last_second_bytes);
+        println!(
+            "Throughput: {} kbit/s of {} kbit/s cap ({}%)",
+            used * 8 / 1000,
+            throughput.limit_bytes_per_sec * 8 / 1000,
+            used * 100 / throughput.limit_bytes_per_sec,
+        );
+    }
+    let drops: Vec<String> = stats.drops.snapshot().into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(reason, count)| format!("{} {}", reason.name(), count))
+        .collect();
+    if drops.is_empty() {
+        println!("Packets dropped: none");
+    } else {
+        println!("Packets dropped: {}", drops.join(", "));
+    }
+    if !stats.traffic.enabled() {
+        println!("Protocol accounting is disabled (start with --protocol-stats).");
+        return;
+    }
+    let snapshot = stats.traffic.snapshot(&stats.clients);
+    let print_row = |label: &str, traffic: &stats::DirectionalTraffic| {
+        for (direction, breakdown) in [("from client", &traffic.from_client), ("to client", &traffic.to_client)] {
+            println!(
+                "{} {}: tcp {}/{}B, udp {}/{}B, icmp {}/{}B, other {}/{}B",
+                label, direction,
+                breakdown.tcp.packets, breakdown.tcp.bytes,
+                breakdown.udp.packets, breakdown.udp.bytes,
+                breakdown.icmp.packets, breakdown.icmp.bytes,
+                breakdown.other.packets, breakdown.other.bytes,
+            );
+        }
+    };
+    print_row("Total", &snapshot.total);
+    for (name, traffic) in &snapshot.clients {
+        print_row(&format!("Client {}", name), traffic);
+    }
+}
+
+pub fn prompt_command(server: &ServerHandles, stats: &stats::Stats) {
+    use std::io::{self, Write};
+    let _config = &server.config.read().unwrap().clone();
+    let (registry, sessions) = (&server.registry, &server.sessions);
+    print!("Enter command (add_client, remove_client, list_clients, stats, reload_firewall, migrate_clients, shutdown, restart): ");
+    io::stdout().flush().unwrap();
+    let mut command = String::new();
+    io::stdin().read_line(&mut command).unwrap();
+    let command = command.trim();
+    match command {
+        "add_client" => {
+            println!("Adding a new client...");
+            let mut name = String::new();
+            print!("Enter client name: ");
+            io::stdout().flush().unwrap();
+            io::stdin().read_line(&mut name).unwrap();
+            // the prompts only collect input; the client is added once, after the last of them
+            let password = loop {
+                print!("Enter client password: ");
+                io::stdout().flush().unwrap();
+                let password = rpassword::read_password().unwrap();
+                if password.len() >= MIN_PASSWORD_LENGTH {
+                    break password;
+                }
+                println!("Password must be at least {} characters long. Please try again.", MIN_PASSWORD_LENGTH);
+            };
+            let ip = loop {
+                print!("Enter client IP address (e.g., 10.10.10.2, 2001:db8::2), or nothing for the next free one: ");
+                io::stdout().flush().unwrap();
+                let mut ip = String::new();
+                io::stdin().read_line(&mut ip).unwrap();
+                let ip = ip.trim();
+                if ip.is_empty() {
+                    break None;
+                }
+                match ip.parse::<IpAddr>() {
+                    Ok(ip) => break Some(ip),
+                    Err(_) => println!("Invalid IP address format. Please try again."),
+                }
+            };
+            let added = add_client(name.trim(), password.trim(), ip, &_config.server_args);
+            reload_after_change(added.map(|ip| format!("Client {} added successfully with IP {}.", name.trim(), ip)), server);
+        }
+        "remove_client" => {
+            println!("Removing a client...");
+            let mut name = String::new();
+            print!("Enter client name: ");
+            io::stdout().flush().unwrap();
+            io::stdin().read_line(&mut name).unwrap();
+            let name = name.trim();
+            let removed = tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(remove_live_client(server, name)));
+            reload_after_change(removed.map(|closed| format!("Client {} removed successfully, {} session(s) closed.", name, closed)), server);
+        }
+        "list_clients" => {
+            println!("Listing clients...");
+            let sessions = sessions.lock().unwrap();
+            for client in _config.clients() {
+                let live: Vec<_> = sessions.get(&client.name)
+                    .map(|list| list.iter().filter_map(|s| s.upgrade()).filter(|s| !s.tx.is_closed()).collect())
+                    .unwrap_or_default();
+                let filtered = stats.clients.get(&client.name).map_or(0, |totals| stats::load(&totals.filtered_by_acl));
+                println!(
+                    "Client Name: {}, IP: {}, Sessions: {}/{}{}{}",
+                    client.name, client.ip, live.len(), client.session_limit(&_config.server_args),
+                    if live.is_empty() { ", not connected" } else { "" },
+                    if filtered > 0 { format!(", {} packets dropped by its destination allowlist", filtered) } else { String::new() },
+                );
+                for session in live {
+                    println!(
+                        "    connected for {}, {} bytes from client, {} bytes to client, {}/{} packets queued{}",
+                        format_elapsed(session.connected_at.elapsed()),
+                        stats::load(&session.traffic.bytes_from_client),
+                        stats::load(&session.traffic.bytes_to_client),
+                        session.tx.len(),
+                        _config.server_args.client_queue,
+                        match session.rate_limit_bps {
+                            0 => String::new(),
+                            bps => format!(", limited to {} bit/s", bps),
+                        },
+                    );
+                }
+            }
+        }
+        "stats" => {
+            print_stats(stats);
+        }
+        "reload_firewall" => {
+            match reload_firewall(_config) {
+                Ok(removed) => println!("Firewall reloaded: removed {} old rule(s) and installed the new rule.", removed),
+                Err(e) => println!("Failed to reload firewall: {}", e),
+            }
+        }
+        "migrate_clients" => {
+            let mut url = String::new();
+            print!("Enter new server URL (e.g. wss://vpn2.example.com/): ");
+            io::stdout().flush().unwrap();
+            io::stdin().read_line(&mut url).unwrap();
+            let url = url.trim();
+            if ["ws://", "wss://", "http://", "https://"].iter().any(|scheme| url.starts_with(scheme)) {
+                println!("Redirecting connected clients to {}...", url);
+                tokio::spawn(ws::redirect_clients(registry.clone(), url.to_string()));
+            } else {
+                println!("Invalid URL: {}", url);
+            }
+        }
+        "shutdown" => {
+            println!("Shutting down the server...");
+            tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(shutdown(server)));
+            cleanup(_config);
+            std::process::exit(0);
+        }
+        "restart" => {
+            println!("Restarting the server...");
+            restart_server(_config);
+        }
+        _ => {
+            println!("Unknown command: {}", command);
+            println!("Available commands: add_client, remove_client, list_clients, stats, reload_firewall, migrate_clients, shutdown, restart");
+        }
+    }
+}
+
+pub fn cleanup(config : &Config) {
+    if let Some(path) = &config.server_args.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    // no longer allowed to; the cleanup helper does it once this process exits
+    if privileges::dropped() {
+        return;
+    }
+    // removed by comment so rules installed by reload_firewall are caught too
+    match remove_firewall(&config.server_args.tun_interface_name, config) {
+        Ok(n) => println!("Removed {} firewall rule(s).", n),
+        Err(e) => eprintln!("Failed to remove firewall rules: {}", e),
+    }
+    if !config.egress.is_empty() {
+        match fw::remove_egress_routing() {
+            Ok(n) => println!("Removed {} egress routing rule(s).", n),
+            Err(e) => eprintln!("Failed to remove egress routing rules: {}", e),
+        }
+    }
+}
+
+// --check: what would keep the server from starting on this host, found without binding a
+// port or creating the TUN device. Every check runs, so one run reports every problem.
+// Returns whether all of them passed.
+pub fn check_deployment(config: &Config) -> bool {
+    let args = &config.server_args;
+    let mut ok = true;
+    let mut report = |what: &str, result: Result<String, String>| match result {
+        Ok(detail) => println!("ok      {}: {}", what, detail),
+        Err(e) => {
+            println!("FAILED  {}: {}", what, e);
+            ok = false;
+        }
+    };
+    report("config", config.validate().map(|()| format!("{}, {} client(s)", args.config_file, config.clients().len())));
+    report("server address", validate_server_ip(args).and_then(|()| args.subnet()).map(|net| net.to_string()));
+    let interfaces = match config.egress.is_empty() {
+        true => args.external_interface_name.clone(),
+        false => config.egress.iter().map(|uplink| uplink.interface.as_str()).collect::<Vec<_>>().join(", "),
+    };
+    report("NAT interfaces", check_external_interfaces(config).map(|()| interfaces));
+    report("firewall", fw::FirewallBackend::select(args.firewall_backend).and_then(|backend| {
+        // the uplinks' policy routing is set up with ip whichever backend NATs
+        if !config.egress.is_empty() && !fw::binary_exists("ip") {
+            return Err("ip is not installed, but multiple egress interfaces need it".to_string());
+        }
+        let families = fw::nat_families(config);
+        if backend == fw::FirewallBackend::Iptables
+            && let Some(family) = families.iter().find(|family| !fw::binary_exists(family.iptables())) {
+            return Err(format!("{} is not installed, but the tunnel carries {}", family.iptables(), family.name()));
+        }
+        let families: Vec<_> = families.iter().map(|family| family.name()).collect();
+        Ok(format!("{} found, NATing {}", backend.binary(), families.join(" and ")))
+    }));
+    ok
+}
+
+// Maintenance mode: remove every httpstun-tagged NAT rule and optionally leftover TUN
+// devices, reporting what was removed. Returns false if anything failed.
+pub fn cleanup_orphans(interface_pattern: Option<&str>) -> bool {
+    let mut ok = true;
+    for (binary, result) in fw::remove_all_httpstun_rules() {
+        match result {
+            Ok(rules) if rules.is_empty() => println!("{}: no httpstun rules found", binary),
+            Ok(rules) => {
+                for rule in rules {
+                    println!("{}: removed {}", binary, rule);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", binary, e);
+                ok = false;
+            }
+        }
+    }
+    match fw::remove_egress_routing() {
+        Ok(0) => {}
+        Ok(n) => println!("ip: removed {} egress routing rule(s)", n),
+        Err(e) => {
+            eprintln!("ip: {}", e);
+            ok = false;
+        }
+    }
+    if let Some(pattern) = interface_pattern {
+        let interfaces = fw::find_tun_interfaces(pattern);
+        if interfaces.is_empty() {
+            println!("No TUN interfaces matching {}", pattern);
+        }
+        for if_name in interfaces {
+            match fw::delete_interface(&if_name) {
+                Ok(()) => println!("Deleted TUN interface {}", if_name),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    ok = false;
+                }
+            }
+        }
+    }
+    ok
+}
+
+// Re-apply the NAT rule using the firewall settings currently in the config file, leaving
+// client sessions untouched. Returns how many old rules were removed.
+pub fn reload_firewall(config: &Config) -> Result<usize, String> {
+    if privileges::dropped() {
+        return Err("the server dropped its privileges (--run-as-user); restart it to change the firewall".to_string());
+    }
+    let tun_if_name = &config.server_args.tun_interface_name;
+    // the running command line overrides the file, as it did at startup
+    let fresh = match parse_config(&config.server_args.config_file) {
+        Err(ConfigError::NotFound(_)) => config.clone(),
+        _ => check_config(&config.server_args)?,
+    };
+    check_external_interfaces(&fresh)?;
+    let removed = remove_firewall(tun_if_name, config)?;
+    if !config.egress.is_empty() {
+        fw::remove_egress_routing()?;
+    }
+    install_firewall(tun_if_name, &fresh)?;
+    Ok(removed)
+}
+
+// What the running server needs to apply a reload
+#[derive(Clone)]
+pub struct ServerHandles {
+    pub config: SharedConfig,
+    pub registry: ClientRegistry,
+    pub sessions: SessionIndex,
+    pub accounting: std::sync::Arc<accounting::Accounting>,
+}
+
+// Re-read the config file and apply its client list without touching unaffected sessions.
+// Clients that were removed, or whose IP or password changed, are disconnected; the others
+// keep their tunnel and see any other change to their entry (allowlist, session limit) at
+// once. Server settings are left alone, they need a restart. Returns a summary of the changes.
+pub async fn reload_config(server: &ServerHandles) -> Result<String, String> {
+    let current = server.config.read().unwrap().clone();
+    let fresh = check_config(&current.server_args)?;
+    let mut added = 0;
+    let mut disconnect = Vec::new();
+    for client in fresh.clients() {
+        match current.clients().iter().find(|c| c.name == client.name) {
+            None => added += 1,
+            // it can reconnect right away at its new address
+            Some(old) if old.ip != client.ip => disconnect.push((client.name.clone(), CloseCode::Policy, "address changed")),
+            Some(old) if old.token != client.token => disconnect.push((client.name.clone(), CloseCode::Other(control::CLOSE_UNAUTHORIZED), "credentials changed")),
+            Some(_) => {}
+        }
+    }
+    let changed = disconnect.len();
+    for client in current.clients().iter().filter(|c| !fresh.clients().iter().any(|f| f.name == c.name)) {
+        disconnect.push((client.name.clone(), CloseCode::Other(control::CLOSE_CLIENT_REMOVED), "removed from config"));
+    }
+    let removed = disconnect.len() - changed;
+    let tun_if_name = &current.server_args.tun_interface_name;
+    let (old_networks, new_networks) = (current.client_networks(), fresh.client_networks());
+    let gone: Vec<IpNet> = old_networks.iter().filter(|net| !new_networks.contains(net)).copied().collect();
+    fw::route_to_tun(tun_if_name, &new_networks)?;
+    if let Err(e) = fw::unroute_from_tun(tun_if_name, &gone) {
+        log::warn!("Failed to remove routes of networks no longer in the config: {}", e);
+    }
+    // swap first, so the disconnected clients reconnect against the new entries
+    *server.config.write().unwrap().clients_mut() = fresh.clients().to_vec();
+    let mut closed = 0;
+    for (name, code, reason) in &disconnect {
+        closed += ws::disconnect_client(&server.registry, &server.sessions, &server.accounting, name, *code, reason).await;
+    }
+    Ok(format!("{} client(s) added, {} removed, {} with new address or password; {} session(s) closed", added, removed, changed, closed))
+}
+
+// Stop taking connections, close every session with a close frame so clients know to
+// reconnect, and give them up to --shutdown-grace to finish before the HTTP server goes away
+pub async fn shutdown(server: &ServerHandles) {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    systemd::stopping();
+    let grace = Duration::from_secs(server.config.read().unwrap().server_args.shutdown_grace);
+    let http = HTTP_SERVER.get();
+    if let Some(http) = http {
+        http.pause().await;
+    }
+    let closed = ws::close_all_sessions(&server.registry, &server.sessions, &server.accounting, "server shutting down").await;
+    info!("Closed {} session(s), waiting up to {:?} for them to finish", closed, grace);
+    if let Some(http) = http
+        && tokio::time::timeout(grace, http.stop(true)).await.is_err() {
+        info!("Grace period over, dropping the remaining connections");
+    }
+}
+
+pub fn setup_signal_handlers(server: &ServerHandles) {
+    let mut sighup = SigSet::empty();
+    sighup.add(Signal::SIGHUP);
+    let config = server.config.read().unwrap().clone();
+    if config.server_args.sighup_policy == SighupPolicy::Ignore {
+        // discards a SIGHUP left pending by the restart that started this process
+        // SAFETY: SIG_IGN runs no code in signal context
+        if let Err(e) = unsafe { nix::sys::signal::signal(Signal::SIGHUP, SigHandler::SigIgn) } {
+            eprintln!("Failed to discard pending SIGHUP: {}", e);
+        }
+    }
+    let mut signals = signal_hook::iterator::Signals::new([
+        signal_hook::consts::SIGINT,
+        signal_hook::consts::SIGTERM,
+        signal_hook::consts::SIGHUP,
+    ]).expect("Failed to set up signal handlers");
+    let server = server.clone();
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                signal_hook::consts::SIGINT | signal_hook::consts::SIGTERM if SHUTTING_DOWN.load(Ordering::SeqCst) => {
+                    println!("Received another termination signal. Exiting now...");
+                    cleanup(&config);
+                    std::process::exit(0);
+                }
+                signal_hook::consts::SIGINT | signal_hook::consts::SIGTERM => {
+                    println!("Received termination signal. Shutting down...");
+                    // on a thread of its own so a second signal can cut the grace period short
+                    let (server, config, runtime) = (server.clone(), config.clone(), runtime.clone());
+                    std::thread::spawn(move || {
+                        runtime.block_on(shutdown(&server));
+                        cleanup(&config);
+                        std::process::exit(0);
+                    });
+                }
+                // the process about to be exec'd reads the config afresh anyway
+                signal_hook::consts::SIGHUP if RESTARTING.load(Ordering::SeqCst) => {
+                    println!("Received SIGHUP during restart, ignoring.");
+                }
+                signal_hook::consts::SIGHUP => {
+                    println!("Received SIGHUP. Reloading config...");
+                    match runtime.block_on(reload_config(&server)) {
+                        Ok(summary) => info!("Config reloaded: {}", summary),
+                        Err(e) => error!("Config reload failed, keeping the current config: {}", e),
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    });
+    // a SIGHUP that arrived during our own startup is delivered now, with the handler in place
+    if let Err(e) = sighup.thread_unblock() {
+        eprintln!("Failed to unblock SIGHUP: {}", e);
+    }
+}
+
+fn describe_exit(name: &str, res: Result<std::io::Result<()>, tokio::task::JoinError>) -> String {
+    match res {
+        Ok(Ok(())) => format!("{} stopped", name),
+        Ok(Err(e)) => format!("{} failed: {}", name, e),
+        Err(e) => format!("{} crashed: {}", name, e),
+    }
+}
+
+use log::{error, info};
+#[tokio::main]
+pub async fn run() -> std::io::Result<()> {
+    STARTUP_ARGS.get_or_init(|| std::env::args_os().collect());
+    let Cli { args, command } = Cli::parse();
+    let config = match parse_config(&args.config_file) {
+        Ok(cfg) => override_config_with_args(cfg, &args),
+        // first run: clients get added to a new file
+        Err(e @ ConfigError::NotFound(_)) => {
+            eprintln!("{}, using command line arguments only.", e);
+            Config::new(args.clone())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config.server_args.log_level, config.server_args.log_format);
+    if args.cleanup_helper {
+        privileges::run_cleanup_helper(&config);
+    }
+    if args.cleanup {
+        let ok = cleanup_orphans(args.cleanup_interfaces.as_deref());
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Err(e) = load_pepper(&config.server_args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(command) = &command {
+        if let Err(e) = run_command(command, &args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+    if args.self_test {
+        match self_test() {
+            Ok(()) => println!("Self-test passed."),
+            Err(e) => {
+                eprintln!("Self-test failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if args.check {
+        let ok = check_deployment(&config);
+        println!("{}", if ok { "Configuration check passed." } else { "Configuration check failed." });
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
+    // before anything is set up, rather than as a failure of the TUN handler once it is
+    if let Err(e) = check_external_interfaces(&config) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let run_as = match privileges::resolve(&config.server_args) {
+        Ok(run_as) => run_as,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = syslog::init(&config.server_args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = systemd::init(&config.server_args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    compute_decoy_hash();
+
+
+
+    let bind_addresses = config.server_args.bind_addresses();
+    let tls = match config.tls() {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let (wstx, wsrx): (Sender<WsToTunPacket>, Receiver<WsToTunPacket>) = bounded(config.server_args.tun_queue);
+    // Global client registry for routing TUN->WS traffic per client
+    let registry: ClientRegistry = std::sync::Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+    let registry_for_http = registry.clone();
+    let sessions: SessionIndex = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let sessions_for_http = sessions.clone();
+    let server_stats = std::sync::Arc::new(stats::Stats::new(config.server_args.protocol_stats, config.server_args.max_throughput_kbps * 1000 / 8));
+    let accounting = match accounting::Accounting::open(config.server_args.accounting_log.as_deref()) {
+        Ok(accounting) => std::sync::Arc::new(accounting),
+        Err(e) => {
+            eprintln!("Failed to open accounting log: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if accounting.enabled() && config.server_args.accounting_interval > 0 {
+        tokio::spawn(accounting::run_interim(registry.clone(), accounting.clone(), Duration::from_secs(config.server_args.accounting_interval)));
+    }
+    let accounting_for_http = accounting.clone();
+    let shared_config: SharedConfig = std::sync::Arc::new(std::sync::RwLock::new(config.clone()));
+    let server = ServerHandles {
+        config: shared_config.clone(),
+        registry: registry.clone(),
+        sessions: sessions.clone(),
+        accounting: accounting.clone(),
+    };
+    setup_signal_handlers(&server);
+    let unauthenticated = match unauthenticated::UnauthenticatedResponse::load(&config.server_args) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let decoy = match decoy::Decoy::load(&config.server_args) {
+        Ok(decoy) => decoy,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let keep_alive = match config.server_args.keep_alive {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    };
+    let backlog = config.server_args.backlog;
+    let client_request_timeout = Duration::from_secs(config.server_args.client_request_timeout);
+    let metrics = config.server_args.metrics;
+    let health_check = config.server_args.health_check;
+    let ws_path = config.server_args.ws_path.clone();
+    let shutdown_grace = config.server_args.shutdown_grace;
+    let auth_limiter = Data::new(ratelimit::AuthLimiter::new(
+        config.server_args.auth_max_failures,
+        Duration::from_secs(config.server_args.auth_failure_window),
+        Duration::from_secs(config.server_args.auth_ban),
+    ));
+    let stats_for_http = server_stats.clone();
+    let confclone = shared_config.clone();
+    let http_task = tokio::spawn(async move {
+        // an address that can't be bound is skipped, as long as another one can
+        let mut listeners = Vec::new();
+        for address in &bind_addresses {
+            match bind_listeners(address, backlog).await {
+                Ok(bound) if bound.is_empty() => log::warn!("Failed to listen on {}: it resolves to no address", address),
+                Ok(bound) => listeners.extend(bound),
+                Err(e) => log::warn!("Failed to listen on {}: {}", address, e),
+            }
+        }
+        if listeners.is_empty() {
+            return Err(std::io::Error::other(format!("none of the listen addresses ({}) could be bound", bind_addresses.join(", "))));
+        }
+        // signals are handled by setup_signal_handlers, not actix
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(confclone.clone()))
+                .app_data(Data::new(wstx.clone()))
+                .app_data(Data::new(registry_for_http.clone()))
+                .app_data(Data::new(sessions_for_http.clone()))
+                .app_data(Data::new(accounting_for_http.clone()))
+                .app_data(Data::new(unauthenticated.clone()))
+                .app_data(Data::new(stats_for_http.clone()))
+                .app_data(auth_limiter.clone())
+                .configure(|cfg| ws::configure(cfg, &ws_path))
+                .configure(|cfg| {
+                    if let Some(decoy) = &decoy {
+                        cfg.app_data(Data::new(decoy.clone()));
+                        if matches!(decoy, decoy::Decoy::Dir(_)) {
+                            cfg.default_service(actix_web::web::to(decoy::decoy_service));
+                        }
+                    }
+                })
+                .configure(|cfg| {
+                    // off by default: it answers anyone, and names every client
+                    if metrics {
+                        cfg.service(metrics::metrics_service);
+                    }
+                    // also off by default, as it gives away that this is a tunnel server
+                    if health_check {
+                        cfg.service(health::health_service);
+                    }
+                })
+        })
+        .disable_signals()
+        .shutdown_timeout(shutdown_grace)
+        .backlog(backlog)
+        .keep_alive(keep_alive)
+        .client_request_timeout(client_request_timeout);
+        let server = {
+            let mut server = server;
+            for listener in listeners {
+                let local = listener.local_addr()?;
+                server = match &tls {
+                    Some(tls) => server.listen_rustls_0_23(listener, tls.clone())?,
+                    None => server.listen(listener)?,
+                };
+                println!("Starting server at {}://{}", scheme, local);
+            }
+            server.run()
+        };
+        let _ = HTTP_SERVER.set(server.handle());
+        systemd::component_ready(systemd::Component::Http);
+        server.await
+    });
+    tokio::spawn(systemd::run_watchdog());
+    if let Some(port) = config.server_args.admin_port
+        && let Some(token) = config.server_args.admin_token.clone() {
+        let address = format!("{}:{}", config.server_args.admin_host, port);
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::run_admin(server, address.clone(), token).await {
+                error!("Admin API on {} failed: {}", address, e);
+            }
+        });
+    }
+    if let Some(path) = &config.server_args.control_socket {
+        match admin_socket::bind(path) {
+            Ok(listener) => {
+                tokio::spawn(admin_socket::run(listener, server.clone(), server_stats.clone()));
+            }
+            Err(e) => {
+                eprintln!("Failed to listen on control socket {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    tokio::spawn(ws::sweep_sessions(
+        registry.clone(),
+        server_stats.clone(),
+        accounting.clone(),
+        Duration::from_secs(config.server_args.sweep_interval.max(1)),
+        ws::SessionLimits {
+            idle_timeout: Duration::from_secs(config.server_args.client_idle_timeout),
+            max_lifetime: Duration::from_secs(config.server_args.client_max_lifetime),
+            first_packet_timeout: Duration::from_secs(config.server_args.first_packet_timeout),
+            client_timeout: Duration::from_secs(config.server_args.client_timeout),
+        },
+    ));
+    let registry_for_tun = registry.clone();
+    let stats_for_tun = server_stats.clone();
+    let config_for_tun = shared_config.clone();
+    let tun_task = tokio::spawn(async move {
+        tun::run_tun(wsrx, registry_for_tun, stats_for_tun, config_for_tun).await
+    });
+    if let Some(run_as) = run_as {
+        let config = config.clone();
+        tokio::spawn(async move {
+            systemd::all_ready().await;
+            if let Err(e) = privileges::drop_to(&run_as) {
+                error!("{}, shutting down", e);
+                cleanup(&config);
+                std::process::exit(1);
+            }
+        });
+    }
+    // The HTTP server and TUN handler only work together; if either stops, shut down cleanly
+    let confclone = config.clone();
+    tokio::spawn(async move {
+        let stopped = tokio::select! {
+            res = http_task => describe_exit("HTTP server", res),
+            res = tun_task => describe_exit("TUN handler", res),
+        };
+        // the shutdown stopped the HTTP server itself and exits once it's done
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return;
+        }
+        error!("{}, shutting down", stopped);
+        systemd::stopping();
+        cleanup(&confclone);
+        std::process::exit(1);
+    });
+    // parse client commands, adding and deleting clients, shutdown, restart.
+    loop {
+        if config.server_args.interactive {
+            prompt_command(&server, &server_stats);
+        } else {
+            std::thread::sleep(std::time::Duration::from_secs(60));
+        }
+    }
+}
+
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use actix_web::{get, web, HttpResponse};
+
+use httpstun_core::{ClientRegistry, SharedConfig};
+use httpstun_core::stats::{load, DropReason, L4Protocol, Stats};
+
+// Prometheus text exposition of the server's counters, for --metrics. Per-client traffic is
+// labelled by client name and kept across reconnects; clients removed from the config keep
+// their last values until restart.
+#[get("/metrics")]
+async fn metrics_service(registry: web::Data<ClientRegistry>, stats: web::Data<Arc<Stats>>, config: web::Data<SharedConfig>) -> HttpResponse {
+    let max_clients = config.read().unwrap().server_args.max_clients;
+    let (connected, queued) = {
+        let map = registry.read().await;
+        // summed over a client's sessions
+        let mut queued: BTreeMap<String, usize> = BTreeMap::new();
+        for session in map.values() {
+            *queued.entry(session.name.clone()).or_default() += session.tx.len();
+        }
+        (map.len(), queued)
+    };
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(render(&stats, connected, max_clients, &queued))
+}
+
+fn render(stats: &Stats, connected: usize, max_clients: usize, queued: &BTreeMap<String, usize>) -> String {
+    let mut out = String::new();
+    let totals = stats.clients.snapshot();
+    header(&mut out, "httpstun_client_bytes_total", "counter", "Tunneled bytes per client and direction");
+    for (name, traffic) in &totals {
+        sample(&mut out, "httpstun_client_bytes_total", &[("client", name), ("direction", "from_client")], load(&traffic.bytes_from_client));
+        sample(&mut out, "httpstun_client_bytes_total", &[("client", name), ("direction", "to_client")], load(&traffic.bytes_to_client));
+    }
+    header(&mut out, "httpstun_client_packets_total", "counter", "Tunneled packets per client and direction");
+    for (name, traffic) in &totals {
+        sample(&mut out, "httpstun_client_packets_total", &[("client", name), ("direction", "from_client")], load(&traffic.packets_from_client));
+        sample(&mut out, "httpstun_client_packets_total", &[("client", name), ("direction", "to_client")], load(&traffic.packets_to_client));
+    }
+    header(&mut out, "httpstun_client_filtered_packets_total", "counter", "Packets per client dropped by its destination allowlist");
+    for (name, traffic) in &totals {
+        sample(&mut out, "httpstun_client_filtered_packets_total", &[("client", name)], load(&traffic.filtered_by_acl));
+    }
+    if stats.traffic.enabled() {
+        header(&mut out, "httpstun_client_protocol_bytes_total", "counter", "Tunneled bytes per client, L4 protocol and direction");
+        for (name, traffic) in &totals {
+            for protocol in L4Protocol::ALL {
+                sample(&mut out, "httpstun_client_protocol_bytes_total", &[("client", name), ("protocol", protocol.name()), ("direction", "from_client")], traffic.protocols.from_client.get(protocol).bytes);
+                sample(&mut out, "httpstun_client_protocol_bytes_total", &[("client", name), ("protocol", protocol.name()), ("direction", "to_client")], traffic.protocols.to_client.get(protocol).bytes);
+            }
+        }
+    }
+    header(&mut out, "httpstun_connected_clients", "gauge", "Clients with a registered session");
+    sample(&mut out, "httpstun_connected_clients", &[], connected as u64);
+    header(&mut out, "httpstun_max_clients", "gauge", "Clients allowed to connect at once, 0 for no limit");
+    sample(&mut out, "httpstun_max_clients", &[], max_clients as u64);
+    header(&mut out, "httpstun_client_queue_packets", "gauge", "Packets waiting in the send queues of connected clients");
+    for (name, packets) in queued {
+        sample(&mut out, "httpstun_client_queue_packets", &[("client", name)], *packets as u64);
+    }
+    header(&mut out, "httpstun_auth_failures_total", "counter", "Requests that failed authentication");
+    sample(&mut out, "httpstun_auth_failures_total", &[], load(&stats.auth.failures));
+    header(&mut out, "httpstun_auth_rate_limited_total", "counter", "Requests refused because their source failed to authenticate too often");
+    sample(&mut out, "httpstun_auth_rate_limited_total", &[], load(&stats.auth.rate_limited));
+    header(&mut out, "httpstun_tun_read_errors_total", "counter", "Failed reads from the TUN device");
+    sample(&mut out, "httpstun_tun_read_errors_total", &[], load(&stats.tun.read_errors));
+    header(&mut out, "httpstun_tun_write_errors_total", "counter", "Failed writes to the TUN device");
+    sample(&mut out, "httpstun_tun_write_errors_total", &[], load(&stats.tun.write_errors));
+    header(&mut out, "httpstun_dropped_packets_total", "counter", "Packets dropped by the data plane, by reason");
+    for reason in DropReason::ALL {
+        sample(&mut out, "httpstun_dropped_packets_total", &[("reason", reason.name())], stats.drops.get(reason));
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, escape(value))).collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+// Label values are quoted; client names come from the config and may contain anything
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,87 @@
+use std::io::Read;
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::OnceLock;
+
+use log::info;
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Group, Uid, User};
+
+use httpstun_core::{Args, Config};
+
+// Running as an unprivileged user once the TUN device is up, the NAT rule installed and the
+// listeners bound (--run-as-user, --run-as-group). No capability is kept: removing the NAT
+// rule on exit is left to a helper process started as root just before the drop, which does
+// nothing but wait for the server to exit. It finds out through a pipe the server holds the
+// write end of, so it cleans up however the server ends, SIGKILL included.
+
+// The helper's end of the pipe; never written to, closed by the server exiting
+static HELPER_PIPE: OnceLock<ChildStdin> = OnceLock::new();
+
+pub struct RunAs {
+    user: String,
+    uid: Uid,
+    gid: Gid,
+}
+
+// Look the user and group up at startup, so a typo fails before the tunnel comes up
+pub fn resolve(args: &Args) -> Result<Option<RunAs>, String> {
+    let Some(user) = &args.run_as_user else {
+        return Ok(None);
+    };
+    if !Uid::effective().is_root() {
+        return Err("--run-as-user needs the server to start as root".to_string());
+    }
+    let found = User::from_name(user)
+        .map_err(|e| format!("Failed to look up user {}: {}", user, e))?
+        .ok_or_else(|| format!("User {} does not exist", user))?;
+    let gid = match &args.run_as_group {
+        Some(group) => Group::from_name(group)
+            .map_err(|e| format!("Failed to look up group {}: {}", group, e))?
+            .ok_or_else(|| format!("Group {} does not exist", group))?
+            .gid,
+        None => found.gid,
+    };
+    if found.uid.is_root() {
+        return Err(format!("--run-as-user {} is root, which drops nothing", user));
+    }
+    Ok(Some(RunAs { user: user.clone(), uid: found.uid, gid }))
+}
+
+pub fn dropped() -> bool {
+    HELPER_PIPE.get().is_some()
+}
+
+// Start the cleanup helper, then give up root for good
+pub fn drop_to(run_as: &RunAs) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Unable to locate the server binary: {}", e))?;
+    let args = crate::STARTUP_ARGS.get().map(|args| &args[1..]).unwrap_or_default();
+    let helper = Command::new(exe)
+        .args(args)
+        .arg("--cleanup-helper")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start the cleanup helper: {}", e))?;
+    let _ = HELPER_PIPE.set(helper.stdin.expect("stdin is piped"));
+    setgroups(&[run_as.gid]).map_err(|e| format!("Failed to drop supplementary groups: {}", e))?;
+    setgid(run_as.gid).map_err(|e| format!("Failed to switch to group {}: {}", run_as.gid, e))?;
+    setuid(run_as.uid).map_err(|e| format!("Failed to switch to user {}: {}", run_as.user, e))?;
+    if setuid(Uid::from_raw(0)).is_ok() {
+        return Err("Still able to regain root after dropping privileges".to_string());
+    }
+    info!("Dropped privileges to user {} (uid {}, gid {})", run_as.user, run_as.uid, run_as.gid);
+    Ok(())
+}
+
+// The helper process: wait for the server to close the pipe by exiting, then remove its
+// firewall rules
+pub fn run_cleanup_helper(config: &Config) -> ! {
+    // the terminal's process group or the service's cgroup gets the server's signals too;
+    // the helper must outlive the server to clean up after it
+    for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+        // SAFETY: SIG_IGN runs no code in signal context
+        let _ = unsafe { signal(sig, SigHandler::SigIgn) };
+    }
+    let _ = std::io::stdin().read_to_end(&mut Vec::new());
+    crate::cleanup(config);
+    std::process::exit(0);
+}
@@ -0,0 +1,440 @@
+// End-to-end runs of the server and the client over loopback. Neither end has a TUN device:
+// both run their data planes over in-memory devices, so this needs no privileges and works
+// where tun interfaces can't be created.
+//
+//     cargo test -p httpstun_server --test tunnel
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use actix_web::{rt, web::Data, App, HttpServer};
+use async_channel::{unbounded, Receiver, Sender};
+use bytes::Bytes;
+use clap::Parser;
+use futures_util::StreamExt;
+use httpstun_core::accounting::Accounting;
+use httpstun_core::device::TunDevice;
+use httpstun_core::pool::PacketPool;
+use httpstun_core::stats::{load, DropReason, Stats};
+use httpstun_core::{ClientRegistry, Config, SessionIndex, SharedConfig, WsToTunPacket};
+use reqwest_websocket::{CloseCode, Message, RequestBuilderExt};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const CLIENT_NAME: &str = "client1";
+const CLIENT_PASSWORD: &str = "hunter22";
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 10, 10, 1);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 10, 10, 2);
+// configured with CLIENT_IP too by the address conflict tests
+const OTHER_CLIENT_NAME: &str = "client2";
+const OTHER_CLIENT_PASSWORD: &str = "correct horse";
+const TIMEOUT: Duration = Duration::from_secs(10);
+// two 32-byte keys for --psk
+const PSK: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+const OTHER_PSK: &str = "ICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj8=";
+
+// Server TUN device: packets the test injects come out of `recv_batch`, packets the server
+// writes go to `written`
+struct ServerTun {
+    injected: Receiver<Vec<u8>>,
+    written: Sender<Vec<u8>>,
+}
+
+impl TunDevice for ServerTun {
+    async fn send(&self, packet: Bytes) -> io::Result<usize> {
+        self.written.send(packet.to_vec()).await.map_err(io::Error::other)?;
+        Ok(packet.len())
+    }
+
+    async fn recv_batch(&self, pool: &mut PacketPool, packets: &mut Vec<Bytes>) -> io::Result<()> {
+        let packet = self.injected.recv().await.map_err(io::Error::other)?;
+        packets.push(pool.copy(&packet));
+        Ok(())
+    }
+}
+
+struct ClientTun {
+    injected: Receiver<Vec<u8>>,
+    written: Sender<Vec<u8>>,
+}
+
+impl httpstun_client::tun::TunDevice for ClientTun {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self.injected.recv().await.map_err(io::Error::other)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.written.send(buf.to_vec()).await.map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+}
+
+// The test's ends of both devices, with a client connected to the server
+struct Tunnel {
+    server_inject: Sender<Vec<u8>>,
+    server_written: Receiver<Vec<u8>>,
+    client_inject: Sender<Vec<u8>>,
+    client_written: Receiver<Vec<u8>>,
+    stats: Arc<Stats>,
+    registry: ClientRegistry,
+    sessions: SessionIndex,
+    accounting: Arc<Accounting>,
+    port: u16,
+    // ends when the client stops reconnecting
+    client: rt::task::JoinHandle<()>,
+}
+
+fn udp_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let builder = etherparse::PacketBuilder::ipv4(src.octets(), dst.octets(), 64).udp(40000, 9);
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+fn merge(into: &mut Value, fields: Value) {
+    if let (Some(into), Value::Object(fields)) = (into.as_object_mut(), fields) {
+        into.extend(fields);
+    }
+}
+
+async fn recv(rx: &Receiver<Vec<u8>>) -> Vec<u8> {
+    tokio::time::timeout(TIMEOUT, rx.recv()).await.expect("timed out waiting for a packet").unwrap()
+}
+
+// Status of a plain request to the tunnel endpoint logging in as `name` with a wrong password
+async fn status_logging_in_as(port: u16, name: &str) -> u16 {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Httpstun-Client-Name: {name}\r\nX-Httpstun-Client-Password: wrong\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+// A WebSocket session logged in as `name`, offering no features
+async fn open_session(port: u16, name: &str, password: &str) -> reqwest_websocket::WebSocket {
+    let response = reqwest::Client::new().get(format!("ws://127.0.0.1:{port}/"))
+        .header("X-Httpstun-Client-Name", name)
+        .header("X-Httpstun-Client-Password", password)
+        .upgrade()
+        .send().await.unwrap();
+    response.into_websocket().await.unwrap()
+}
+
+// The next binary frame or close code the server sends, skipping control messages
+async fn next_frame(ws: &mut reqwest_websocket::WebSocket) -> Message {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Text(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(message)) => return message,
+                other => panic!("expected a frame, got {other:?}"),
+            }
+        }
+    }).await.expect("timed out waiting for a frame")
+}
+
+// Every drop counter is zero but the given ones
+fn assert_drops(stats: &Stats, expected: &[(DropReason, u64)]) {
+    for reason in DropReason::ALL {
+        let want = expected.iter().find(|(r, _)| *r == reason).map_or(0, |(_, n)| *n);
+        assert_eq!(stats.drops.get(reason), want, "{} drops", reason.name());
+    }
+}
+
+// The registered holder of CLIENT_IP
+async fn holder(tunnel: &Tunnel) -> Option<String> {
+    tunnel.registry.read().await.get(&IpAddr::V4(CLIENT_IP)).map(|session| session.name.clone())
+}
+
+async fn start() -> Tunnel {
+    start_with(json!({}), json!({}), &[]).await
+}
+
+// Serve the tunnel endpoint and data plane as the server's main does, and connect a client.
+// `server_args` and `client_entry` are merged into the server's config, and `client_args`
+// appended to the client's command line.
+async fn start_with(server_args: Value, client_entry: Value, client_args: &[&str]) -> Tunnel {
+    let mut config = json!({
+        "server_args": { "server_ip": SERVER_IP.to_string() },
+        "clients": [{
+            "name": CLIENT_NAME,
+            "token": httpstun_core::hash_password(CLIENT_PASSWORD),
+            "ip": CLIENT_IP.to_string(),
+        }],
+    });
+    merge(&mut config["server_args"], server_args);
+    merge(&mut config["clients"][0], client_entry);
+    // not validated, so it may share the first client's IP
+    config["clients"].as_array_mut().unwrap().push(json!({
+        "name": OTHER_CLIENT_NAME,
+        "token": httpstun_core::hash_password(OTHER_CLIENT_PASSWORD),
+        "ip": CLIENT_IP.to_string(),
+    }));
+    let config: Config = serde_json::from_value(config).unwrap();
+    let config: SharedConfig = Arc::new(RwLock::new(config));
+    let registry: ClientRegistry = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+    let sessions: SessionIndex = Arc::new(Mutex::new(HashMap::new()));
+    let accounting = Arc::new(Accounting::open(None).unwrap());
+    let stats = Arc::new(Stats::new(false, 0));
+    let (wstx, wsrx) = async_channel::bounded::<WsToTunPacket>(64);
+
+    let (server_inject, injected) = unbounded();
+    let (written, server_written) = unbounded();
+    let tun = ServerTun { injected, written };
+    let (plane_registry, plane_stats, plane_config) = (registry.clone(), stats.clone(), config.clone());
+    rt::spawn(async move {
+        let _ = httpstun_core::tun::run_data_plane(&tun, 1500, wsrx, plane_registry, plane_stats, &plane_config).await;
+    });
+
+    let (http_registry, http_config, http_sessions, http_accounting, http_stats) = (registry.clone(), config.clone(), sessions.clone(), accounting.clone(), stats.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(http_config.clone()))
+            .app_data(Data::new(wstx.clone()))
+            .app_data(Data::new(http_registry.clone()))
+            .app_data(Data::new(http_sessions.clone()))
+            .app_data(Data::new(http_accounting.clone()))
+            .app_data(Data::new(http_stats.clone()))
+            .configure(|cfg| httpstun_core::ws::configure(cfg, "/"))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let port = server.addrs()[0].port();
+    rt::spawn(server.run());
+
+    let server_url = format!("ws://127.0.0.1:{port}/");
+    let tun_address = format!("{CLIENT_IP}/24");
+    let mut argv = vec![
+        "httpstun_client",
+        "--server-url", &server_url,
+        "--client-name", CLIENT_NAME,
+        "--client-password", CLIENT_PASSWORD,
+        // set locally, so the client doesn't try to address a real interface
+        "--tun-address", &tun_address,
+    ];
+    argv.extend(client_args);
+    let client_config = httpstun_client::Config { client_args: httpstun_client::Args::parse_from(argv) };
+    let (client_inject, injected) = unbounded();
+    let (written, client_written) = unbounded();
+    let mut tun = ClientTun { injected, written };
+    let client = rt::spawn(async move {
+        let pushed_dns = Default::default();
+        httpstun_client::run_forever(&client_config, &mut tun, &pushed_dns, None, None).await;
+    });
+
+    tokio::time::timeout(TIMEOUT, async {
+        while !registry.read().await.contains_key(&IpAddr::V4(CLIENT_IP)) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("client did not connect");
+    Tunnel { server_inject, server_written, client_inject, client_written, stats, registry, sessions, accounting, port, client }
+}
+
+#[actix_web::test]
+async fn packet_for_client_reaches_it() {
+    let tunnel = start().await;
+    let packet = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the client");
+    tunnel.server_inject.send(packet.clone()).await.unwrap();
+    assert_eq!(recv(&tunnel.client_written).await, packet);
+}
+
+#[actix_web::test]
+async fn spoofed_source_is_dropped() {
+    let tunnel = start().await;
+    let spoofed = udp_packet(Ipv4Addr::new(10, 10, 10, 99), Ipv4Addr::new(192, 0, 2, 7), b"spoofed");
+    let genuine = udp_packet(CLIENT_IP, Ipv4Addr::new(192, 0, 2, 7), b"genuine");
+    tunnel.client_inject.send(spoofed).await.unwrap();
+    tunnel.client_inject.send(genuine.clone()).await.unwrap();
+    // packets leave the client in order, so the genuine one arriving first means the spoofed one was dropped
+    assert_eq!(recv(&tunnel.server_written).await, genuine);
+    assert!(tunnel.server_written.is_empty());
+    assert_drops(&tunnel.stats, &[(DropReason::Spoofed, 1)]);
+}
+
+#[actix_web::test]
+async fn undeliverable_tun_packets_are_counted_by_reason() {
+    let tunnel = start().await;
+    let mut truncated = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"cut short");
+    truncated.truncate(24);
+    let genuine = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the client");
+    for packet in [vec![0; 40], truncated, udp_packet(Ipv4Addr::new(192, 0, 2, 7), Ipv4Addr::new(192, 0, 2, 9), b"nobody's"), genuine.clone()] {
+        tunnel.server_inject.send(packet).await.unwrap();
+    }
+    // the data plane takes packets in order
+    assert_eq!(recv(&tunnel.client_written).await, genuine);
+    assert_drops(&tunnel.stats, &[(DropReason::ParseError, 1), (DropReason::Truncated, 1), (DropReason::UnassignedDestination, 1)]);
+}
+
+#[actix_web::test]
+async fn packets_held_for_an_absent_client_are_bounded_and_delivered_on_reconnect() {
+    let tunnel = start_with(json!({ "reconnect_buffer": 2, "reconnect_buffer_max_age": 2 }), json!({}), &[]).await;
+    tunnel.client.abort();
+    // closing its queue tears the session down as a dropped connection would
+    tunnel.registry.read().await[&IpAddr::V4(CLIENT_IP)].tx.close();
+    tokio::time::timeout(TIMEOUT, async {
+        while holder(&tunnel).await.is_some() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("client session was not closed");
+    let packet = |payload: &[u8]| udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, payload);
+    tunnel.server_inject.send(packet(b"too old")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    // the second packet pushes the first out of a buffer holding two
+    for payload in [b"first", b"again", b"newer"] {
+        tunnel.server_inject.send(packet(payload)).await.unwrap();
+    }
+    tokio::time::timeout(TIMEOUT, async {
+        while tunnel.stats.drops.get(DropReason::ReconnectBufferFull) == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("buffer did not fill");
+    let mut reconnected = open_session(tunnel.port, CLIENT_NAME, CLIENT_PASSWORD).await;
+    for payload in [b"again", b"newer"] {
+        assert!(matches!(next_frame(&mut reconnected).await, Message::Binary(frame) if frame == packet(payload)));
+    }
+    assert_drops(&tunnel.stats, &[(DropReason::ReconnectBufferFull, 1), (DropReason::ReconnectBufferExpired, 1)]);
+}
+
+#[actix_web::test]
+async fn packet_over_client_mtu_is_answered_with_fragmentation_needed() {
+    let tunnel = start_with(json!({}), json!({ "mtu": 1280 }), &[]).await;
+    let sender = Ipv4Addr::new(192, 0, 2, 7);
+    // don't-fragment is set, as TCP sets it for path MTU discovery
+    tunnel.server_inject.send(udp_packet(sender, CLIENT_IP, &[0; 1300])).await.unwrap();
+    let reply = recv(&tunnel.server_written).await;
+    let reply = etherparse::SlicedPacket::from_ip(&reply).unwrap();
+    let Some(etherparse::NetSlice::Ipv4(ip)) = &reply.net else { panic!("expected an IPv4 reply") };
+    assert_eq!((ip.header().source_addr(), ip.header().destination_addr()), (SERVER_IP, sender));
+    let Some(etherparse::TransportSlice::Icmpv4(icmp)) = &reply.transport else { panic!("expected an ICMP reply") };
+    assert_eq!(
+        icmp.icmp_type(),
+        etherparse::Icmpv4Type::DestinationUnreachable(etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: 1280 }),
+    );
+    assert!(tunnel.client_written.is_empty());
+    assert_drops(&tunnel.stats, &[(DropReason::OverMtu, 1)]);
+    assert_eq!(load(&tunnel.stats.icmp.too_big_sent), 1);
+}
+
+#[actix_web::test]
+async fn disallowed_destination_is_dropped() {
+    let tunnel = start_with(json!({}), json!({ "allowed_destinations": ["192.0.2.0/24"] }), &[]).await;
+    let disallowed = udp_packet(CLIENT_IP, Ipv4Addr::new(198, 51, 100, 7), b"disallowed");
+    let allowed = udp_packet(CLIENT_IP, Ipv4Addr::new(192, 0, 2, 7), b"allowed");
+    tunnel.client_inject.send(disallowed).await.unwrap();
+    tunnel.client_inject.send(allowed.clone()).await.unwrap();
+    assert_eq!(recv(&tunnel.server_written).await, allowed);
+    assert!(tunnel.server_written.is_empty());
+    assert_drops(&tunnel.stats, &[(DropReason::FilteredByAcl, 1)]);
+    // and counted against the client that sent it
+    assert_eq!(load(&tunnel.stats.clients.get(CLIENT_NAME).unwrap().filtered_by_acl), 1);
+}
+
+#[actix_web::test]
+async fn removed_client_stops_reconnecting() {
+    let tunnel = start().await;
+    let code = actix_ws::CloseCode::Other(httpstun_core::control::CLOSE_CLIENT_REMOVED);
+    let closed = httpstun_core::ws::disconnect_client(&tunnel.registry, &tunnel.sessions, &tunnel.accounting, CLIENT_NAME, code, "removed from config").await;
+    assert_eq!(closed, 1);
+    tokio::time::timeout(TIMEOUT, tunnel.client).await.expect("client kept reconnecting").unwrap();
+}
+
+#[actix_web::test]
+async fn packets_cross_sealed_with_a_psk() {
+    let tunnel = start_with(json!({ "psk": PSK }), json!({}), &["--psk", PSK]).await;
+    let to_client = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the client");
+    tunnel.server_inject.send(to_client.clone()).await.unwrap();
+    assert_eq!(recv(&tunnel.client_written).await, to_client);
+    let from_client = udp_packet(CLIENT_IP, Ipv4Addr::new(192, 0, 2, 7), b"from the client");
+    tunnel.client_inject.send(from_client.clone()).await.unwrap();
+    assert_eq!(recv(&tunnel.server_written).await, from_client);
+}
+
+#[actix_web::test]
+async fn frames_under_another_psk_are_dropped() {
+    let tunnel = start_with(json!({ "psk": PSK }), json!({}), &["--psk", OTHER_PSK]).await;
+    tunnel.client_inject.send(udp_packet(CLIENT_IP, Ipv4Addr::new(192, 0, 2, 7), b"wrong key")).await.unwrap();
+    tokio::time::timeout(TIMEOUT, async {
+        while tunnel.stats.drops.get(DropReason::Undecryptable) == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("frame was not dropped");
+    assert!(tunnel.server_written.is_empty());
+    assert_drops(&tunnel.stats, &[(DropReason::Undecryptable, 1)]);
+}
+
+#[actix_web::test]
+async fn psk_server_refuses_clients_sending_no_salt() {
+    let tunnel = start_with(json!({ "psk": PSK }), json!({}), &["--psk", PSK]).await;
+    let response = reqwest::Client::new().get(format!("ws://127.0.0.1:{}/", tunnel.port))
+        .header("X-Httpstun-Client-Name", CLIENT_NAME)
+        .header("X-Httpstun-Client-Password", CLIENT_PASSWORD)
+        .upgrade()
+        .send().await.unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_web::test]
+async fn full_server_refuses_other_clients_before_authenticating() {
+    let tunnel = start_with(json!({ "max_clients": 1 }), json!({}), &[]).await;
+    assert_eq!(status_logging_in_as(tunnel.port, "client2").await, 503);
+    // a connected client gets past the limit to authentication, which fails on the password
+    assert_eq!(status_logging_in_as(tunnel.port, CLIENT_NAME).await, 404);
+    assert_eq!(load(&tunnel.stats.auth.failures), 1);
+}
+
+#[actix_web::test]
+async fn client_lacking_a_required_feature_is_closed_with_policy() {
+    let tunnel = start_with(json!({ "require_feature": ["control-channel"] }), json!({}), &["--control-channel"]).await;
+    let mut ws = open_session(tunnel.port, CLIENT_NAME, CLIENT_PASSWORD).await;
+    assert!(matches!(next_frame(&mut ws).await, Message::Close { code: CloseCode::Policy, .. }));
+}
+
+#[actix_web::test]
+async fn reconnect_replaces_the_clients_open_session() {
+    let tunnel = start().await;
+    // the client's first session is still open, as after a drop the server hasn't noticed
+    let mut reconnected = open_session(tunnel.port, CLIENT_NAME, CLIENT_PASSWORD).await;
+    tokio::time::timeout(TIMEOUT, async {
+        while tunnel.sessions.lock().unwrap().get(CLIENT_NAME).map_or(0, Vec::len) != 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("old session was not replaced");
+    // the replaced client waits 5s before reconnecting and taking its slot back
+    let packet = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the new session");
+    tunnel.server_inject.send(packet.clone()).await.unwrap();
+    assert!(matches!(next_frame(&mut reconnected).await, Message::Binary(frame) if frame == packet));
+    assert!(tunnel.client_written.is_empty());
+}
+
+#[actix_web::test]
+async fn client_connecting_with_a_held_address_is_rejected() {
+    let tunnel = start().await;
+    let mut other = open_session(tunnel.port, OTHER_CLIENT_NAME, OTHER_CLIENT_PASSWORD).await;
+    assert!(matches!(next_frame(&mut other).await, Message::Close { code: CloseCode::Policy, .. }));
+    assert_eq!(holder(&tunnel).await.as_deref(), Some(CLIENT_NAME));
+    let packet = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the holder");
+    tunnel.server_inject.send(packet.clone()).await.unwrap();
+    assert_eq!(recv(&tunnel.client_written).await, packet);
+}
+
+#[actix_web::test]
+async fn client_connecting_with_a_held_address_evicts_the_holder() {
+    let tunnel = start_with(json!({ "ip_conflict_policy": "evict" }), json!({}), &[]).await;
+    let mut other = open_session(tunnel.port, OTHER_CLIENT_NAME, OTHER_CLIENT_PASSWORD).await;
+    // the evicted client waits 5s before reconnecting and taking the address back
+    tokio::time::timeout(TIMEOUT, async {
+        while holder(&tunnel).await.as_deref() != Some(OTHER_CLIENT_NAME) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("address was not taken over");
+    let packet = udp_packet(Ipv4Addr::new(192, 0, 2, 7), CLIENT_IP, b"to the newcomer");
+    tunnel.server_inject.send(packet.clone()).await.unwrap();
+    assert!(matches!(next_frame(&mut other).await, Message::Binary(frame) if frame == packet));
+    assert!(tunnel.client_written.is_empty());
+}